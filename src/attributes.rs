@@ -0,0 +1,389 @@
+use std::collections::HashMap;
+
+use cgmath::{InnerSpace, Vector3};
+
+use crate::model::ModelVertex;
+
+/// How texture coordinates are generated for meshes without them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UvProjection {
+    /// Projects the whole mesh along the axis where it is thinnest
+    Planar,
+    /// Projects each triangle along the axis its normal is closest to
+    Box,
+}
+
+fn face_normal(vertices: &[ModelVertex], triangle: &[u32]) -> Vector3<f32> {
+    let p0 = Vector3::from(vertices[triangle[0] as usize].position);
+    let p1 = Vector3::from(vertices[triangle[1] as usize].position);
+    let p2 = Vector3::from(vertices[triangle[2] as usize].position);
+
+    // not normalized, so larger triangles weight more when averaging
+    (p1 - p0).cross(p2 - p0)
+}
+
+/// Index of the axis with the largest magnitude
+fn dominant_axis(v: Vector3<f32>) -> usize {
+    let v = [v.x.abs(), v.y.abs(), v.z.abs()];
+
+    if v[0] >= v[1] && v[0] >= v[2] {
+        0
+    } else if v[1] >= v[2] {
+        1
+    } else {
+        2
+    }
+}
+
+/// Rebuilds the mesh giving every triangle corner its own vertex, then merges the
+/// corners that ended up identical. `corner` sets the new attribute of each corner.
+fn split_corners<F: Fn(usize, &mut ModelVertex)>(
+    vertices: &mut Vec<ModelVertex>,
+    indices: &mut [u32],
+    corner: F,
+) {
+    let mut merged: HashMap<Vec<u8>, u32> = HashMap::new();
+    let mut split = Vec::with_capacity(vertices.len());
+
+    for (i, index) in indices.iter_mut().enumerate() {
+        let mut vertex = vertices[*index as usize];
+        corner(i, &mut vertex);
+
+        *index = *merged
+            .entry(bytemuck::bytes_of(&vertex).to_vec())
+            .or_insert_with(|| {
+                split.push(vertex);
+                split.len() as u32 - 1
+            });
+    }
+
+    *vertices = split;
+}
+
+/// Generates normals averaging the faces around each position whose normals are within
+/// `smoothing_angle` of each other, a zero angle gives flat shading
+pub fn generate_normals(
+    vertices: &mut Vec<ModelVertex>,
+    indices: &mut [u32],
+    smoothing_angle: cgmath::Deg<f32>,
+) {
+    let faces = indices
+        .chunks(3)
+        .map(|triangle| face_normal(vertices, triangle))
+        .collect::<Vec<_>>();
+
+    let unit_faces = faces
+        .iter()
+        .map(|face| face.normalize())
+        .collect::<Vec<_>>();
+
+    // vertices may be duplicated for other attributes, so faces are shared by position
+    let mut faces_at: HashMap<[u32; 3], Vec<usize>> = HashMap::new();
+    for (i, index) in indices.iter().enumerate() {
+        let position = vertices[*index as usize].position.map(f32::to_bits);
+        faces_at.entry(position).or_default().push(i / 3);
+    }
+
+    let threshold = cgmath::Rad::from(smoothing_angle).0.cos();
+
+    let normals = indices
+        .iter()
+        .enumerate()
+        .map(|(i, index)| {
+            let face = unit_faces[i / 3];
+            let position = vertices[*index as usize].position.map(f32::to_bits);
+
+            let mut normal = Vector3::new(0.0, 0.0, 0.0);
+            for &other in &faces_at[&position] {
+                // the corner's own face counts even when rounding puts it past the threshold
+                if other == i / 3 || face.dot(unit_faces[other]) >= threshold {
+                    normal += faces[other];
+                }
+            }
+
+            if normal.magnitude2() > 0.0 {
+                normal.normalize()
+            } else {
+                // degenerate triangle
+                Vector3::unit_y()
+            }
+        })
+        .collect::<Vec<_>>();
+
+    split_corners(vertices, indices, |corner, vertex| {
+        vertex.normal = normals[corner].into();
+    });
+}
+
+/// Generates texture coordinates spanning `[0, 1]` over the bounding box of the mesh
+pub fn generate_tex_coords(
+    vertices: &mut Vec<ModelVertex>,
+    indices: &mut [u32],
+    projection: UvProjection,
+) {
+    let mut min = Vector3::from([f32::MAX; 3]);
+    let mut max = Vector3::from([f32::MIN; 3]);
+    for vertex in vertices.iter() {
+        for axis in 0..3 {
+            min[axis] = min[axis].min(vertex.position[axis]);
+            max[axis] = max[axis].max(vertex.position[axis]);
+        }
+    }
+
+    let extent = max - min;
+
+    let planar_axis = {
+        // the thinnest axis is the one to project along
+        let inverse = Vector3::new(1.0 / extent.x, 1.0 / extent.y, 1.0 / extent.z);
+        dominant_axis(inverse)
+    };
+
+    let axes = indices
+        .chunks(3)
+        .map(|triangle| match projection {
+            UvProjection::Planar => planar_axis,
+            UvProjection::Box => dominant_axis(face_normal(vertices, triangle)),
+        })
+        .collect::<Vec<_>>();
+
+    let project = |position: [f32; 3], axis: usize| {
+        let (u, v) = match axis {
+            0 => (2, 1),
+            1 => (0, 2),
+            _ => (0, 1),
+        };
+
+        let normalized = |i: usize| {
+            if extent[i] > 0.0 {
+                (position[i] - min[i]) / extent[i]
+            } else {
+                0.0
+            }
+        };
+
+        // texture coordinates grow downwards
+        [normalized(u), 1.0 - normalized(v)]
+    };
+
+    split_corners(vertices, indices, |corner, vertex| {
+        vertex.tex_coords = project(vertex.position, axes[corner / 3]);
+    });
+}
+
+/// Indexed triangle list as seen by the MikkTSpace generator, which works on corners
+struct MikkTSpaceMesh<'a> {
+    vertices: &'a [ModelVertex],
+    indices: &'a [u32],
+    tangents: Vec<[f32; 4]>,
+}
+
+impl MikkTSpaceMesh<'_> {
+    fn vertex(&self, face: usize, vert: usize) -> &ModelVertex {
+        &self.vertices[self.indices[face * 3 + vert] as usize]
+    }
+}
+
+impl bevy_mikktspace::Geometry for MikkTSpaceMesh<'_> {
+    fn num_faces(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    fn num_vertices_of_face(&self, _face: usize) -> usize {
+        3
+    }
+
+    fn position(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.vertex(face, vert).position
+    }
+
+    fn normal(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.vertex(face, vert).normal
+    }
+
+    fn tex_coord(&self, face: usize, vert: usize) -> [f32; 2] {
+        // MikkTSpace, as the tools baking normal maps, expects v to grow upwards
+        let [u, v] = self.vertex(face, vert).tex_coords;
+        [u, 1.0 - v]
+    }
+
+    fn set_tangent_encoded(&mut self, tangent: [f32; 4], face: usize, vert: usize) {
+        self.tangents[face * 3 + vert] = tangent;
+    }
+}
+
+/// Generates MikkTSpace tangents, splitting vertices where adjacent triangles disagree
+pub fn generate_tangents(vertices: &mut Vec<ModelVertex>, indices: &mut [u32]) {
+    let mut mesh = MikkTSpaceMesh {
+        vertices,
+        indices,
+        tangents: vec![[1.0, 0.0, 0.0, 1.0]; indices.len()],
+    };
+
+    if !bevy_mikktspace::generate_tangents(&mut mesh) {
+        log::warn!("Could not generate tangents, normal maps will not render correctly");
+    }
+
+    let tangents = mesh.tangents;
+
+    split_corners(vertices, indices, |corner, vertex| {
+        vertex.tangent = tangents[corner];
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vertex(position: [f32; 3]) -> ModelVertex {
+        ModelVertex {
+            position,
+            tex_coords: [0.0; 2],
+            normal: [0.0; 3],
+            tangent: [0.0; 4],
+        }
+    }
+
+    /// Two triangles per face sharing the 8 corners, wound counter-clockwise from outside
+    fn cube() -> (Vec<ModelVertex>, Vec<u32>) {
+        let vertices = (0..8)
+            .map(|i| {
+                let coordinate = |bit: u32| if i & (1 << bit) != 0 { 1.0 } else { -1.0 };
+                vertex([coordinate(0), coordinate(1), coordinate(2)])
+            })
+            .collect::<Vec<_>>();
+
+        let faces: [[u32; 4]; 6] = [
+            [1, 3, 7, 5],
+            [0, 4, 6, 2],
+            [2, 6, 7, 3],
+            [0, 1, 5, 4],
+            [4, 5, 7, 6],
+            [0, 2, 3, 1],
+        ];
+
+        let mut indices = Vec::new();
+        for [a, b, c, d] in faces {
+            for triangle in [[a, b, c], [a, c, d]] {
+                let center = Vector3::from(vertices[a as usize].position)
+                    + Vector3::from(vertices[c as usize].position);
+                if face_normal(&vertices, &triangle).dot(center) > 0.0 {
+                    indices.extend(triangle);
+                } else {
+                    indices.extend([triangle[0], triangle[2], triangle[1]]);
+                }
+            }
+        }
+
+        (vertices, indices)
+    }
+
+    #[test]
+    fn hard_edges_keep_face_normals() {
+        let (mut vertices, mut indices) = cube();
+        generate_normals(&mut vertices, &mut indices, cgmath::Deg(60.0));
+
+        // 4 corners for each of the 6 faces
+        assert_eq!(vertices.len(), 24);
+
+        for triangle in indices.chunks(3) {
+            let face = face_normal(&vertices, triangle).normalize();
+            for &index in triangle {
+                let normal = Vector3::from(vertices[index as usize].normal);
+                assert!((normal - face).magnitude() < 1e-5, "{:?}", normal);
+            }
+        }
+    }
+
+    #[test]
+    fn zero_angle_gives_every_corner_its_face_normal() {
+        // scattered triangles, some of which have normals whose dot product with themselves
+        // rounds below 1
+        let mut seed = 1u32;
+        let mut random = || {
+            seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (seed >> 8) as f32 / (1 << 24) as f32 * 2.0 - 1.0
+        };
+
+        let mut vertices = (0..300)
+            .map(|_| vertex([random(), random(), random()]))
+            .collect::<Vec<_>>();
+        let mut indices = (0..vertices.len() as u32).collect::<Vec<_>>();
+        let faces = indices
+            .chunks(3)
+            .map(|triangle| face_normal(&vertices, triangle).normalize())
+            .collect::<Vec<_>>();
+
+        generate_normals(&mut vertices, &mut indices, cgmath::Deg(0.0));
+
+        for (triangle, face) in indices.chunks(3).zip(faces) {
+            for &index in triangle {
+                let normal = Vector3::from(vertices[index as usize].normal);
+                assert!(
+                    (normal - face).magnitude() < 1e-5,
+                    "{:?} {:?}",
+                    normal,
+                    face
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn smooth_edges_share_normals() {
+        let (mut vertices, mut indices) = cube();
+        generate_normals(&mut vertices, &mut indices, cgmath::Deg(100.0));
+
+        assert_eq!(vertices.len(), 8);
+
+        for vertex in &vertices {
+            let normal = Vector3::from(vertex.normal);
+            let position = Vector3::from(vertex.position);
+            assert!((normal.magnitude() - 1.0).abs() < 1e-5);
+            // pointing away from the center, along the corner
+            assert!(normal.x * position.x > 0.0);
+            assert!(normal.y * position.y > 0.0);
+            assert!(normal.z * position.z > 0.0);
+        }
+    }
+
+    #[test]
+    fn planar_projection_spans_the_quad() {
+        let mut vertices = vec![
+            vertex([0.0, 0.0, 0.0]),
+            vertex([2.0, 0.0, 0.0]),
+            vertex([2.0, 1.0, 0.0]),
+            vertex([0.0, 1.0, 0.0]),
+        ];
+        let mut indices = vec![0, 1, 2, 0, 2, 3];
+
+        generate_tex_coords(&mut vertices, &mut indices, UvProjection::Planar);
+
+        assert_eq!(vertices.len(), 4);
+        for vertex in &vertices {
+            let [x, y, _] = vertex.position;
+            // projected along z, v grows downwards
+            assert_eq!(vertex.tex_coords, [x / 2.0, 1.0 - y]);
+        }
+    }
+
+    #[test]
+    fn box_projection_follows_the_faces() {
+        let (mut vertices, mut indices) = cube();
+        generate_tex_coords(&mut vertices, &mut indices, UvProjection::Box);
+
+        for triangle in indices.chunks(3) {
+            let axis = dominant_axis(face_normal(&vertices, triangle));
+            let (u, v) = match axis {
+                0 => (2, 1),
+                1 => (0, 2),
+                _ => (0, 1),
+            };
+
+            for &index in triangle {
+                let vertex = vertices[index as usize];
+                let normalized = |i: usize| (vertex.position[i] + 1.0) / 2.0;
+                assert_eq!(vertex.tex_coords, [normalized(u), 1.0 - normalized(v)]);
+            }
+        }
+    }
+}
@@ -0,0 +1,517 @@
+use anyhow::*;
+use basis_universal::{TranscodeParameters, Transcoder, TranscoderTextureFormat};
+
+use crate::compressed::{self, CompressedImage};
+
+/// Formats Basis Universal textures are transcoded to, in the order of the compression
+/// families of [`compressed::variants`]
+const TARGETS: [(wgpu::Features, TranscoderTextureFormat, wgpu::TextureFormat); 3] = [
+    (
+        wgpu::Features::TEXTURE_COMPRESSION_ASTC,
+        TranscoderTextureFormat::ASTC_4x4_RGBA,
+        wgpu::TextureFormat::Astc {
+            block: wgpu::AstcBlock::B4x4,
+            channel: wgpu::AstcChannel::Unorm,
+        },
+    ),
+    (
+        wgpu::Features::TEXTURE_COMPRESSION_BC,
+        TranscoderTextureFormat::BC7_RGBA,
+        wgpu::TextureFormat::Bc7RgbaUnorm,
+    ),
+    (
+        wgpu::Features::TEXTURE_COMPRESSION_ETC2,
+        TranscoderTextureFormat::ETC2_RGBA,
+        wgpu::TextureFormat::Etc2Rgba8Unorm,
+    ),
+];
+
+// layout of the .basis files the transcoder reads, all fields are little endian
+const SIGNATURE: u32 = (b'B' as u32) << 8 | b's' as u32;
+const VERSION: u32 = 0x13;
+const HEADER_SIZE: usize = 77;
+const SLICE_DESC_SIZE: usize = 23;
+const FORMAT_ETC1S: u32 = 0;
+const FORMAT_UASTC: u32 = 1;
+const FLAG_ETC1S: u32 = 1;
+const FLAG_HAS_ALPHA_SLICES: u32 = 4;
+const SLICE_HAS_ALPHA: u32 = 1;
+
+// KTX2 data format descriptor values of Basis Universal textures
+const MODEL_UASTC: u32 = 166;
+const CHANNEL_UASTC_RGBA: u32 = 3;
+const CHANNEL_UASTC_RRRG: u32 = 5;
+
+/// Compressed data of a mip level, ETC1S alpha slices follow their colour slice
+struct Slice<'a> {
+    level: u32,
+    alpha: bool,
+    data: &'a [u8],
+}
+
+/// ETC1S codebooks and Huffman tables shared by all slices, empty for UASTC
+#[derive(Default)]
+struct Codebooks<'a> {
+    endpoint_count: u32,
+    endpoints: &'a [u8],
+    selector_count: u32,
+    selectors: &'a [u8],
+    tables: &'a [u8],
+}
+
+/// Reads a little endian integer of `size` bytes
+fn read(bytes: &[u8], offset: usize, size: usize) -> Option<u32> {
+    let bytes = bytes.get(offset..offset + size)?;
+    Some(
+        bytes
+            .iter()
+            .rev()
+            .fold(0, |value, byte| value << 8 | *byte as u32),
+    )
+}
+
+fn write(file: &mut Vec<u8>, value: usize, size: usize) {
+    file.extend_from_slice(&value.to_le_bytes()[..size]);
+}
+
+/// Wraps the slices of a KTX2 file in a .basis file, the only container the transcoder
+/// bindings read. The CRCs are left zeroed, the transcoder only checks them on request.
+fn basis_file(
+    width: u32,
+    height: u32,
+    format: u32,
+    flags: u32,
+    codebooks: &Codebooks,
+    slices: &[Slice],
+) -> Vec<u8> {
+    let endpoints_offset = HEADER_SIZE + slices.len() * SLICE_DESC_SIZE;
+    let selectors_offset = endpoints_offset + codebooks.endpoints.len();
+    let tables_offset = selectors_offset + codebooks.selectors.len();
+    let slices_offset = tables_offset + codebooks.tables.len();
+    let size = slices_offset + slices.iter().map(|slice| slice.data.len()).sum::<usize>();
+
+    let mut file = Vec::with_capacity(size);
+    write(&mut file, SIGNATURE as usize, 2);
+    write(&mut file, VERSION as usize, 2);
+    write(&mut file, HEADER_SIZE, 2);
+    write(&mut file, 0, 2); // header CRC
+    write(&mut file, size - HEADER_SIZE, 4);
+    write(&mut file, 0, 2); // data CRC
+    write(&mut file, slices.len(), 3);
+    write(&mut file, 1, 3); // images
+    write(&mut file, format as usize, 1);
+    write(&mut file, flags as usize, 2);
+    write(&mut file, 0, 1); // 2D texture
+    write(&mut file, 0, 3); // microseconds per video frame
+    write(&mut file, 0, 4); // reserved
+    write(&mut file, 0, 8); // user data
+    write(&mut file, codebooks.endpoint_count as usize, 2);
+    write(&mut file, endpoints_offset, 4);
+    write(&mut file, codebooks.endpoints.len(), 3);
+    write(&mut file, codebooks.selector_count as usize, 2);
+    write(&mut file, selectors_offset, 4);
+    write(&mut file, codebooks.selectors.len(), 3);
+    write(&mut file, tables_offset, 4);
+    write(&mut file, codebooks.tables.len(), 4);
+    write(&mut file, HEADER_SIZE, 4); // slice descriptions
+    write(&mut file, 0, 8); // extended data
+    debug_assert_eq!(file.len(), HEADER_SIZE);
+
+    let mut offset = slices_offset;
+    for slice in slices {
+        let width = (width >> slice.level).max(1) as usize;
+        let height = (height >> slice.level).max(1) as usize;
+
+        write(&mut file, 0, 3); // image
+        write(&mut file, slice.level as usize, 1);
+        write(
+            &mut file,
+            if slice.alpha { SLICE_HAS_ALPHA } else { 0 } as usize,
+            1,
+        );
+        write(&mut file, width, 2);
+        write(&mut file, height, 2);
+        write(&mut file, width.div_ceil(4), 2);
+        write(&mut file, height.div_ceil(4), 2);
+        write(&mut file, offset, 4);
+        write(&mut file, slice.data.len(), 4);
+        write(&mut file, 0, 2); // slice CRC
+        offset += slice.data.len();
+    }
+
+    file.extend_from_slice(codebooks.endpoints);
+    file.extend_from_slice(codebooks.selectors);
+    file.extend_from_slice(codebooks.tables);
+    for slice in slices {
+        file.extend_from_slice(slice.data);
+    }
+
+    file
+}
+
+/// Transcodes a KTX2 texture holding ETC1S (BasisLZ supercompressed) or UASTC data to the
+/// first family of [`TARGETS`] the device supports, or to uncompressed RGBA
+pub fn transcode(
+    reader: &ktx2::Reader<&[u8]>,
+    features: wgpu::Features,
+) -> Result<CompressedImage> {
+    let header = reader.header();
+    let (width, height) = (header.pixel_width, header.pixel_height);
+    if width > u16::MAX as u32 || height > u16::MAX as u32 {
+        bail!("{}x{} is too large for Basis Universal", width, height);
+    }
+
+    let levels = reader.levels().collect::<Vec<_>>();
+    let level_count = levels.len() as u32;
+
+    let file = if header.supercompression_scheme == Some(ktx2::SupercompressionScheme::BasisLZ) {
+        etc1s_file(reader, &levels)?
+    } else {
+        uastc_file(reader)?
+    };
+
+    // compressed formats need whole blocks at the top level
+    let (target, format) = TARGETS
+        .iter()
+        .find(|(required, ..)| features.contains(*required) && width % 4 == 0 && height % 4 == 0)
+        .map_or(
+            (
+                TranscoderTextureFormat::RGBA32,
+                wgpu::TextureFormat::Rgba8Unorm,
+            ),
+            |(_, target, format)| (*target, *format),
+        );
+
+    let mut transcoder = Transcoder::new();
+    transcoder
+        .prepare_transcoding(&file)
+        .map_err(|_| anyhow!("invalid Basis Universal data"))?;
+
+    let levels = (0..level_count)
+        .map(|level| {
+            let parameters = TranscodeParameters {
+                level_index: level,
+                ..Default::default()
+            };
+            transcoder
+                .transcode_image_level(&file, target, parameters)
+                .map_err(|e| anyhow!("could not transcode mip level {}: {:?}", level, e))
+        })
+        .collect::<Result<Vec<_>>>();
+    transcoder.end_transcoding();
+
+    Ok(CompressedImage {
+        format,
+        width,
+        height,
+        levels: levels?,
+    })
+}
+
+/// ETC1S slices are located by the image descriptions of the supercompression global data,
+/// which also holds the codebooks
+fn etc1s_file(reader: &ktx2::Reader<&[u8]>, levels: &[&[u8]]) -> Result<Vec<u8>> {
+    let header = reader.header();
+    // the crate keeps the location of the global data private, the header is at least 80 bytes
+    let data = reader.data();
+    let location =
+        |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap()) as usize;
+    let global = data
+        .get(location(64)..)
+        .and_then(|global| global.get(..location(72)))
+        .ok_or_else(|| anyhow!("BasisLZ global data is outside the file"))?;
+    let truncated = || anyhow!("BasisLZ global data is truncated");
+
+    let endpoint_count = read(global, 0, 2).ok_or_else(truncated)?;
+    let selector_count = read(global, 2, 2).ok_or_else(truncated)?;
+    let endpoints_length = read(global, 4, 4).ok_or_else(truncated)? as usize;
+    let selectors_length = read(global, 8, 4).ok_or_else(truncated)? as usize;
+    let tables_length = read(global, 12, 4).ok_or_else(truncated)? as usize;
+
+    // one image description per level, each of 5 integers
+    let descriptions = 20;
+    let endpoints = descriptions + levels.len() * 20;
+    let selectors = endpoints + endpoints_length;
+    let tables = selectors + selectors_length;
+    let section =
+        |start: usize, length: usize| global.get(start..start + length).ok_or_else(truncated);
+
+    let codebooks = Codebooks {
+        endpoint_count,
+        endpoints: section(endpoints, endpoints_length)?,
+        selector_count,
+        selectors: section(selectors, selectors_length)?,
+        tables: section(tables, tables_length)?,
+    };
+
+    let has_alpha = read(global, descriptions + 16, 4).ok_or_else(truncated)? > 0;
+
+    let mut slices = Vec::new();
+    for (level, data) in levels.iter().enumerate() {
+        let description = descriptions + level * 20;
+        let field = |i: usize| read(global, description + i * 4, 4).ok_or_else(truncated);
+        let slice = |offset: u32, length: u32| {
+            data.get(offset as usize..(offset + length) as usize)
+                .ok_or_else(|| anyhow!("slice of mip level {} is outside the level", level))
+        };
+
+        slices.push(Slice {
+            level: level as u32,
+            alpha: false,
+            data: slice(field(1)?, field(2)?)?,
+        });
+        if has_alpha {
+            slices.push(Slice {
+                level: level as u32,
+                alpha: true,
+                data: slice(field(3)?, field(4)?)?,
+            });
+        }
+    }
+
+    let flags = FLAG_ETC1S | if has_alpha { FLAG_HAS_ALPHA_SLICES } else { 0 };
+
+    Ok(basis_file(
+        header.pixel_width,
+        header.pixel_height,
+        FORMAT_ETC1S,
+        flags,
+        &codebooks,
+        &slices,
+    ))
+}
+
+/// UASTC levels are plain blocks, possibly Zstandard supercompressed, their alpha is given
+/// by the channel of the data format descriptor
+fn uastc_file(reader: &ktx2::Reader<&[u8]>) -> Result<Vec<u8>> {
+    let header = reader.header();
+
+    let descriptor = reader
+        .data_format_descriptors()
+        .find(|descriptor| descriptor.header == ktx2::DataFormatDescriptorHeader::BASIC)
+        .ok_or_else(|| anyhow!("KTX2 file has no basic data format descriptor"))?;
+    let descriptor = ktx2::BasicDataFormatDescriptor::parse(descriptor.data)
+        .map_err(|e| anyhow!("invalid KTX2 data format descriptor: {:?}", e))?;
+
+    if descriptor.color_model.map(|model| model.0.get()) != Some(MODEL_UASTC) {
+        bail!("KTX2 file has no format");
+    }
+
+    let has_alpha = descriptor
+        .sample_information()
+        .next()
+        .is_some_and(|sample| {
+            matches!(sample.channel_type, CHANNEL_UASTC_RGBA | CHANNEL_UASTC_RRRG)
+        });
+
+    let levels = compressed::ktx2_levels(reader)?;
+    let slices = levels
+        .iter()
+        .enumerate()
+        .map(|(level, data)| Slice {
+            level: level as u32,
+            alpha: has_alpha,
+            data,
+        })
+        .collect::<Vec<_>>();
+
+    let flags = if has_alpha { FLAG_HAS_ALPHA_SLICES } else { 0 };
+
+    Ok(basis_file(
+        header.pixel_width,
+        header.pixel_height,
+        FORMAT_UASTC,
+        flags,
+        &Codebooks::default(),
+        &slices,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use basis_universal::{BasisTextureFormat, Compressor, CompressorParams};
+
+    use super::*;
+
+    const SIZE: u32 = 8;
+    const MODEL_ETC1S: usize = 163;
+
+    /// One colour and alpha for each 4x4 block, which ETC1S can represent
+    fn pixels() -> Vec<u8> {
+        (0..SIZE * SIZE)
+            .flat_map(|i| {
+                let (x, y) = ((i % SIZE / 4) as u8, (i / SIZE / 4) as u8);
+                [x * 255, y * 255, 128, 255 - x * 128]
+            })
+            .collect()
+    }
+
+    fn encode(format: BasisTextureFormat) -> Vec<u8> {
+        let mut params = CompressorParams::new();
+        params.set_basis_format(format);
+        params.set_generate_mipmaps(true);
+        params.source_image_mut(0).init(&pixels(), SIZE, SIZE, 4);
+
+        let mut compressor = Compressor::new(1);
+        unsafe {
+            assert!(compressor.init(&params));
+            compressor.process().unwrap();
+        }
+        compressor.basis_file().to_vec()
+    }
+
+    /// Moves the slices of a .basis file into a KTX2 file, the inverse of [`basis_file`]
+    fn ktx2(basis: &[u8]) -> Vec<u8> {
+        // offsets are those of the header and slice descriptions `basis_file` writes
+        let field = |offset: usize, size: usize| read(basis, offset, size).unwrap() as usize;
+        let etc1s = field(20, 1) as u32 == FORMAT_ETC1S;
+        let has_alpha = field(21, 2) as u32 & FLAG_HAS_ALPHA_SLICES != 0;
+
+        // colour then alpha slice of each level
+        let mut levels: Vec<Vec<u8>> = Vec::new();
+        let mut descriptions = Vec::new();
+        for slice in 0..field(14, 3) {
+            let desc = field(65, 4) + slice * SLICE_DESC_SIZE;
+            let (level, offset, length) =
+                (field(desc + 3, 1), field(desc + 13, 4), field(desc + 17, 4));
+            if levels.len() <= level {
+                levels.push(Vec::new());
+                descriptions.push([0; 5]);
+            }
+            let alpha = field(desc + 4, 1) as u32 & SLICE_HAS_ALPHA != 0;
+            descriptions[level][if alpha { 3 } else { 1 }] = levels[level].len();
+            descriptions[level][if alpha { 4 } else { 2 }] = length;
+            levels[level].extend_from_slice(&basis[offset..offset + length]);
+        }
+
+        let mut global = Vec::new();
+        if etc1s {
+            let section = |offset: usize, size: usize| &basis[field(offset, 4)..][..field(size, 3)];
+            let tables = &basis[field(57, 4)..][..field(61, 4)];
+            write(&mut global, field(39, 2), 2);
+            write(&mut global, field(48, 2), 2);
+            write(&mut global, section(41, 45).len(), 4);
+            write(&mut global, section(50, 54).len(), 4);
+            write(&mut global, tables.len(), 4);
+            write(&mut global, 0, 4);
+            for description in &descriptions {
+                for value in description {
+                    write(&mut global, *value, 4);
+                }
+            }
+            global.extend_from_slice(section(41, 45));
+            global.extend_from_slice(section(50, 54));
+            global.extend_from_slice(tables);
+        }
+
+        let channels: &[usize] = match (etc1s, has_alpha) {
+            (_, false) => &[0],
+            (true, true) => &[0, 15],
+            (false, true) => &[CHANNEL_UASTC_RGBA as usize],
+        };
+        let block_size = 24 + 16 * channels.len();
+        let mut dfd = Vec::new();
+        write(&mut dfd, 4 + block_size, 4);
+        write(&mut dfd, 0, 4);
+        write(&mut dfd, 2 | block_size << 16, 4);
+        write(
+            &mut dfd,
+            if etc1s {
+                MODEL_ETC1S
+            } else {
+                MODEL_UASTC as usize
+            } | 1 << 8
+                | 2 << 16,
+            4,
+        );
+        write(&mut dfd, 3 | 3 << 8, 4);
+        write(&mut dfd, 0, 8);
+        for channel in channels {
+            write(&mut dfd, 63 << 16 | channel << 24, 4);
+            write(&mut dfd, 0, 8);
+            write(&mut dfd, u32::MAX as usize, 4);
+        }
+
+        let dfd_offset = 80 + levels.len() * 24;
+        let global_offset = dfd_offset + dfd.len();
+        let mut level_offset = global_offset + global.len();
+
+        let mut file = vec![
+            0xab, 0x4b, 0x54, 0x58, 0x20, 0x32, 0x30, 0xbb, 0x0d, 0x0a, 0x1a, 0x0a,
+        ];
+        for value in [
+            0,
+            1,
+            SIZE as usize,
+            SIZE as usize,
+            0,
+            0,
+            1,
+            levels.len(),
+            etc1s as usize,
+        ] {
+            write(&mut file, value, 4);
+        }
+        for value in [dfd_offset, dfd.len(), 0, 0] {
+            write(&mut file, value, 4);
+        }
+        write(&mut file, global_offset, 8);
+        write(&mut file, global.len(), 8);
+        for level in &levels {
+            write(&mut file, level_offset, 8);
+            write(&mut file, level.len(), 8);
+            write(&mut file, level.len(), 8);
+            level_offset += level.len();
+        }
+        file.extend(dfd);
+        file.extend(global);
+        file.extend(levels.concat());
+        file
+    }
+
+    fn assert_close(image: &CompressedImage, tolerance: u8) {
+        assert_eq!(image.format, wgpu::TextureFormat::Rgba8Unorm);
+        for (transcoded, source) in image.levels[0].iter().zip(pixels()) {
+            assert!(
+                transcoded.abs_diff(source) <= tolerance,
+                "{} != {}",
+                transcoded,
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn etc1s_transcodes_with_alpha() {
+        let file = ktx2(&encode(BasisTextureFormat::ETC1S));
+        let image = compressed::decode(&file, wgpu::Features::empty()).unwrap();
+
+        image.validate(wgpu::Features::empty()).unwrap();
+        assert_eq!(image.levels.len(), 4);
+        assert_close(&image, 16);
+    }
+
+    #[test]
+    fn uastc_transcodes_with_alpha() {
+        let file = ktx2(&encode(BasisTextureFormat::UASTC4x4));
+        let image = compressed::decode(&file, wgpu::Features::empty()).unwrap();
+
+        image.validate(wgpu::Features::empty()).unwrap();
+        assert_eq!(image.levels.len(), 4);
+        assert_close(&image, 16);
+    }
+
+    #[test]
+    fn transcodes_to_the_preferred_family() {
+        let features =
+            wgpu::Features::TEXTURE_COMPRESSION_BC | wgpu::Features::TEXTURE_COMPRESSION_ETC2;
+
+        for format in [BasisTextureFormat::ETC1S, BasisTextureFormat::UASTC4x4] {
+            let file = ktx2(&encode(format));
+            let image = compressed::decode(&file, features).unwrap();
+
+            assert_eq!(image.format, wgpu::TextureFormat::Bc7RgbaUnorm);
+            image.validate(features).unwrap();
+        }
+    }
+}
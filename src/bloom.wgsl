@@ -0,0 +1,60 @@
+// Glow around the bright parts of the HDR frame, they are picked before tonemapping, kept at a
+// quarter of the size, blurred horizontally then vertically and added to the tonemapped frame
+
+struct Bloom {
+    // brightness above which a colour glows
+    threshold: f32,
+    intensity: f32,
+};
+@group(1) @binding(0) var<uniform> bloom: Bloom;
+@group(1) @binding(1) var t_bloom: texture_2d<f32>;
+@group(1) @binding(2) var s_bloom: sampler;
+
+// Keeps what is above the threshold, averaging the 4x4 block of the HDR frame under the target
+// texel. The input is the scene before tonemapping, linear and not clamped to 1
+@fragment
+fn fs_threshold(in: VertexOutput) -> @location(0) vec4<f32> {
+    let texel = texel_size();
+
+    var color = vec3<f32>(0.0);
+    for (var i = 0; i < 4; i++) {
+        let offset = vec2<f32>(f32(i & 1), f32(i >> 1u)) * 2.0 - 1.0;
+        color += textureSampleLevel(t_input, s_input, in.tex_coords + offset * texel, 0.0).rgb * 0.25;
+    }
+
+    let brightness = max(color.r, max(color.g, color.b));
+    let contribution = max(brightness - bloom.threshold, 0.0) / max(brightness, 0.0001);
+    return vec4<f32>(color * contribution, 1.0);
+}
+
+// 9 taps gaussian, read in 5 linearly filtered samples, indexed arrays have to be variables
+fn blur(tex_coords: vec2<f32>, direction: vec2<f32>) -> vec4<f32> {
+    var offsets = array<f32, 3>(0.0, 1.3846153846, 3.2307692308);
+    var weights = array<f32, 3>(0.2270270270, 0.3162162162, 0.0702702703);
+
+    let step = direction / vec2<f32>(textureDimensions(t_input));
+
+    var color = textureSampleLevel(t_input, s_input, tex_coords, 0.0).rgb * weights[0];
+    for (var i = 1; i < 3; i++) {
+        color += textureSampleLevel(t_input, s_input, tex_coords + step * offsets[i], 0.0).rgb * weights[i];
+        color += textureSampleLevel(t_input, s_input, tex_coords - step * offsets[i], 0.0).rgb * weights[i];
+    }
+    return vec4<f32>(color, 1.0);
+}
+
+@fragment
+fn fs_blur_horizontal(in: VertexOutput) -> @location(0) vec4<f32> {
+    return blur(in.tex_coords, vec2<f32>(1.0, 0.0));
+}
+
+@fragment
+fn fs_blur_vertical(in: VertexOutput) -> @location(0) vec4<f32> {
+    return blur(in.tex_coords, vec2<f32>(0.0, 1.0));
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = sample_input(in.tex_coords);
+    let glow = textureSampleLevel(t_bloom, s_bloom, in.tex_coords, 0.0).rgb;
+    return output(vec4<f32>(color.rgb + glow * bloom.intensity, color.a));
+}
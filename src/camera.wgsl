@@ -0,0 +1,6 @@
+// Layout of `view::FlatView`
+
+struct Camera {
+    view_pos: vec4<f32>,
+    view_proj: mat4x4<f32>,
+}
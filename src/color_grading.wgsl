@@ -0,0 +1,21 @@
+// Remaps the colours of the frame through a 3D lookup table
+
+struct ColorGrading {
+    // blend between the original and the graded colour
+    intensity: f32,
+};
+@group(1) @binding(0) var<uniform> color_grading: ColorGrading;
+@group(1) @binding(1) var t_lut: texture_3d<f32>;
+@group(1) @binding(2) var s_lut: sampler;
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = sample_input(in.tex_coords);
+
+    // the table is indexed by gamma encoded colours, at the centre of its texels
+    let size = f32(textureDimensions(t_lut).x);
+    let coords = linear_to_srgb(clamp(color.rgb, vec3<f32>(0.0), vec3<f32>(1.0))) * (size - 1.0) / size + 0.5 / size;
+    let graded = srgb_to_linear(textureSampleLevel(t_lut, s_lut, coords, 0.0).rgb);
+
+    return output(vec4<f32>(mix(color.rgb, graded, color_grading.intensity), color.a));
+}
@@ -0,0 +1,495 @@
+use std::io::Read;
+
+use anyhow::*;
+
+const KTX2_MAGIC: [u8; 12] = [
+    0xab, 0x4b, 0x54, 0x58, 0x20, 0x32, 0x30, 0xbb, 0x0d, 0x0a, 0x1a, 0x0a,
+];
+const DDS_MAGIC: [u8; 4] = *b"DDS ";
+
+/// Texture read from a KTX2 or DDS container, levels already in the GPU format
+pub struct CompressedImage {
+    pub format: wgpu::TextureFormat,
+    pub width: u32,
+    pub height: u32,
+    /// Mip levels from the largest, tightly packed rows of blocks
+    pub levels: Vec<Vec<u8>>,
+}
+
+/// True when `bytes` start like a KTX2 or DDS container
+pub fn is_container(bytes: &[u8]) -> bool {
+    bytes.starts_with(&KTX2_MAGIC) || bytes.starts_with(&DDS_MAGIC)
+}
+
+/// Block compression families, from the most to the least preferred
+const FAMILIES: [(&str, wgpu::Features); 3] = [
+    ("astc", wgpu::Features::TEXTURE_COMPRESSION_ASTC),
+    ("bc", wgpu::Features::TEXTURE_COMPRESSION_BC),
+    ("etc2", wgpu::Features::TEXTURE_COMPRESSION_ETC2),
+];
+
+/// Features worth requesting from the adapter for compressed textures
+pub fn features() -> wgpu::Features {
+    FAMILIES
+        .iter()
+        .fold(wgpu::Features::empty(), |features, (_, family)| {
+            features | *family
+        })
+}
+
+/// Files to try for a container texture, `name.ktx2` is looked up as `name.astc.ktx2`,
+/// `name.bc.ktx2` and `name.etc2.ktx2` for the families the device supports, then as is
+pub fn variants(file_name: &str, features: wgpu::Features) -> Vec<String> {
+    let mut variants = Vec::new();
+
+    if let Some((stem, extension)) = file_name.rsplit_once('.') {
+        if matches!(extension.to_lowercase().as_str(), "ktx2" | "dds") {
+            for (family, required) in FAMILIES {
+                if features.contains(required) {
+                    variants.push(format!("{}.{}.{}", stem, family, extension));
+                }
+            }
+        }
+    }
+
+    variants.push(file_name.to_string());
+    variants
+}
+
+/// Reads a container, Basis Universal textures are transcoded for the device `features`
+pub fn decode(bytes: &[u8], features: wgpu::Features) -> Result<CompressedImage> {
+    if bytes.starts_with(&KTX2_MAGIC) {
+        decode_ktx2(bytes, features)
+    } else if bytes.starts_with(&DDS_MAGIC) {
+        decode_dds(bytes)
+    } else {
+        bail!("not a KTX2 or DDS container")
+    }
+}
+
+/// Size in bytes of a mip level, or None for formats without a fixed block size
+fn level_size(format: wgpu::TextureFormat, width: u32, height: u32, level: u32) -> Option<usize> {
+    let (block_width, block_height) = format.block_dimensions();
+    let block_size = format.block_size(None)?;
+
+    let width = (width >> level).max(1).div_ceil(block_width);
+    let height = (height >> level).max(1).div_ceil(block_height);
+
+    Some((width * height * block_size) as usize)
+}
+
+/// Levels of a KTX2 file without their Zstandard supercompression
+pub(crate) fn ktx2_levels(reader: &ktx2::Reader<&[u8]>) -> Result<Vec<Vec<u8>>> {
+    use ktx2::SupercompressionScheme;
+
+    reader
+        .levels()
+        .map(|level| match reader.header().supercompression_scheme {
+            None => Ok(level.to_vec()),
+            Some(SupercompressionScheme::Zstandard) => {
+                let mut decoder = ruzstd::StreamingDecoder::new(level)
+                    .map_err(|e| anyhow!("invalid Zstandard level: {}", e))?;
+                let mut data = Vec::new();
+                decoder.read_to_end(&mut data)?;
+                Ok(data)
+            }
+            Some(scheme) => bail!("unsupported KTX2 supercompression {:?}", scheme),
+        })
+        .collect()
+}
+
+/// Basis Universal textures are transcoded with the C++ transcoder, which is only built natively
+fn transcode_basis(
+    reader: &ktx2::Reader<&[u8]>,
+    features: wgpu::Features,
+) -> Result<CompressedImage> {
+    cfg_if::cfg_if! {
+        if #[cfg(target_arch = "wasm32")] {
+            let _ = (reader, features);
+            bail!("Basis Universal textures are not transcoded on the web, export the texture as BC, ETC2 or ASTC")
+        } else {
+            crate::basis::transcode(reader, features)
+        }
+    }
+}
+
+fn decode_ktx2(bytes: &[u8], features: wgpu::Features) -> Result<CompressedImage> {
+    use ktx2::{Format, SupercompressionScheme};
+
+    let reader = ktx2::Reader::new(bytes).map_err(|e| anyhow!("invalid KTX2 file: {:?}", e))?;
+    let header = reader.header();
+
+    if header.pixel_depth > 1 || header.layer_count > 1 || header.face_count > 1 {
+        bail!("only 2D KTX2 textures are supported");
+    }
+
+    if header.supercompression_scheme == Some(SupercompressionScheme::BasisLZ) {
+        return transcode_basis(&reader, features);
+    }
+
+    let format = match header.format {
+        Some(Format::R8G8B8A8_UNORM) => wgpu::TextureFormat::Rgba8Unorm,
+        Some(Format::R8G8B8A8_SRGB) => wgpu::TextureFormat::Rgba8UnormSrgb,
+        Some(Format::B8G8R8A8_UNORM) => wgpu::TextureFormat::Bgra8Unorm,
+        Some(Format::B8G8R8A8_SRGB) => wgpu::TextureFormat::Bgra8UnormSrgb,
+        Some(Format::BC1_RGBA_UNORM_BLOCK) => wgpu::TextureFormat::Bc1RgbaUnorm,
+        Some(Format::BC1_RGBA_SRGB_BLOCK) => wgpu::TextureFormat::Bc1RgbaUnormSrgb,
+        Some(Format::BC3_UNORM_BLOCK) => wgpu::TextureFormat::Bc3RgbaUnorm,
+        Some(Format::BC3_SRGB_BLOCK) => wgpu::TextureFormat::Bc3RgbaUnormSrgb,
+        Some(Format::BC5_UNORM_BLOCK) => wgpu::TextureFormat::Bc5RgUnorm,
+        Some(Format::BC7_UNORM_BLOCK) => wgpu::TextureFormat::Bc7RgbaUnorm,
+        Some(Format::BC7_SRGB_BLOCK) => wgpu::TextureFormat::Bc7RgbaUnormSrgb,
+        Some(Format::ETC2_R8G8B8_UNORM_BLOCK) => wgpu::TextureFormat::Etc2Rgb8Unorm,
+        Some(Format::ETC2_R8G8B8_SRGB_BLOCK) => wgpu::TextureFormat::Etc2Rgb8UnormSrgb,
+        Some(Format::ETC2_R8G8B8A8_UNORM_BLOCK) => wgpu::TextureFormat::Etc2Rgba8Unorm,
+        Some(Format::ETC2_R8G8B8A8_SRGB_BLOCK) => wgpu::TextureFormat::Etc2Rgba8UnormSrgb,
+        Some(Format::EAC_R11G11_UNORM_BLOCK) => wgpu::TextureFormat::EacRg11Unorm,
+        Some(Format::ASTC_4x4_UNORM_BLOCK) => wgpu::TextureFormat::Astc {
+            block: wgpu::AstcBlock::B4x4,
+            channel: wgpu::AstcChannel::Unorm,
+        },
+        Some(Format::ASTC_4x4_SRGB_BLOCK) => wgpu::TextureFormat::Astc {
+            block: wgpu::AstcBlock::B4x4,
+            channel: wgpu::AstcChannel::UnormSrgb,
+        },
+        Some(Format::ASTC_6x6_UNORM_BLOCK) => wgpu::TextureFormat::Astc {
+            block: wgpu::AstcBlock::B6x6,
+            channel: wgpu::AstcChannel::Unorm,
+        },
+        Some(Format::ASTC_6x6_SRGB_BLOCK) => wgpu::TextureFormat::Astc {
+            block: wgpu::AstcBlock::B6x6,
+            channel: wgpu::AstcChannel::UnormSrgb,
+        },
+        Some(Format::ASTC_8x8_UNORM_BLOCK) => wgpu::TextureFormat::Astc {
+            block: wgpu::AstcBlock::B8x8,
+            channel: wgpu::AstcChannel::Unorm,
+        },
+        Some(Format::ASTC_8x8_SRGB_BLOCK) => wgpu::TextureFormat::Astc {
+            block: wgpu::AstcBlock::B8x8,
+            channel: wgpu::AstcChannel::UnormSrgb,
+        },
+        // UASTC leaves the format undefined
+        None => return transcode_basis(&reader, features),
+        Some(format) => bail!("unsupported KTX2 format {:?}", format),
+    };
+
+    let levels = ktx2_levels(&reader)?;
+
+    Ok(CompressedImage {
+        format,
+        width: header.pixel_width,
+        height: header.pixel_height,
+        levels,
+    })
+}
+
+fn decode_dds(bytes: &[u8]) -> Result<CompressedImage> {
+    use ddsfile::{D3DFormat, DxgiFormat};
+
+    let dds = ddsfile::Dds::read(bytes)?;
+
+    if dds.get_depth() > 1 || dds.get_num_array_layers() > 1 {
+        bail!("only 2D DDS textures are supported");
+    }
+
+    let format = match (dds.get_dxgi_format(), dds.get_d3d_format()) {
+        (Some(DxgiFormat::R8G8B8A8_UNorm), _) | (_, Some(D3DFormat::A8B8G8R8)) => {
+            wgpu::TextureFormat::Rgba8Unorm
+        }
+        (Some(DxgiFormat::R8G8B8A8_UNorm_sRGB), _) => wgpu::TextureFormat::Rgba8UnormSrgb,
+        (Some(DxgiFormat::B8G8R8A8_UNorm), _) | (_, Some(D3DFormat::A8R8G8B8)) => {
+            wgpu::TextureFormat::Bgra8Unorm
+        }
+        (Some(DxgiFormat::B8G8R8A8_UNorm_sRGB), _) => wgpu::TextureFormat::Bgra8UnormSrgb,
+        (Some(DxgiFormat::BC1_UNorm), _) | (_, Some(D3DFormat::DXT1)) => {
+            wgpu::TextureFormat::Bc1RgbaUnorm
+        }
+        (Some(DxgiFormat::BC1_UNorm_sRGB), _) => wgpu::TextureFormat::Bc1RgbaUnormSrgb,
+        (Some(DxgiFormat::BC2_UNorm), _) | (_, Some(D3DFormat::DXT3)) => {
+            wgpu::TextureFormat::Bc2RgbaUnorm
+        }
+        (Some(DxgiFormat::BC2_UNorm_sRGB), _) => wgpu::TextureFormat::Bc2RgbaUnormSrgb,
+        (Some(DxgiFormat::BC3_UNorm), _) | (_, Some(D3DFormat::DXT5)) => {
+            wgpu::TextureFormat::Bc3RgbaUnorm
+        }
+        (Some(DxgiFormat::BC3_UNorm_sRGB), _) => wgpu::TextureFormat::Bc3RgbaUnormSrgb,
+        (Some(DxgiFormat::BC4_UNorm), _) => wgpu::TextureFormat::Bc4RUnorm,
+        (Some(DxgiFormat::BC5_UNorm), _) => wgpu::TextureFormat::Bc5RgUnorm,
+        (Some(DxgiFormat::BC7_UNorm), _) => wgpu::TextureFormat::Bc7RgbaUnorm,
+        (Some(DxgiFormat::BC7_UNorm_sRGB), _) => wgpu::TextureFormat::Bc7RgbaUnormSrgb,
+        (Some(dxgi), _) => bail!("unsupported DDS format {:?}", dxgi),
+        (None, d3d) => bail!("unsupported DDS format {:?}", d3d),
+    };
+
+    let width = dds.get_width();
+    let height = dds.get_height();
+
+    // all levels of the first layer are stored one after another
+    let mut data = dds.get_data(0).context("DDS file is truncated")?;
+    let mut levels = Vec::new();
+    for level in 0..dds.get_num_mipmap_levels() {
+        let size = level_size(format, width, height, level).expect("DDS formats have a block size");
+        if data.len() < size {
+            bail!("DDS file is truncated at mip level {}", level);
+        }
+
+        let (level, rest) = data.split_at(size);
+        levels.push(level.to_vec());
+        data = rest;
+    }
+
+    Ok(CompressedImage {
+        format,
+        width,
+        height,
+        levels,
+    })
+}
+
+impl CompressedImage {
+    /// Checks the image can be uploaded as a texture of `format`'s colour space on `device`
+    pub fn validate(&self, features: wgpu::Features) -> Result<()> {
+        let required = self.format.required_features();
+        if !features.contains(required) {
+            bail!("{:?} textures need the {:?} feature", self.format, required);
+        }
+
+        let (block_width, block_height) = self.format.block_dimensions();
+        if !self.width.is_multiple_of(block_width) || !self.height.is_multiple_of(block_height) {
+            bail!(
+                "{}x{} is not a multiple of the {}x{} blocks of {:?}",
+                self.width,
+                self.height,
+                block_width,
+                block_height,
+                self.format
+            );
+        }
+
+        for (level, data) in self.levels.iter().enumerate() {
+            let expected = level_size(self.format, self.width, self.height, level as u32);
+            if Some(data.len()) != expected {
+                bail!("mip level {} has an unexpected size", level);
+            }
+        }
+
+        if self.levels.is_empty() {
+            bail!("texture has no mip levels");
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const R8G8B8A8_UNORM: u32 = 37;
+    const BC1_RGBA_UNORM_BLOCK: u32 = 133;
+    const ZSTANDARD: u32 = 2;
+
+    fn push(bytes: &mut Vec<u8>, value: u64, size: usize) {
+        bytes.extend_from_slice(&value.to_le_bytes()[..size]);
+    }
+
+    /// 2D KTX2 file without data format descriptor, which the decoder does not read for
+    /// formats it knows
+    fn ktx2(format: u32, width: u32, height: u32, scheme: u32, levels: &[Vec<u8>]) -> Vec<u8> {
+        let mut file = KTX2_MAGIC.to_vec();
+        for value in [
+            format,
+            1,
+            width,
+            height,
+            0,
+            0,
+            1,
+            levels.len() as u32,
+            scheme,
+        ] {
+            push(&mut file, value as u64, 4);
+        }
+        // data format descriptor, key/values and supercompression global data
+        file.extend([0; 32]);
+
+        let mut offset = 80 + levels.len() * 24;
+        for level in levels {
+            push(&mut file, offset as u64, 8);
+            push(&mut file, level.len() as u64, 8);
+            push(&mut file, level.len() as u64, 8);
+            offset += level.len();
+        }
+        file.extend(levels.concat());
+        file
+    }
+
+    /// Zstandard frame holding `data` in a single raw block
+    fn zstd(data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x28, 0xb5, 0x2f, 0xfd];
+        // single segment, the content size in one byte
+        frame.extend([0x20, data.len() as u8]);
+        // last block, raw
+        push(&mut frame, 1 | (data.len() as u64) << 3, 3);
+        frame.extend(data);
+        frame
+    }
+
+    fn dds(format: ddsfile::DxgiFormat, width: u32, height: u32, levels: u32) -> ddsfile::Dds {
+        ddsfile::Dds::new_dxgi(ddsfile::NewDxgiParams {
+            height,
+            width,
+            depth: None,
+            format,
+            mipmap_levels: Some(levels),
+            array_layers: None,
+            caps2: None,
+            is_cubemap: false,
+            resource_dimension: ddsfile::D3D10ResourceDimension::Texture2D,
+            alpha_mode: ddsfile::AlphaMode::Straight,
+        })
+        .unwrap()
+    }
+
+    fn write_dds(dds: &ddsfile::Dds) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        dds.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn variants_follow_the_family_order() {
+        let features = wgpu::Features::TEXTURE_COMPRESSION_ETC2
+            | wgpu::Features::TEXTURE_COMPRESSION_ASTC
+            | wgpu::Features::TEXTURE_COMPRESSION_BC;
+
+        assert_eq!(
+            variants("textures/wall.ktx2", features),
+            [
+                "textures/wall.astc.ktx2",
+                "textures/wall.bc.ktx2",
+                "textures/wall.etc2.ktx2",
+                "textures/wall.ktx2",
+            ]
+        );
+        assert_eq!(
+            variants("wall.DDS", wgpu::Features::TEXTURE_COMPRESSION_BC),
+            ["wall.bc.DDS", "wall.DDS"]
+        );
+        assert_eq!(variants("wall.png", features), ["wall.png"]);
+        assert_eq!(
+            variants("wall.ktx2", wgpu::Features::empty()),
+            ["wall.ktx2"]
+        );
+    }
+
+    #[test]
+    fn level_sizes_round_up_to_blocks() {
+        let bc1 = wgpu::TextureFormat::Bc1RgbaUnorm;
+
+        assert_eq!(level_size(bc1, 8, 8, 0), Some(32));
+        // 4x4, then levels smaller than a block still take a whole one
+        assert_eq!(level_size(bc1, 8, 8, 1), Some(8));
+        assert_eq!(level_size(bc1, 8, 8, 3), Some(8));
+        assert_eq!(
+            level_size(wgpu::TextureFormat::Rgba8Unorm, 5, 3, 1),
+            Some(2 * 4)
+        );
+        assert_eq!(level_size(wgpu::TextureFormat::Depth24Plus, 4, 4, 0), None);
+    }
+
+    #[test]
+    fn decodes_ktx2_levels() {
+        let levels = vec![vec![1; 4 * 4 * 4], vec![2; 2 * 2 * 4], vec![3; 4]];
+        let file = ktx2(R8G8B8A8_UNORM, 4, 4, 0, &levels);
+
+        assert!(is_container(&file));
+        let image = decode(&file, wgpu::Features::empty()).unwrap();
+        assert_eq!(image.format, wgpu::TextureFormat::Rgba8Unorm);
+        assert_eq!((image.width, image.height), (4, 4));
+        assert_eq!(image.levels, levels);
+        image.validate(wgpu::Features::empty()).unwrap();
+    }
+
+    #[test]
+    fn decodes_zstandard_ktx2_levels() {
+        let levels = [vec![7; 4 * 4 * 4], vec![8; 2 * 2 * 4]];
+        let compressed = levels.iter().map(|level| zstd(level)).collect::<Vec<_>>();
+        let file = ktx2(R8G8B8A8_UNORM, 4, 4, ZSTANDARD, &compressed);
+
+        let image = decode(&file, wgpu::Features::empty()).unwrap();
+        assert_eq!(image.levels, levels);
+    }
+
+    #[test]
+    fn rejects_truncated_ktx2() {
+        let file = ktx2(BC1_RGBA_UNORM_BLOCK, 8, 8, 0, &[vec![0; 32]]);
+
+        let error = decode(&file[..file.len() - 1], wgpu::Features::empty())
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("invalid KTX2 file"), "{}", error);
+    }
+
+    #[test]
+    fn decodes_dds_levels() {
+        let mut dds = dds(ddsfile::DxgiFormat::BC1_UNorm, 8, 8, 2);
+        dds.data = (0..40).collect();
+
+        let image = decode(&write_dds(&dds), wgpu::Features::empty()).unwrap();
+        assert_eq!(image.format, wgpu::TextureFormat::Bc1RgbaUnorm);
+        assert_eq!(
+            image.levels,
+            [(0..32).collect::<Vec<_>>(), (32..40).collect()]
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_dds() {
+        let mut dds = dds(ddsfile::DxgiFormat::BC1_UNorm, 8, 8, 2);
+        dds.data.truncate(36);
+
+        let error = decode(&write_dds(&dds), wgpu::Features::empty())
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("truncated"), "{}", error);
+    }
+
+    #[test]
+    fn validates_against_the_device() {
+        let image = CompressedImage {
+            format: wgpu::TextureFormat::Bc1RgbaUnorm,
+            width: 8,
+            height: 8,
+            levels: vec![vec![0; 32], vec![0; 8]],
+        };
+        let bc = wgpu::Features::TEXTURE_COMPRESSION_BC;
+
+        image.validate(bc).unwrap();
+        assert!(image.validate(wgpu::Features::empty()).is_err());
+
+        let odd = CompressedImage { width: 6, ..image };
+        assert!(odd
+            .validate(bc)
+            .unwrap_err()
+            .to_string()
+            .contains("multiple"));
+
+        let short = CompressedImage {
+            width: 8,
+            levels: vec![vec![0; 32], vec![0; 4]],
+            ..odd
+        };
+        assert!(short
+            .validate(bc)
+            .unwrap_err()
+            .to_string()
+            .contains("mip level 1"));
+
+        let empty = CompressedImage {
+            levels: Vec::new(),
+            ..short
+        };
+        assert!(empty.validate(bc).is_err());
+    }
+}
@@ -0,0 +1,506 @@
+use wgpu::util::DeviceExt;
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::reflection;
+use crate::{mipmaps, texture};
+
+/// Width of the irradiance cube faces, irradiance varies slowly so they can be tiny
+const IRRADIANCE_SIZE: u32 = 32;
+/// Width of the first prefiltered level, for a roughness of 0
+const PREFILTERED_SIZE: u32 = 128;
+/// Prefiltered levels, the roughness goes from 0 to 1 over them
+const PREFILTERED_LEVELS: u32 = 5;
+const BRDF_LUT_SIZE: u32 = 128;
+
+/// Image the environment is baked from
+pub enum Source {
+    /// Panorama with the longitude along x and the latitude along y
+    Equirectangular(texture::Texture),
+    /// Faces in +x, -x, +y, -y, +z, -z order
+    Faces(Vec<texture::Texture>),
+}
+
+/// Surroundings of the scene, drawn as the skybox and lighting the models through the
+/// irradiance and prefiltered maps baked from it
+pub struct Environment {
+    pub intensity: f32,
+    pub skybox: texture::Texture,
+    /// Diffuse light arriving around each normal
+    pub irradiance: texture::Texture,
+    /// Specular reflections, one level per roughness
+    pub prefiltered: texture::Texture,
+    /// Scale and bias of the specular reflectance by view angle and roughness
+    pub brdf_lut: texture::Texture,
+    pub bind_group_layout: wgpu::BindGroupLayout,
+    pub bind_group: wgpu::BindGroup,
+}
+
+pub struct EnvironmentBuilder<R, I> {
+    resolution: R,
+    intensity: I,
+}
+
+impl<R, I> EnvironmentBuilder<R, I> {
+    /// Width of the skybox cube faces in texels
+    pub fn resolution(self, resolution: u32) -> EnvironmentBuilder<u32, I> {
+        EnvironmentBuilder {
+            resolution,
+            intensity: self.intensity,
+        }
+    }
+
+    /// Scales the light the environment casts on the models
+    pub fn intensity(self, intensity: f32) -> EnvironmentBuilder<R, f32> {
+        EnvironmentBuilder {
+            resolution: self.resolution,
+            intensity,
+        }
+    }
+}
+
+impl EnvironmentBuilder<u32, f32> {
+    /// Bakes the maps from `source` on the GPU
+    pub fn finalize(
+        self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        source: &Source,
+    ) -> Environment {
+        let baker = Baker::new(device);
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Environment Encoder"),
+        });
+
+        let skybox = texture::Texture::create_cube_texture(
+            device,
+            self.resolution,
+            mipmaps::mip_level_count(self.resolution, self.resolution),
+            texture::Texture::HDR_FORMAT,
+            "skybox_texture",
+        );
+
+        // each level is read from the source level of about the same detail, rather than from the
+        // level above, as GL backends can not sample views starting past the first level
+        for mip_level in 0..skybox.texture.mip_level_count() {
+            let level_size = (self.resolution >> mip_level).max(1) as f32;
+
+            match source {
+                Source::Equirectangular(panorama) => {
+                    let bind_group = baker.flat_source(&panorama.view);
+                    // the panorama wraps around the four side faces
+                    let face_size = panorama.texture.width() as f32 / 4.0;
+
+                    for face in 0..6 {
+                        baker.render(
+                            &mut encoder,
+                            &baker.equirectangular_pipeline,
+                            Some(&bind_group),
+                            &skybox.texture,
+                            face,
+                            mip_level,
+                            FlatParams {
+                                lod: (face_size / level_size).log2().max(0.0),
+                                ..Default::default()
+                            },
+                        );
+                    }
+                }
+                Source::Faces(faces) => {
+                    for (face, texture) in faces.iter().enumerate() {
+                        let bind_group = baker.flat_source(&texture.view);
+                        let face_size = texture.texture.width() as f32;
+
+                        baker.render(
+                            &mut encoder,
+                            &baker.face_pipeline,
+                            Some(&bind_group),
+                            &skybox.texture,
+                            face as u32,
+                            mip_level,
+                            FlatParams {
+                                lod: (face_size / level_size).log2().max(0.0),
+                                ..Default::default()
+                            },
+                        );
+                    }
+                }
+            }
+        }
+
+        let source_bind_group = baker.cube_source(&skybox.view);
+
+        let irradiance = texture::Texture::create_cube_texture(
+            device,
+            IRRADIANCE_SIZE,
+            1,
+            texture::Texture::HDR_FORMAT,
+            "irradiance_texture",
+        );
+
+        for face in 0..6 {
+            baker.render(
+                &mut encoder,
+                &baker.irradiance_pipeline,
+                Some(&source_bind_group),
+                &irradiance.texture,
+                face,
+                0,
+                FlatParams {
+                    // read a level about as detailed as the result
+                    lod: (self.resolution as f32 / IRRADIANCE_SIZE as f32).log2(),
+                    ..Default::default()
+                },
+            );
+        }
+
+        let prefiltered = texture::Texture::create_cube_texture(
+            device,
+            PREFILTERED_SIZE,
+            PREFILTERED_LEVELS,
+            texture::Texture::HDR_FORMAT,
+            "prefiltered_texture",
+        );
+
+        for mip_level in 0..PREFILTERED_LEVELS {
+            for face in 0..6 {
+                baker.render(
+                    &mut encoder,
+                    &baker.prefilter_pipeline,
+                    Some(&source_bind_group),
+                    &prefiltered.texture,
+                    face,
+                    mip_level,
+                    FlatParams {
+                        roughness: mip_level as f32 / (PREFILTERED_LEVELS - 1) as f32,
+                        source_size: self.resolution as f32,
+                        ..Default::default()
+                    },
+                );
+            }
+        }
+
+        let brdf_lut = texture::Texture::create_render_target(
+            device,
+            BRDF_LUT_SIZE,
+            BRDF_LUT_SIZE,
+            texture::Texture::HDR_FORMAT,
+            "brdf_lut_texture",
+        );
+
+        baker.render(
+            &mut encoder,
+            &baker.brdf_pipeline,
+            None,
+            &brdf_lut.texture,
+            0,
+            0,
+            FlatParams::default(),
+        );
+
+        queue.submit([encoder.finish()]);
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Skybox bind group layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::Cube,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Skybox bind group"),
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&skybox.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&skybox.sampler),
+                },
+            ],
+        });
+
+        Environment {
+            intensity: self.intensity,
+            skybox,
+            irradiance,
+            prefiltered,
+            brdf_lut,
+            bind_group_layout,
+            bind_group,
+        }
+    }
+}
+
+impl Environment {
+    pub fn build() -> EnvironmentBuilder<Option<u32>, Option<f32>> {
+        EnvironmentBuilder {
+            resolution: None,
+            intensity: None,
+        }
+    }
+
+    /// Prefiltered level of the roughest reflections
+    pub fn max_reflection_lod(&self) -> f32 {
+        (self.prefiltered.texture.mip_level_count() - 1) as f32
+    }
+}
+
+/// Pipelines rendering the faces of the environment maps
+struct Baker<'a> {
+    device: &'a wgpu::Device,
+    params_layout: wgpu::BindGroupLayout,
+    flat_layout: wgpu::BindGroupLayout,
+    cube_layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+    equirectangular_pipeline: wgpu::RenderPipeline,
+    face_pipeline: wgpu::RenderPipeline,
+    irradiance_pipeline: wgpu::RenderPipeline,
+    prefilter_pipeline: wgpu::RenderPipeline,
+    brdf_pipeline: wgpu::RenderPipeline,
+}
+
+impl<'a> Baker<'a> {
+    fn new(device: &'a wgpu::Device) -> Self {
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Environment Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("environment.wgsl").into()),
+        });
+
+        let params_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Environment params bind group layout"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+        });
+
+        let source_layout = |label, binding, view_dimension| {
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some(label),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                            view_dimension,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                ],
+            })
+        };
+
+        // 2D sources are read from binding 0 and cube ones from binding 2
+        let flat_layout = source_layout(
+            "Environment flat source bind group layout",
+            0,
+            wgpu::TextureViewDimension::D2,
+        );
+        let cube_layout = source_layout(
+            "Environment cube source bind group layout",
+            2,
+            wgpu::TextureViewDimension::Cube,
+        );
+
+        let pipeline = |entry_point, bind_group_layouts: &[&wgpu::BindGroupLayout]| {
+            let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Environment Pipeline Layout"),
+                bind_group_layouts,
+                push_constant_ranges: &[],
+            });
+
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some(entry_point),
+                layout: Some(&layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vs_main",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point,
+                    targets: &[Some(texture::Texture::HDR_FORMAT.into())],
+                }),
+                primitive: wgpu::PrimitiveState {
+                    topology: wgpu::PrimitiveTopology::TriangleList,
+                    ..Default::default()
+                },
+                depth_stencil: None,
+                multisample: wgpu::MultisampleState::default(),
+                multiview: None,
+            })
+        };
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Environment Sampler"),
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        Self {
+            equirectangular_pipeline: pipeline(
+                "fs_equirectangular",
+                &[&params_layout, &flat_layout],
+            ),
+            face_pipeline: pipeline("fs_face", &[&params_layout, &flat_layout]),
+            irradiance_pipeline: pipeline("fs_irradiance", &[&params_layout, &cube_layout]),
+            prefilter_pipeline: pipeline("fs_prefilter", &[&params_layout, &cube_layout]),
+            brdf_pipeline: pipeline("fs_brdf", &[&params_layout]),
+            device,
+            params_layout,
+            flat_layout,
+            cube_layout,
+            sampler,
+        }
+    }
+
+    fn flat_source(&self, view: &wgpu::TextureView) -> wgpu::BindGroup {
+        self.source_bind_group(&self.flat_layout, 0, view)
+    }
+
+    fn cube_source(&self, view: &wgpu::TextureView) -> wgpu::BindGroup {
+        self.source_bind_group(&self.cube_layout, 2, view)
+    }
+
+    fn source_bind_group(
+        &self,
+        layout: &wgpu::BindGroupLayout,
+        binding: u32,
+        view: &wgpu::TextureView,
+    ) -> wgpu::BindGroup {
+        self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Environment source bind group"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding,
+                    resource: wgpu::BindingResource::TextureView(view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+            ],
+        })
+    }
+
+    /// Renders a layer of a level of `target` with one of the pipelines
+    #[allow(clippy::too_many_arguments)]
+    fn render(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        pipeline: &wgpu::RenderPipeline,
+        source: Option<&wgpu::BindGroup>,
+        target: &wgpu::Texture,
+        face: u32,
+        mip_level: u32,
+        params: FlatParams,
+    ) {
+        let buffer = self
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Environment Params Buffer"),
+                contents: bytemuck::cast_slice(&[FlatParams { face, ..params }]),
+                usage: wgpu::BufferUsages::UNIFORM,
+            });
+
+        let params_bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Environment params bind group"),
+            layout: &self.params_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: buffer.as_entire_binding(),
+            }],
+        });
+
+        let view = target.create_view(&wgpu::TextureViewDescriptor {
+            label: Some("Environment Face View"),
+            dimension: Some(wgpu::TextureViewDimension::D2),
+            base_mip_level: mip_level,
+            mip_level_count: Some(1),
+            base_array_layer: face,
+            array_layer_count: Some(1),
+            ..Default::default()
+        });
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Environment Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: &view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+
+        render_pass.set_pipeline(pipeline);
+        render_pass.set_bind_group(0, &params_bind_group, &[]);
+        if let Some(source) = source {
+            render_pass.set_bind_group(1, source, &[]);
+        }
+        render_pass.draw(0..3, 0..1);
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct FlatParams {
+    face: u32,
+    roughness: f32,
+    lod: f32,
+    source_size: f32,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl FlatParams {
+    pub(crate) fn host_layout() -> reflection::HostLayout {
+        reflection::host_layout!(FlatParams {
+            face,
+            roughness,
+            lod,
+            source_size,
+        })
+    }
+}
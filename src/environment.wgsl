@@ -0,0 +1,200 @@
+// Bakes the environment maps, each pass renders one face of a cube level or the whole BRDF lookup table
+
+struct Params {
+    // cube layer rendered, in +x, -x, +y, -y, +z, -z order
+    face: u32,
+    roughness: f32,
+    // source level read, about as detailed as the target
+    lod: f32,
+    // width of the first source level, for the solid angle of its texels
+    source_size: f32,
+}
+
+@group(0) @binding(0) var<uniform> params: Params;
+
+@group(1) @binding(0) var t_source: texture_2d<f32>;
+@group(1) @binding(1) var s_source: sampler;
+@group(1) @binding(2) var t_source_cube: texture_cube<f32>;
+
+const PI: f32 = 3.14159265;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+};
+
+// a single triangle covering the whole target
+@vertex
+fn vs_main(@builtin(vertex_index) vertex_index: u32) -> VertexOutput {
+    let x = f32((vertex_index << 1u) & 2u);
+    let y = f32(vertex_index & 2u);
+
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(x * 2.0 - 1.0, 1.0 - y * 2.0, 0.0, 1.0);
+    out.tex_coords = vec2<f32>(x, y);
+    return out;
+}
+
+// Direction through a texel of a cube face, in the layer order and orientation wgpu samples cubes with
+fn face_direction(face: u32, tex_coords: vec2<f32>) -> vec3<f32> {
+    let p = tex_coords * 2.0 - 1.0;
+
+    switch face {
+        case 0u: { return normalize(vec3<f32>(1.0, -p.y, -p.x)); }
+        case 1u: { return normalize(vec3<f32>(-1.0, -p.y, p.x)); }
+        case 2u: { return normalize(vec3<f32>(p.x, 1.0, p.y)); }
+        case 3u: { return normalize(vec3<f32>(p.x, -1.0, -p.y)); }
+        case 4u: { return normalize(vec3<f32>(p.x, -p.y, 1.0)); }
+        default: { return normalize(vec3<f32>(-p.x, -p.y, -1.0)); }
+    }
+}
+
+// Orthonormal basis around `n`, to take tangent space samples into world space
+fn tangent_to_world(n: vec3<f32>, v: vec3<f32>) -> vec3<f32> {
+    var up = vec3<f32>(0.0, 1.0, 0.0);
+    if abs(n.y) > 0.999 {
+        up = vec3<f32>(1.0, 0.0, 0.0);
+    }
+    let tangent = normalize(cross(up, n));
+    let bitangent = cross(n, tangent);
+    return tangent * v.x + bitangent * v.y + n * v.z;
+}
+
+// Van der Corput sequence by reversing the bits, without reverseBits for WebGL
+fn radical_inverse(i: u32) -> f32 {
+    var bits = (i << 16u) | (i >> 16u);
+    bits = ((bits & 0x55555555u) << 1u) | ((bits & 0xAAAAAAAAu) >> 1u);
+    bits = ((bits & 0x33333333u) << 2u) | ((bits & 0xCCCCCCCCu) >> 2u);
+    bits = ((bits & 0x0F0F0F0Fu) << 4u) | ((bits & 0xF0F0F0F0u) >> 4u);
+    bits = ((bits & 0x00FF00FFu) << 8u) | ((bits & 0xFF00FF00u) >> 8u);
+    return f32(bits) * 2.3283064365386963e-10;
+}
+
+fn hammersley(i: u32, count: u32) -> vec2<f32> {
+    return vec2<f32>(f32(i) / f32(count), radical_inverse(i));
+}
+
+// Half vector around `n` distributed like the GGX lobe of `roughness`
+fn importance_sample_ggx(xi: vec2<f32>, n: vec3<f32>, roughness: f32) -> vec3<f32> {
+    let alpha = roughness * roughness;
+    let phi = 2.0 * PI * xi.x;
+    let cos_theta = sqrt((1.0 - xi.y) / (1.0 + (alpha * alpha - 1.0) * xi.y));
+    let sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+
+    return normalize(tangent_to_world(n, vec3<f32>(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta)));
+}
+
+fn distribution_ggx(n_dot_h: f32, roughness: f32) -> f32 {
+    let alpha2 = roughness * roughness * roughness * roughness;
+    let d = n_dot_h * n_dot_h * (alpha2 - 1.0) + 1.0;
+    return alpha2 / (PI * d * d);
+}
+
+@fragment
+fn fs_equirectangular(in: VertexOutput) -> @location(0) vec4<f32> {
+    let direction = face_direction(params.face, in.tex_coords);
+    let tex_coords = vec2<f32>(
+        atan2(direction.z, direction.x) / (2.0 * PI) + 0.5,
+        0.5 - asin(direction.y) / PI,
+    );
+    return vec4<f32>(textureSampleLevel(t_source, s_source, tex_coords, params.lod).rgb, 1.0);
+}
+
+@fragment
+fn fs_face(in: VertexOutput) -> @location(0) vec4<f32> {
+    return vec4<f32>(textureSampleLevel(t_source, s_source, in.tex_coords, params.lod).rgb, 1.0);
+}
+
+// Cosine weighted light arriving from the hemisphere around the direction, over pi
+@fragment
+fn fs_irradiance(in: VertexOutput) -> @location(0) vec4<f32> {
+    let normal = face_direction(params.face, in.tex_coords);
+
+    let phi_steps = 64;
+    let theta_steps = 16;
+
+    var irradiance = vec3<f32>(0.0);
+    for (var i = 0; i < phi_steps; i++) {
+        let phi = (f32(i) + 0.5) / f32(phi_steps) * 2.0 * PI;
+        for (var j = 0; j < theta_steps; j++) {
+            let theta = (f32(j) + 0.5) / f32(theta_steps) * 0.5 * PI;
+            let sample = vec3<f32>(sin(theta) * cos(phi), sin(theta) * sin(phi), cos(theta));
+            let color = textureSampleLevel(t_source_cube, s_source, tangent_to_world(normal, sample), params.lod).rgb;
+            irradiance += color * cos(theta) * sin(theta);
+        }
+    }
+
+    return vec4<f32>(PI * irradiance / f32(phi_steps * theta_steps), 1.0);
+}
+
+// Environment convolved with the GGX lobe of the roughness, assuming the view along the normal
+@fragment
+fn fs_prefilter(in: VertexOutput) -> @location(0) vec4<f32> {
+    let normal = face_direction(params.face, in.tex_coords);
+
+    if params.roughness == 0.0 {
+        return vec4<f32>(textureSampleLevel(t_source_cube, s_source, normal, 0.0).rgb, 1.0);
+    }
+
+    let sample_count = 128u;
+    // solid angle of a texel of the first level
+    let texel_solid_angle = 4.0 * PI / (6.0 * params.source_size * params.source_size);
+
+    var color = vec3<f32>(0.0);
+    var weight = 0.0;
+    for (var i = 0u; i < sample_count; i++) {
+        let half_dir = importance_sample_ggx(hammersley(i, sample_count), normal, params.roughness);
+        let light_dir = normalize(2.0 * dot(normal, half_dir) * half_dir - normal);
+        let n_dot_l = dot(normal, light_dir);
+
+        if n_dot_l > 0.0 {
+            // reading a level whose texels cover the solid angle of the sample filters out the fireflies
+            let n_dot_h = max(dot(normal, half_dir), 0.0);
+            let pdf = distribution_ggx(n_dot_h, params.roughness) / 4.0 + 0.0001;
+            let sample_solid_angle = 1.0 / (f32(sample_count) * pdf);
+            let lod = 0.5 * log2(sample_solid_angle / texel_solid_angle) + 1.0;
+
+            color += textureSampleLevel(t_source_cube, s_source, light_dir, max(lod, 0.0)).rgb * n_dot_l;
+            weight += n_dot_l;
+        }
+    }
+
+    return vec4<f32>(color / weight, 1.0);
+}
+
+// Scale and bias applied to F0 by the specular BRDF integrated over the hemisphere, by the
+// angle to the view along x and the roughness along y
+@fragment
+fn fs_brdf(in: VertexOutput) -> @location(0) vec4<f32> {
+    let n_dot_v = in.tex_coords.x;
+    let roughness = in.tex_coords.y;
+
+    let view_dir = vec3<f32>(sqrt(1.0 - n_dot_v * n_dot_v), 0.0, n_dot_v);
+    let normal = vec3<f32>(0.0, 0.0, 1.0);
+    // Schlick-GGX remapping for image based lighting
+    let k = roughness * roughness / 2.0;
+
+    let sample_count = 256u;
+
+    var scale = 0.0;
+    var bias = 0.0;
+    for (var i = 0u; i < sample_count; i++) {
+        let half_dir = importance_sample_ggx(hammersley(i, sample_count), normal, roughness);
+        let light_dir = normalize(2.0 * dot(view_dir, half_dir) * half_dir - view_dir);
+
+        let n_dot_l = max(light_dir.z, 0.0);
+        let n_dot_h = max(half_dir.z, 0.0);
+        let v_dot_h = max(dot(view_dir, half_dir), 0.0);
+
+        if n_dot_l > 0.0 {
+            let g = n_dot_v / (n_dot_v * (1.0 - k) + k) * n_dot_l / (n_dot_l * (1.0 - k) + k);
+            let g_vis = g * v_dot_h / (n_dot_h * n_dot_v);
+            let fresnel = pow(1.0 - v_dot_h, 5.0);
+
+            scale += (1.0 - fresnel) * g_vis;
+            bias += fresnel * g_vis;
+        }
+    }
+
+    return vec4<f32>(scale / f32(sample_count), bias / f32(sample_count), 0.0, 1.0);
+}
@@ -0,0 +1,56 @@
+// Fast approximate anti-aliasing, blurs each pixel along the edge found in the luma around it
+
+struct Fxaa {
+    // contrast, relative to the brightest neighbour, below which a pixel is left alone
+    edge_threshold: f32,
+    // longest blur along an edge, in pixels
+    span_max: f32,
+};
+@group(1) @binding(0) var<uniform> fxaa: Fxaa;
+
+const REDUCE_MIN: f32 = 0.0078125;
+const REDUCE_MUL: f32 = 0.125;
+
+// the square root follows the perceived contrast closer than the linear luma
+fn luma(color: vec3<f32>) -> f32 {
+    return sqrt(dot(color, vec3<f32>(0.299, 0.587, 0.114)));
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let texel = texel_size();
+    let center = sample_input(in.tex_coords);
+
+    let luma_nw = luma(sample_input(in.tex_coords + vec2<f32>(-1.0, -1.0) * texel).rgb);
+    let luma_ne = luma(sample_input(in.tex_coords + vec2<f32>(1.0, -1.0) * texel).rgb);
+    let luma_sw = luma(sample_input(in.tex_coords + vec2<f32>(-1.0, 1.0) * texel).rgb);
+    let luma_se = luma(sample_input(in.tex_coords + vec2<f32>(1.0, 1.0) * texel).rgb);
+    let luma_m = luma(center.rgb);
+
+    let luma_min = min(luma_m, min(min(luma_nw, luma_ne), min(luma_sw, luma_se)));
+    let luma_max = max(luma_m, max(max(luma_nw, luma_ne), max(luma_sw, luma_se)));
+
+    if luma_max - luma_min < luma_max * fxaa.edge_threshold {
+        return output(center);
+    }
+
+    // along the edge, across the luma gradient
+    var direction = vec2<f32>(
+        (luma_sw + luma_se) - (luma_nw + luma_ne),
+        (luma_nw + luma_sw) - (luma_ne + luma_se),
+    );
+
+    let direction_reduce = max((luma_nw + luma_ne + luma_sw + luma_se) * 0.25 * REDUCE_MUL, REDUCE_MIN);
+    let scale = 1.0 / (min(abs(direction.x), abs(direction.y)) + direction_reduce);
+    direction = clamp(direction * scale, vec2<f32>(-fxaa.span_max), vec2<f32>(fxaa.span_max)) * texel;
+
+    let near = 0.5 * (sample_input(in.tex_coords - direction / 6.0).rgb + sample_input(in.tex_coords + direction / 6.0).rgb);
+    let far = 0.5 * near + 0.25 * (sample_input(in.tex_coords - direction * 0.5).rgb + sample_input(in.tex_coords + direction * 0.5).rgb);
+
+    // the wider blur crossed another edge
+    let luma_far = luma(far);
+    if luma_far < luma_min || luma_far > luma_max {
+        return output(vec4<f32>(near, center.a));
+    }
+    return output(vec4<f32>(far, center.a));
+}
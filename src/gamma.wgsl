@@ -0,0 +1,12 @@
+// Display gamma adjustment, above 1 brightens the midtones and below 1 darkens them
+
+struct Gamma {
+    gamma: f32,
+};
+@group(1) @binding(0) var<uniform> gamma: Gamma;
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = sample_input(in.tex_coords);
+    return output(vec4<f32>(pow(max(color.rgb, vec3<f32>(0.0)), vec3<f32>(1.0 / gamma.gamma)), color.a));
+}
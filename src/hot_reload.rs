@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+
+use notify::{EventKind, RecursiveMode, Watcher as _};
+
+/// Time without events after which a file counts as changed, editors often save in several writes
+const SETTLE_TIME: instant::Duration = instant::Duration::from_millis(100);
+
+/// Watches a directory of assets for files being written
+pub struct Watcher {
+    root: PathBuf,
+    events: mpsc::Receiver<notify::Result<notify::Event>>,
+    /// Files written to, with the time of their last event
+    pending: HashMap<String, instant::Instant>,
+    _watcher: notify::RecommendedWatcher,
+}
+
+impl Watcher {
+    pub fn new<P: AsRef<Path>>(root: P) -> anyhow::Result<Self> {
+        let root = root.as_ref().canonicalize()?;
+
+        let (sender, events) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(sender)?;
+        watcher.watch(&root, RecursiveMode::Recursive)?;
+
+        Ok(Self {
+            root,
+            events,
+            pending: HashMap::new(),
+            _watcher: watcher,
+        })
+    }
+
+    /// Canonical path of the watched directory
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Files that settled since the last call, named relative to the root like the loaders of
+    /// [`crate::resources`] expect
+    pub fn changed(&mut self) -> Vec<String> {
+        let now = instant::Instant::now();
+
+        for event in self.events.try_iter() {
+            match event {
+                Ok(event) if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) => {
+                    for path in event.paths {
+                        if let Some(file_name) = relative_name(&self.root, &path) {
+                            self.pending.insert(file_name, now);
+                        }
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => log::warn!("Could not watch the assets: {}", e),
+            }
+        }
+
+        let mut changed = Vec::new();
+        self.pending.retain(|file_name, last_event| {
+            let settled = now - *last_event >= SETTLE_TIME;
+            if settled {
+                changed.push(file_name.clone());
+            }
+            !settled
+        });
+
+        changed
+    }
+}
+
+fn relative_name(root: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(root).ok()?;
+
+    let components = relative
+        .components()
+        .map(|component| component.as_os_str().to_str())
+        .collect::<Option<Vec<_>>>()?;
+
+    Some(components.join("/"))
+}
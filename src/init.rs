@@ -1,71 +1,348 @@
-pub async fn init(
-    window: &winit::window::Window,
-) -> (
-    wgpu::Surface,
-    wgpu::Device,
-    wgpu::Queue,
-    wgpu::SurfaceConfiguration,
-) {
-    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+use std::fmt;
+
+/// Renderer initialization stage that failed
+#[derive(Debug, thiserror::Error)]
+pub enum InitError {
+    #[error("could not create a surface for the window: {0}")]
+    CreateSurface(#[from] wgpu::CreateSurfaceError),
+    #[error("no adapter found, tried {0}")]
+    RequestAdapter(AdapterPreferences),
+    #[error("could not request a device from {adapter}: {source}")]
+    RequestDevice {
+        adapter: String,
+        source: wgpu::RequestDeviceError,
+    },
+    #[error("the surface is not supported by {0}")]
+    IncompatibleSurface(String),
+    #[error("could not load {asset}: {error:#}")]
+    Asset { asset: String, error: anyhow::Error },
+    #[error("could not create the lights: {0}")]
+    Lights(#[from] crate::light::LightsError),
+    #[error("could not preprocess the shaders: {0}")]
+    Shader(#[from] crate::preprocessor::PreprocessError),
+}
+
+impl InitError {
+    /// Wraps the error of loading `asset`, a file or a description of it
+    pub fn asset(asset: impl Into<String>) -> impl FnOnce(anyhow::Error) -> Self {
+        let asset = asset.into();
+        move |error| Self::Asset { asset, error }
+    }
+}
+
+/// Adapters requested in order until one is found
+const ADAPTER_PREFERENCES: [(wgpu::PowerPreference, bool); 3] = [
+    (wgpu::PowerPreference::HighPerformance, false),
+    (wgpu::PowerPreference::LowPower, false),
+    (wgpu::PowerPreference::None, true),
+];
+
+/// Adapter preferences that were tried, used to report what failed
+#[derive(Debug)]
+pub struct AdapterPreferences(Vec<(wgpu::PowerPreference, bool)>);
+
+impl fmt::Display for AdapterPreferences {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tried = self
+            .0
+            .iter()
+            .map(|(power_preference, fallback)| {
+                if *fallback {
+                    format!("{:?} (fallback)", power_preference)
+                } else {
+                    format!("{:?}", power_preference)
+                }
+            })
+            .collect::<Vec<_>>();
+
+        write!(f, "{}", tried.join(", "))
+    }
+}
+
+/// Surface preferences, negotiated against what the surface supports by [`RendererConfig::configure`]
+#[derive(Debug, Clone)]
+pub struct RendererConfig {
+    /// Pick an sRGB surface format when available so shaders can output linear colours
+    pub prefer_srgb: bool,
+    /// Present modes in order of preference, `Fifo` is used if none is supported
+    pub present_modes: Vec<wgpu::PresentMode>,
+    /// Alpha modes in order of preference, the first supported mode is used if none is
+    pub alpha_modes: Vec<wgpu::CompositeAlphaMode>,
+    /// MSAA samples per pixel, lowered to the highest count the adapter supports
+    pub sample_count: u32,
+}
+
+impl Default for RendererConfig {
+    fn default() -> Self {
+        Self {
+            prefer_srgb: true,
+            present_modes: vec![wgpu::PresentMode::Fifo],
+            alpha_modes: vec![
+                wgpu::CompositeAlphaMode::Opaque,
+                wgpu::CompositeAlphaMode::PreMultiplied,
+            ],
+            sample_count: 4,
+        }
+    }
+}
+
+impl RendererConfig {
+    pub fn format(&self, capabilities: &wgpu::SurfaceCapabilities) -> Option<wgpu::TextureFormat> {
+        capabilities
+            .formats
+            .iter()
+            .copied()
+            .find(|format| format.is_srgb() == self.prefer_srgb)
+            .or_else(|| capabilities.formats.first().copied())
+    }
+
+    pub fn present_mode(&self, capabilities: &wgpu::SurfaceCapabilities) -> wgpu::PresentMode {
+        self.present_modes
+            .iter()
+            .copied()
+            .find(|mode| capabilities.present_modes.contains(mode))
+            // Fifo is the only mode guaranteed to be supported
+            .unwrap_or(wgpu::PresentMode::Fifo)
+    }
+
+    pub fn alpha_mode(&self, capabilities: &wgpu::SurfaceCapabilities) -> wgpu::CompositeAlphaMode {
+        self.alpha_modes
+            .iter()
+            .copied()
+            .find(|mode| capabilities.alpha_modes.contains(mode))
+            .or_else(|| capabilities.alpha_modes.first().copied())
+            // lets the backend pick when it reports no modes
+            .unwrap_or(wgpu::CompositeAlphaMode::Auto)
+    }
+
+    pub fn sample_count(&self, supported: &[u32]) -> u32 {
+        closest_sample_count(supported, self.sample_count)
+    }
+
+    /// Surface configuration closest to the preferences, `None` if the surface supports no format
+    pub fn configure(
+        &self,
+        capabilities: &wgpu::SurfaceCapabilities,
+        width: u32,
+        height: u32,
+    ) -> Option<wgpu::SurfaceConfiguration> {
+        Some(wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            format: self.format(capabilities)?,
+            width,
+            height,
+            present_mode: self.present_mode(capabilities),
+            alpha_mode: self.alpha_mode(capabilities),
+            view_formats: vec![],
+        })
+    }
+}
+
+/// MSAA sample counts both the scene colour target and the depth buffer support, from the lowest
+pub fn supported_sample_counts(adapter: &wgpu::Adapter, device: &wgpu::Device) -> Vec<u32> {
+    let format_features = |format: wgpu::TextureFormat| {
+        // beyond the guaranteed counts, the device needs to allow what the adapter supports
+        if device
+            .features()
+            .contains(wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES)
+        {
+            adapter.get_texture_format_features(format)
+        } else {
+            format.guaranteed_format_features(device.features())
+        }
+    };
+
+    let color = format_features(crate::texture::Texture::HDR_FORMAT);
+    let depth = format_features(crate::texture::Texture::DEPTH_FORMAT);
+
+    [1, 2, 4, 8]
+        .into_iter()
+        .filter(|count| {
+            color.flags.sample_count_supported(*count) && depth.flags.sample_count_supported(*count)
+        })
+        .collect()
+}
+
+/// Highest supported sample count not above `requested`
+pub fn closest_sample_count(supported: &[u32], requested: u32) -> u32 {
+    supported
+        .iter()
+        .copied()
+        .filter(|count| *count <= requested)
+        .max()
+        .unwrap_or(1)
+}
+
+/// Whether storage buffers can be read in vertex shaders, WebGL has none and needs uniform arrays instead
+pub fn supports_vertex_storage(adapter: &wgpu::Adapter, device: &wgpu::Device) -> bool {
+    adapter
+        .get_downlevel_capabilities()
+        .flags
+        .contains(wgpu::DownlevelFlags::VERTEX_STORAGE)
+        && device.limits().max_storage_buffers_per_shader_stage > 0
+}
+
+fn create_instance() -> wgpu::Instance {
+    wgpu::Instance::new(wgpu::InstanceDescriptor {
         backends: wgpu::Backends::all(),
         dx12_shader_compiler: Default::default(),
         flags: wgpu::InstanceFlags::default(),
         gles_minor_version: wgpu::Gles3MinorVersion::default(),
-    });
+    })
+}
 
-    // let window_handle = window.window_handle().unwrap().as_raw();
-    // let display_handle = window.display_handle().unwrap();
+async fn request_adapter(
+    instance: &wgpu::Instance,
+    compatible_surface: Option<&wgpu::Surface>,
+    force_fallback_adapter: bool,
+) -> Result<wgpu::Adapter, InitError> {
+    let preferences = ADAPTER_PREFERENCES
+        .into_iter()
+        .filter(|(_, fallback)| *fallback || !force_fallback_adapter)
+        .collect::<Vec<_>>();
 
-    let surface = unsafe { instance.create_surface(&window).unwrap() };
+    for &(power_preference, force_fallback_adapter) in &preferences {
+        let adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference,
+                compatible_surface,
+                force_fallback_adapter,
+            })
+            .await;
 
-    let adapter = instance
-        .request_adapter(&wgpu::RequestAdapterOptions {
-            power_preference: wgpu::PowerPreference::default(),
-            compatible_surface: Some(&surface),
-            force_fallback_adapter: false,
-        })
-        .await
-        .unwrap();
-
-    let (device, queue) = adapter
-        .request_device(
-            &wgpu::DeviceDescriptor {
-                label: None,
-                features: wgpu::Features::empty(),
-                //limits: wgpu::Limits::downlevel_webgl2_defaults()
-                limits: wgpu::Limits::default(),
-            },
-            None, // Trace path
-        )
-        .await
-        .unwrap();
+        if let Some(adapter) = adapter {
+            log::info!("Using adapter {:?}", adapter.get_info());
+            return Ok(adapter);
+        }
+
+        log::warn!(
+            "No adapter found with {:?} power preference (fallback: {})",
+            power_preference,
+            force_fallback_adapter
+        );
+    }
+
+    Err(InitError::RequestAdapter(AdapterPreferences(preferences)))
+}
+
+async fn request_device(adapter: &wgpu::Adapter) -> Result<(wgpu::Device, wgpu::Queue), InitError> {
+    // from the most to the least capable, WebGL2 being the floor
+    let limits = [
+        wgpu::Limits::default(),
+        wgpu::Limits::downlevel_defaults(),
+        wgpu::Limits::downlevel_webgl2_defaults(),
+    ];
+
+    // compressed textures are uploaded as is where the adapter can sample them, and
+    // MSAA sample counts are not limited to the guaranteed ones where it supports more
+    let features = adapter.features()
+        & (crate::compressed::features()
+            | wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES);
+
+    let mut error = None;
+
+    for limits in limits {
+        let result = adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    label: None,
+                    features,
+                    limits: limits.using_resolution(adapter.limits()),
+                },
+                None, // Trace path
+            )
+            .await;
+
+        match result {
+            Ok(device) => return Ok(device),
+            Err(e) => {
+                log::warn!("Could not request device, trying lower limits: {}", e);
+                error = Some(e);
+            }
+        }
+    }
+
+    Err(InitError::RequestDevice {
+        adapter: adapter.get_info().name,
+        source: error.expect("at least one set of limits was tried"),
+    })
+}
+
+pub async fn init(
+    window: &winit::window::Window,
+    renderer_config: &RendererConfig,
+) -> Result<
+    (
+        wgpu::Surface,
+        wgpu::Adapter,
+        wgpu::Device,
+        wgpu::Queue,
+        wgpu::SurfaceConfiguration,
+    ),
+    InitError,
+> {
+    let instance = create_instance();
+
+    let surface = unsafe { instance.create_surface(&window)? };
+
+    let adapter = request_adapter(&instance, Some(&surface), false).await?;
+
+    let (device, queue) = request_device(&adapter).await?;
 
     let size = window.inner_size();
 
     let capabilities = surface.get_capabilities(&adapter);
 
-    // let format = capabilities
-    //     .formats
-    //     .iter()
-    //     .copied()
-    //     .find(|&f| f == wgpu::TextureFormat::Bgra8UnormSrgb)
-    //     .unwrap();
-    let format = *capabilities.formats.first().unwrap();
+    log::debug!("Surface capabilities {:?}", capabilities);
+
+    let config = renderer_config
+        .configure(&capabilities, size.width, size.height)
+        .ok_or_else(|| InitError::IncompatibleSurface(adapter.get_info().name))?;
 
-    dbg!(&capabilities.formats);
+    log::info!(
+        "Surface configured with {:?}, {:?} and {:?}",
+        config.format,
+        config.present_mode,
+        config.alpha_mode
+    );
+
+    surface.configure(&device, &config);
 
-    // let config = surface.get_default_config(&adapter, size.width, size.height).unwrap();
+    Ok((surface, adapter, device, queue, config))
+}
+
+pub async fn init_headless(
+    width: u32,
+    height: u32,
+    force_fallback_adapter: bool,
+) -> Result<
+    (
+        wgpu::Adapter,
+        wgpu::Device,
+        wgpu::Queue,
+        wgpu::SurfaceConfiguration,
+    ),
+    InitError,
+> {
+    let instance = create_instance();
+
+    // without a surface to be compatible with, any adapter will do, including
+    // software rasterizers on machines with no display or GPU
+    let adapter = request_adapter(&instance, None, force_fallback_adapter).await?;
+
+    let (device, queue) = request_device(&adapter).await?;
+
+    // there is no surface, but the rest of the renderer only needs the
+    // format and the size of the colour target
     let config = wgpu::SurfaceConfiguration {
-        usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
-        format,
-        width: size.width,
-        height: size.height,
-        present_mode: capabilities.present_modes[0],
-        alpha_mode: capabilities.alpha_modes[0],
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+        format: wgpu::TextureFormat::Rgba8UnormSrgb,
+        width,
+        height,
+        present_mode: wgpu::PresentMode::Fifo,
+        alpha_mode: wgpu::CompositeAlphaMode::Opaque,
         view_formats: vec![],
     };
 
-    surface.configure(&device, &config);
-
-    (surface, device, queue, config)
+    Ok((adapter, device, queue, config))
 }
@@ -1,91 +1,338 @@
-mod controller;
-mod geometry;
-mod init;
-mod light;
-mod model;
-mod pipelines;
-mod resources;
-mod texture;
-mod transforms;
-mod view;
-
-use init::init;
-use pipelines::{create_light_render_pipeline, create_model_render_pipeline};
-use std::mem;
+pub mod attributes;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod basis;
+pub mod compressed;
+pub mod controller;
+pub mod environment;
+pub mod geometry;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod hot_reload;
+pub mod init;
+pub mod light;
+pub mod mipmaps;
+pub mod model;
+pub mod pipelines;
+pub mod postprocessing;
+pub mod preprocessor;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod reflection;
+pub mod resources;
+pub mod scene;
+pub mod shaders;
+pub mod shadows;
+pub mod ssao;
+pub mod texture;
+pub mod tonemapping;
+pub mod transforms;
+pub mod view;
+
+use init::{init, init_headless, RendererConfig};
+use pipelines::{
+    create_depth_prepass_pipeline, create_light_render_pipeline, create_model_render_pipeline,
+    create_shadow_render_pipeline, create_skybox_render_pipeline,
+};
 use winit::keyboard::{Key, NamedKey, PhysicalKey};
 use winit::{event::*, event_loop::EventLoop, window::Window};
 
 #[cfg(target_arch = "wasm32")]
 use wasm_bindgen::prelude::*;
 
-struct State {
-    surface: wgpu::Surface,
+enum Target {
+    Surface(wgpu::Surface),
+    Offscreen(texture::Texture),
+}
+
+pub struct State {
+    target: Target,
+    adapter: wgpu::Adapter,
     device: wgpu::Device,
     queue: wgpu::Queue,
     config: wgpu::SurfaceConfiguration,
 
+    /// File the scene was loaded from and its description, to reload it
+    #[cfg(not(target_arch = "wasm32"))]
+    scene_file: String,
+    #[cfg(not(target_arch = "wasm32"))]
+    scene: scene::Scene,
+    #[cfg(not(target_arch = "wasm32"))]
+    watcher: Option<hot_reload::Watcher>,
+    shaders: shaders::Shaders,
+    shader_features: shaders::Features,
+    /// Watches the sources of `shaders` in development
+    #[cfg(not(target_arch = "wasm32"))]
+    shader_watcher: Option<hot_reload::Watcher>,
+
     view: view::View,
 
-    light: light::Light,
+    environment: environment::Environment,
+    skybox_render_pipeline: wgpu::RenderPipeline,
+
+    lights: light::Lights,
     light_render_pipeline: wgpu::RenderPipeline,
 
-    model: model::Model,
+    shadow_map: shadows::ShadowMap,
+    shadow_render_pipeline: wgpu::RenderPipeline,
+
+    ssao: ssao::Ssao,
+    depth_prepass_pipeline: wgpu::RenderPipeline,
+
+    objects: Vec<scene::Object>,
     model_render_pipeline: wgpu::RenderPipeline,
 
     depth_texture: texture::Texture,
-
-    instances: transforms::Transforms,
+    hdr_texture: texture::Texture,
+    sample_count: u32,
+    /// MSAA colour target resolved into `hdr_texture`, `None` with a single sample
+    multisampled_texture: Option<texture::Texture>,
+    tonemapping: tonemapping::Tonemapping,
+    post_processing: postprocessing::PostProcessing,
 }
 
 impl State {
-    async fn new(window: &Window) -> State {
-        let (surface, device, queue, config) = init(window).await;
+    async fn new(
+        window: &Window,
+        renderer_config: &RendererConfig,
+    ) -> Result<State, init::InitError> {
+        let (surface, adapter, device, queue, config) = init(window, renderer_config).await?;
+
+        let sample_count =
+            renderer_config.sample_count(&init::supported_sample_counts(&adapter, &device));
+
+        Self::with_target(
+            Target::Surface(surface),
+            adapter,
+            device,
+            queue,
+            config,
+            sample_count,
+            &scene_file(),
+        )
+        .await
+    }
+
+    pub async fn new_headless(
+        width: u32,
+        height: u32,
+        scene_file: &str,
+        force_fallback_adapter: bool,
+    ) -> Result<State, init::InitError> {
+        let (adapter, device, queue, config) =
+            init_headless(width, height, force_fallback_adapter).await?;
+
+        let frame = texture::Texture::create_render_target(
+            &device,
+            config.width,
+            config.height,
+            config.format,
+            "offscreen_texture",
+        );
+
+        Self::with_target(
+            Target::Offscreen(frame),
+            adapter,
+            device,
+            queue,
+            config,
+            1,
+            scene_file,
+        )
+        .await
+    }
+
+    async fn with_target(
+        target: Target,
+        adapter: wgpu::Adapter,
+        device: wgpu::Device,
+        queue: wgpu::Queue,
+        config: wgpu::SurfaceConfiguration,
+        sample_count: u32,
+        scene_file: &str,
+    ) -> Result<State, init::InitError> {
+        let scene = resources::load_scene(scene_file)
+            .await
+            .map_err(init::InitError::asset(scene_file))?;
 
         let view = view::View::build()
-            .camera((0.0, 5.0, 10.0), cgmath::Deg(-90.0), cgmath::Deg(-20.0))
-            .projection(config.width, config.height, cgmath::Deg(45.0), 0.1, 100.0)
+            .camera(
+                scene.camera.position,
+                cgmath::Deg(scene.camera.yaw),
+                cgmath::Deg(scene.camera.pitch),
+            )
+            .projection(
+                config.width,
+                config.height,
+                cgmath::Deg(scene.projection.fovy),
+                scene.projection.znear,
+                scene.projection.zfar,
+            )
             .controller(controller::Controller::new(4.0, 0.4))
             .finalize(&device);
 
-        let light = light::Light::build()
-            .position([4.0, 4.0, 2.0])
-            .color([1.0, 1.0, 1.0])
-            .controller(60.0)
-            .finalize(&device);
+        let environment = scene
+            .load_environment(&device, &queue)
+            .await
+            .map_err(init::InitError::asset("the environment"))?;
+
+        let skybox_render_pipeline = create_skybox_render_pipeline(
+            &device,
+            texture::Texture::HDR_FORMAT,
+            sample_count,
+            &view,
+            &environment,
+        );
+
+        let lights = scene.create_lights(
+            &device,
+            &environment,
+            init::supports_vertex_storage(&adapter, &device),
+        )?;
+
+        let ssao = ssao::Ssao::build()
+            .quality(ssao::Quality::default())
+            .radius(1.0)
+            .finalize(&device, config.width, config.height, &view.projection);
+
+        let depth_prepass_pipeline = create_depth_prepass_pipeline(&device, &view);
 
-        let light_render_pipeline = create_light_render_pipeline(&device, &config, &view, &light);
+        let shadow_map = shadows::ShadowMap::build()
+            .resolution(1024)
+            .bias(0.002)
+            .finalize(&device, lights.shadow_caster(), &ssao.texture);
+
+        let shadow_render_pipeline = create_shadow_render_pipeline(&device, &shadow_map);
+
+        let shaders = shaders::Shaders::default();
+        let shader_features = shaders::Features::default();
+
+        let light_render_pipeline = create_light_render_pipeline(
+            &device,
+            texture::Texture::HDR_FORMAT,
+            sample_count,
+            &view,
+            &lights,
+            &pipelines::light_shader_source(&lights, &shaders)?.source,
+        );
 
         let depth_texture = texture::Texture::create_depth_texture(
             &device,
             config.width,
             config.height,
+            sample_count,
             "depth_texture",
         );
 
-        let model = resources::load_model("cube.obj", &device, &queue)
+        let multisampled_texture =
+            create_multisampled_texture(&device, config.width, config.height, sample_count);
+
+        let hdr_texture = texture::Texture::create_render_target(
+            &device,
+            config.width,
+            config.height,
+            texture::Texture::HDR_FORMAT,
+            "hdr_texture",
+        );
+
+        let tonemapping = tonemapping::Tonemapping::new(
+            &device,
+            &hdr_texture,
+            config.format,
+            tonemapping::Operator::Aces,
+            1.0,
+        );
+
+        let lut = resources::load_lut("grading.png", &device, &queue)
             .await
-            .unwrap();
+            .map_err(init::InitError::asset("grading.png"))?;
 
-        let model_render_pipeline =
-            create_model_render_pipeline(&device, &config, &model, &view, &light);
+        let post_processing = postprocessing::PostProcessing::build()
+            .effects(vec![
+                postprocessing::Effect::Bloom {
+                    threshold: 1.0,
+                    intensity: 0.6,
+                },
+                postprocessing::Effect::Fxaa {
+                    edge_threshold: 0.125,
+                    span_max: 8.0,
+                },
+                postprocessing::Effect::ColorGrading { intensity: 1.0 },
+                postprocessing::Effect::Vignette {
+                    intensity: 0.4,
+                    radius: 0.6,
+                    smoothness: 0.6,
+                },
+            ])
+            .lut(lut)
+            .finalize(
+                &device,
+                &hdr_texture,
+                config.width,
+                config.height,
+                config.format,
+            );
 
-        let instances = transforms::Transforms::build()
-            .transform_field(3, 3)
-            .finalize(&device);
+        let mut objects = Vec::new();
+        for model in &scene.models {
+            objects.push(
+                scene::Object::load(model, &device, &queue)
+                    .await
+                    .map_err(init::InitError::asset(&model.file))?,
+            );
+        }
+
+        let model_render_pipeline = create_model_render_pipeline(
+            &device,
+            texture::Texture::HDR_FORMAT,
+            sample_count,
+            // models share the layout of their materials
+            &objects[0].model,
+            &view,
+            &lights,
+            &shadow_map,
+            &pipelines::model_shader_source(&lights, &shaders, shader_features)?.source,
+        );
 
-        Self {
-            surface,
+        Ok(Self {
+            target,
+            adapter,
             device,
             queue,
             config,
+            #[cfg(not(target_arch = "wasm32"))]
+            scene_file: scene_file.to_string(),
+            #[cfg(not(target_arch = "wasm32"))]
+            scene,
+            #[cfg(not(target_arch = "wasm32"))]
+            watcher: None,
+            shaders,
+            shader_features,
+            #[cfg(not(target_arch = "wasm32"))]
+            shader_watcher: None,
             depth_texture,
+            hdr_texture,
+            sample_count,
+            multisampled_texture,
+            tonemapping,
+            post_processing,
             view,
-            instances,
+            environment,
+            skybox_render_pipeline,
             model_render_pipeline,
-            model,
-            light,
+            objects,
+            lights,
             light_render_pipeline,
-        }
+            shadow_map,
+            shadow_render_pipeline,
+            ssao,
+            depth_prepass_pipeline,
+        })
+    }
+
+    pub fn device(&self) -> &wgpu::Device {
+        &self.device
+    }
+
+    pub fn queue(&self) -> &wgpu::Queue {
+        &self.queue
     }
 
     pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
@@ -97,10 +344,49 @@ impl State {
                 &self.device,
                 new_size.width,
                 new_size.height,
+                self.sample_count,
                 "depth_texture",
             );
 
-            self.surface.configure(&self.device, &self.config);
+            self.multisampled_texture = create_multisampled_texture(
+                &self.device,
+                new_size.width,
+                new_size.height,
+                self.sample_count,
+            );
+
+            self.hdr_texture = texture::Texture::create_render_target(
+                &self.device,
+                new_size.width,
+                new_size.height,
+                texture::Texture::HDR_FORMAT,
+                "hdr_texture",
+            );
+            self.tonemapping.resize(&self.device, &self.hdr_texture);
+            self.post_processing.resize(
+                &self.device,
+                &self.hdr_texture,
+                new_size.width,
+                new_size.height,
+            );
+
+            self.ssao
+                .resize(&self.device, new_size.width, new_size.height);
+            self.shadow_map
+                .set_ambient_occlusion(&self.device, &self.ssao.texture);
+
+            match &mut self.target {
+                Target::Surface(surface) => surface.configure(&self.device, &self.config),
+                Target::Offscreen(frame) => {
+                    *frame = texture::Texture::create_render_target(
+                        &self.device,
+                        new_size.width,
+                        new_size.height,
+                        self.config.format,
+                        "offscreen_texture",
+                    )
+                }
+            }
 
             self.view.projection.resize(new_size.width, new_size.height);
         }
@@ -113,33 +399,562 @@ impl State {
         })
     }
 
-    fn update(&mut self, dt: instant::Duration) {
+    /// Switches the surface to the next present mode it supports
+    pub fn cycle_present_mode(&mut self) {
+        let Target::Surface(surface) = &self.target else {
+            return;
+        };
+
+        let present_modes = surface.get_capabilities(&self.adapter).present_modes;
+
+        let next = present_modes
+            .iter()
+            .position(|mode| *mode == self.config.present_mode)
+            .map_or(0, |i| i + 1);
+
+        // Fifo is the only mode guaranteed to be supported
+        self.config.present_mode = present_modes
+            .get(next)
+            .or_else(|| present_modes.first())
+            .copied()
+            .unwrap_or(wgpu::PresentMode::Fifo);
+        surface.configure(&self.device, &self.config);
+
+        log::info!("Present mode set to {:?}", self.config.present_mode);
+    }
+
+    /// Renders with `sample_count` MSAA samples per pixel, recreating the pipelines and targets.
+    /// Unsupported counts are lowered to the highest supported one, which is returned.
+    pub fn set_sample_count(&mut self, sample_count: u32) -> u32 {
+        let supported = init::supported_sample_counts(&self.adapter, &self.device);
+        self.sample_count = init::closest_sample_count(&supported, sample_count);
+
+        self.recreate_pipelines();
+        self.reset();
+
+        self.sample_count
+    }
+
+    /// Recreates the pipelines drawing into the frame, after their sample count, lights or
+    /// environment changed
+    fn recreate_pipelines(&mut self) {
+        // the shaders were preprocessed when they were set
+        self.light_render_pipeline = create_light_render_pipeline(
+            &self.device,
+            texture::Texture::HDR_FORMAT,
+            self.sample_count,
+            &self.view,
+            &self.lights,
+            &pipelines::light_shader_source(&self.lights, &self.shaders)
+                .unwrap()
+                .source,
+        );
+
+        self.skybox_render_pipeline = create_skybox_render_pipeline(
+            &self.device,
+            texture::Texture::HDR_FORMAT,
+            self.sample_count,
+            &self.view,
+            &self.environment,
+        );
+
+        self.model_render_pipeline = create_model_render_pipeline(
+            &self.device,
+            texture::Texture::HDR_FORMAT,
+            self.sample_count,
+            &self.objects[0].model,
+            &self.view,
+            &self.lights,
+            &self.shadow_map,
+            &pipelines::model_shader_source(&self.lights, &self.shaders, self.shader_features)
+                .unwrap()
+                .source,
+        );
+    }
+
+    /// Switches to the next MSAA sample count the adapter supports, wrapping around to 1
+    pub fn cycle_sample_count(&mut self) {
+        let supported = init::supported_sample_counts(&self.adapter, &self.device);
+
+        let next = supported
+            .iter()
+            .copied()
+            .find(|count| *count > self.sample_count)
+            .unwrap_or(1);
+
+        self.set_sample_count(next);
+
+        log::info!("MSAA set to {}x", self.sample_count);
+    }
+
+    /// Switches to the next tonemapping operator
+    pub fn cycle_tonemapping(&mut self) {
+        self.tonemapping.operator = self.tonemapping.operator.next();
+        self.tonemapping.update(&self.queue);
+
+        log::info!("Tonemapping set to {:?}", self.tonemapping.operator);
+    }
+
+    /// Scales the exposure by `factor`, in stops of light 2.0 is one stop brighter
+    pub fn scale_exposure(&mut self, factor: f32) {
+        self.tonemapping.exposure *= factor;
+        self.tonemapping.update(&self.queue);
+
+        log::info!("Exposure set to {}", self.tonemapping.exposure);
+    }
+
+    /// Switches to the next ambient occlusion preset, wrapping around to `Off`
+    pub fn cycle_ssao_quality(&mut self) {
+        self.ssao
+            .set_quality(&self.device, self.ssao.quality().next());
+        self.shadow_map
+            .set_ambient_occlusion(&self.device, &self.ssao.texture);
+
+        log::info!("SSAO set to {:?}", self.ssao.quality());
+    }
+
+    /// Replaces the post-processing effects, they apply in order to the tonemapped frame
+    pub fn set_effects(&mut self, effects: Vec<postprocessing::Effect>) {
+        self.post_processing.effects = effects;
+        self.post_processing.update(&self.device, &self.queue);
+    }
+
+    /// Turns the post-processing effects on or off
+    pub fn toggle_post_processing(&mut self) {
+        self.post_processing.enabled = !self.post_processing.enabled;
+
+        log::info!(
+            "Post-processing {}",
+            if self.post_processing.enabled {
+                "enabled"
+            } else {
+                "disabled"
+            }
+        );
+    }
+
+    /// Reloads the assets that change in [`resources::asset_dir`] from now on, see [`State::update`]
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn watch(&mut self) -> anyhow::Result<()> {
+        self.watcher = Some(hot_reload::Watcher::new(resources::asset_dir())?);
+
+        log::info!("Watching {}", resources::asset_dir().display());
+
+        Ok(())
+    }
+
+    /// Reads the model and light shaders from `dir` and rebuilds their pipelines whenever they
+    /// change, see [`State::set_shaders`]
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn watch_shaders<P: AsRef<std::path::Path>>(&mut self, dir: P) -> anyhow::Result<()> {
+        self.shader_watcher = Some(hot_reload::Watcher::new(&dir)?);
+        self.set_shaders(shaders::Shaders::load(&dir)?)?;
+
+        log::info!("Watching the shaders in {}", dir.as_ref().display());
+
+        Ok(())
+    }
+
+    /// Rebuilds the model and light pipelines with `shaders`. Sources that do not preprocess,
+    /// naga rejects, or the device fails to build pipelines from, return the diagnostic and keep
+    /// the previous ones
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn set_shaders(&mut self, shaders: shaders::Shaders) -> anyhow::Result<()> {
+        self.create_shaded_pipelines(shaders, self.shader_features)
+    }
+
+    /// Rebuilds the model pipeline with the shader variant for `features`, see
+    /// [`State::set_shaders`] for the errors
+    pub fn set_shader_features(&mut self, features: shaders::Features) -> anyhow::Result<()> {
+        self.create_shaded_pipelines(self.shaders.clone(), features)
+    }
+
+    /// Turns the normal maps of the materials on or off
+    pub fn toggle_normal_maps(&mut self) {
+        let features = shaders::Features {
+            normal_maps: !self.shader_features.normal_maps,
+            ..self.shader_features
+        };
+
+        match self.set_shader_features(features) {
+            Ok(()) => log::info!("Normal maps set to {}", features.normal_maps),
+            Err(e) => log::error!("Could not toggle the normal maps:\n{}", e),
+        }
+    }
+
+    /// Turns the shadows on or off, the shadow map is not drawn while they are off
+    pub fn toggle_shadows(&mut self) {
+        let features = shaders::Features {
+            shadows: !self.shader_features.shadows,
+            ..self.shader_features
+        };
+
+        match self.set_shader_features(features) {
+            Ok(()) => log::info!("Shadows set to {}", features.shadows),
+            Err(e) => log::error!("Could not toggle the shadows:\n{}", e),
+        }
+    }
+
+    fn create_shaded_pipelines(
+        &mut self,
+        shaders: shaders::Shaders,
+        features: shaders::Features,
+    ) -> anyhow::Result<()> {
+        let model_source = pipelines::model_shader_source(&self.lights, &shaders, features)?;
+        let light_source = pipelines::light_shader_source(&self.lights, &shaders)?;
+
+        // the browser validates the shaders itself
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            shaders::validate(&model_source, shaders::MODEL_FILE)?;
+            shaders::validate(&light_source, shaders::LIGHT_FILE)?;
+            reflection::check_shaders(&shaders)?;
+
+            // the backend may still fail to translate what naga accepts
+            self.device.push_error_scope(wgpu::ErrorFilter::Validation);
+        }
+
+        let light_render_pipeline = create_light_render_pipeline(
+            &self.device,
+            texture::Texture::HDR_FORMAT,
+            self.sample_count,
+            &self.view,
+            &self.lights,
+            &light_source.source,
+        );
+
+        let model_render_pipeline = create_model_render_pipeline(
+            &self.device,
+            texture::Texture::HDR_FORMAT,
+            self.sample_count,
+            &self.objects[0].model,
+            &self.view,
+            &self.lights,
+            &self.shadow_map,
+            &model_source.source,
+        );
+
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(error) = pollster::block_on(self.device.pop_error_scope()) {
+            anyhow::bail!("{}", error);
+        }
+
+        self.light_render_pipeline = light_render_pipeline;
+        self.model_render_pipeline = model_render_pipeline;
+        self.shaders = shaders;
+        self.shader_features = features;
+
+        Ok(())
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn reload_changed(&mut self) {
+        if let Some(watcher) = &mut self.watcher {
+            for file_name in watcher.changed() {
+                // the previous assets stay when the new ones can not be loaded
+                if let Err(e) = self.reload(&file_name) {
+                    log::error!("Could not reload {}: {:#}", file_name, e);
+                }
+            }
+        }
+
+        if let Some(watcher) = &mut self.shader_watcher {
+            let changed = watcher.changed();
+            if !changed
+                .iter()
+                .any(|file_name| shaders::Shaders::is_source(file_name))
+            {
+                return;
+            }
+
+            let dir = watcher.root().to_path_buf();
+            match shaders::Shaders::load(dir).and_then(|shaders| self.set_shaders(shaders)) {
+                Ok(()) => log::info!("Reloaded the shaders"),
+                Err(e) => log::error!(
+                    "Could not reload the shaders, keeping the previous ones:\n{}",
+                    e
+                ),
+            }
+        }
+    }
+
+    /// Reloads whatever was loaded from `file_name`
+    #[cfg(not(target_arch = "wasm32"))]
+    fn reload(&mut self, file_name: &str) -> anyhow::Result<()> {
+        if file_name == self.scene_file {
+            self.reload_scene()?;
+        } else if self.scene.environment.iter().any(|file| file == file_name) {
+            let environment =
+                pollster::block_on(self.scene.load_environment(&self.device, &self.queue))?;
+            self.lights = self.scene.create_lights(
+                &self.device,
+                &environment,
+                init::supports_vertex_storage(&self.adapter, &self.device),
+            )?;
+            self.environment = environment;
+            self.recreate_pipelines();
+        } else {
+            let mut reloaded = false;
+            for object in &mut self.objects {
+                if object.model.files.iter().any(|file| file == file_name) {
+                    object.model = pollster::block_on(resources::load_model(
+                        &object.file,
+                        &self.device,
+                        &self.queue,
+                    ))?;
+                    reloaded = true;
+                }
+            }
+
+            if !reloaded {
+                return Ok(());
+            }
+            // the model pipeline was created with the materials layout of the first model
+            self.recreate_pipelines();
+        }
+
+        log::info!("Reloaded {}", file_name);
+
+        Ok(())
+    }
+
+    /// Swaps in the models, instances, lights and environment of the scene file. The camera
+    /// stays where it was moved to unless the file moves it
+    #[cfg(not(target_arch = "wasm32"))]
+    fn reload_scene(&mut self) -> anyhow::Result<()> {
+        let scene = pollster::block_on(resources::load_scene(&self.scene_file))?;
+
+        // everything is loaded before anything is swapped, so errors leave the previous scene
+        let environment = if scene.environment != self.scene.environment {
+            Some(pollster::block_on(
+                scene.load_environment(&self.device, &self.queue),
+            )?)
+        } else {
+            None
+        };
+
+        // models already loaded are kept, only the occurrences of a file beyond them are loaded
+        let mut loaded = Vec::new();
+        for (i, model) in scene.models.iter().enumerate() {
+            let needed = scene.models[..=i]
+                .iter()
+                .filter(|previous| previous.file == model.file)
+                .count();
+            let available = self
+                .objects
+                .iter()
+                .filter(|object| object.file == model.file)
+                .count();
+
+            if needed > available {
+                loaded.push(pollster::block_on(scene::Object::load(
+                    model,
+                    &self.device,
+                    &self.queue,
+                ))?);
+            }
+        }
+
+        let lights = scene.create_lights(
+            &self.device,
+            environment.as_ref().unwrap_or(&self.environment),
+            init::supports_vertex_storage(&self.adapter, &self.device),
+        )?;
+
+        let mut pool = std::mem::take(&mut self.objects);
+        pool.extend(loaded);
+        for model in &scene.models {
+            let i = pool
+                .iter()
+                .position(|object| object.file == model.file)
+                .expect("every model of the scene is loaded");
+
+            let mut object = pool.remove(i);
+            object.instances = model.instances.create_transforms(&self.device);
+            self.objects.push(object);
+        }
+
+        if let Some(environment) = environment {
+            self.environment = environment;
+        }
+        self.lights = lights;
+        self.recreate_pipelines();
+
+        if scene.camera != self.scene.camera {
+            self.view.camera = scene.camera.to_camera();
+        }
+        if scene.projection != self.scene.projection {
+            self.view.projection = scene
+                .projection
+                .to_projection(self.config.width, self.config.height);
+        }
+
+        self.scene = scene;
+
+        Ok(())
+    }
+
+    pub fn update(&mut self, dt: instant::Duration) {
+        #[cfg(not(target_arch = "wasm32"))]
+        self.reload_changed();
+
         // also update the buffer and adds it to the queue
         self.view.update(dt, &self.queue);
-        self.light.update(dt, &self.queue);
+        self.lights.update(dt, &self.queue);
+        self.ssao.update(&self.view.projection, &self.queue);
+
+        if let Some(caster) = self.lights.shadow_caster() {
+            self.shadow_map.update(caster, &self.queue);
+        }
     }
 
     fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
-        let output = self.surface.get_current_texture()?;
+        match &self.target {
+            Target::Surface(surface) => {
+                let output = surface.get_current_texture()?;
+
+                let view = output
+                    .texture
+                    .create_view(&wgpu::TextureViewDescriptor::default());
+
+                self.draw(&view);
+
+                output.present();
+            }
+            Target::Offscreen(frame) => self.draw(&frame.view),
+        }
+
+        Ok(())
+    }
+
+    /// Renders the scene into the offscreen texture, returns `None` if the state draws to a window surface
+    pub fn render_offscreen(&self) -> Option<&texture::Texture> {
+        let Target::Offscreen(frame) = &self.target else {
+            return None;
+        };
+
+        self.draw(&frame.view);
+
+        Some(frame)
+    }
+
+    /// Renders the scene into a new texture and saves it as a PNG file
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn screenshot<P: AsRef<std::path::Path>>(&self, path: P) -> anyhow::Result<()> {
+        let frame = texture::Texture::create_render_target(
+            &self.device,
+            self.config.width,
+            self.config.height,
+            self.config.format,
+            "screenshot_texture",
+        );
+
+        self.draw(&frame.view);
+
+        frame.to_image(&self.device, &self.queue)?.save(path)?;
+
+        Ok(())
+    }
+
+    fn draw_shadow_map(&self, encoder: &mut wgpu::CommandEncoder) {
+        for face in self.shadow_map.faces() {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Shadow Pass"),
+                color_attachments: &[],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &face.view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: wgpu::StoreOp::Store,
+                    }),
+                    stencil_ops: None,
+                }),
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+
+            render_pass.set_pipeline(&self.shadow_render_pipeline);
+            render_pass.set_bind_group(0, &face.bind_group, &[]);
+
+            for object in &self.objects {
+                render_pass.set_vertex_buffer(1, object.instances.buffer.slice(..));
+
+                for mesh in &object.model.meshes {
+                    render_pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+                    render_pass
+                        .set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                    render_pass.draw_indexed(0..mesh.num_elements, 0, 0..object.instances.number);
+                }
+            }
+        }
+    }
+
+    fn draw_depth_prepass(&self, encoder: &mut wgpu::CommandEncoder) {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Depth Prepass"),
+            color_attachments: &[],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &self.ssao.depth_texture.view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: wgpu::StoreOp::Store,
+                }),
+                stencil_ops: None,
+            }),
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
 
-        let view = output
-            .texture
-            .create_view(&wgpu::TextureViewDescriptor::default());
+        render_pass.set_pipeline(&self.depth_prepass_pipeline);
+        render_pass.set_bind_group(0, &self.view.bind_group, &[]);
 
+        for object in &self.objects {
+            render_pass.set_vertex_buffer(1, object.instances.buffer.slice(..));
+
+            for mesh in &object.model.meshes {
+                render_pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+                render_pass
+                    .set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                render_pass.draw_indexed(0..mesh.num_elements, 0, 0..object.instances.number);
+            }
+        }
+    }
+
+    fn draw(&self, view: &wgpu::TextureView) {
         let mut encoder = self
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor {
                 label: Some("Render Encoder"),
             });
 
+        if self.shader_features.shadows {
+            self.draw_shadow_map(&mut encoder);
+        }
+
+        if self.ssao.quality() != ssao::Quality::Off {
+            self.draw_depth_prepass(&mut encoder);
+        }
+        self.ssao.draw(&mut encoder);
+
         let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
             label: Some("Render Pass"),
-            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                view: &view,
-                resolve_target: None,
-                ops: wgpu::Operations {
-                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
-                    store: wgpu::StoreOp::Store,
+            color_attachments: &[Some(match &self.multisampled_texture {
+                // only the resolved samples are kept
+                Some(multisampled_texture) => wgpu::RenderPassColorAttachment {
+                    view: &multisampled_texture.view,
+                    resolve_target: Some(&self.hdr_texture.view),
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: wgpu::StoreOp::Discard,
+                    },
+                },
+                None => wgpu::RenderPassColorAttachment {
+                    view: &self.hdr_texture.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: wgpu::StoreOp::Store,
+                    },
                 },
             })],
             depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
@@ -154,46 +969,154 @@ impl State {
             occlusion_query_set: None,
         });
 
-        render_pass.set_pipeline(&self.model_render_pipeline);
+        render_pass.set_pipeline(&self.skybox_render_pipeline);
+        render_pass.set_bind_group(0, &self.view.bind_group, &[]);
+        render_pass.set_bind_group(1, &self.environment.bind_group, &[]);
+        render_pass.draw(0..36, 0..1);
 
-        render_pass.set_vertex_buffer(1, self.instances.buffer.slice(..));
+        render_pass.set_pipeline(&self.model_render_pipeline);
 
         render_pass.set_bind_group(1, &self.view.bind_group, &[]);
-        render_pass.set_bind_group(2, &self.light.bind_group, &[]);
+        render_pass.set_bind_group(2, &self.lights.bind_group, &[]);
+        render_pass.set_bind_group(3, &self.shadow_map.bind_group, &[]);
 
-        for mesh in &self.model.meshes {
-            let material = &self.model.materials[mesh.material];
+        for object in &self.objects {
+            render_pass.set_vertex_buffer(1, object.instances.buffer.slice(..));
 
-            render_pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
-            render_pass.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
-            render_pass.set_bind_group(0, &material.bind_group, &[]);
+            for mesh in &object.model.meshes {
+                let material = &object.model.materials[mesh.material];
 
-            render_pass.draw_indexed(0..mesh.num_elements, 0, 0..self.instances.number);
+                render_pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+                render_pass
+                    .set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                render_pass.set_bind_group(0, &material.bind_group, &[]);
+
+                render_pass.draw_indexed(0..mesh.num_elements, 0, 0..object.instances.number);
+            }
         }
 
+        // the lights are drawn with the meshes of the first model
         render_pass.set_pipeline(&self.light_render_pipeline);
-        for mesh in &self.model.meshes {
+        for mesh in &self.objects[0].model.meshes {
             render_pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
             render_pass.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
             render_pass.set_bind_group(0, &self.view.bind_group, &[]);
-            render_pass.set_bind_group(1, &self.light.bind_group, &[]);
-            render_pass.draw_indexed(0..mesh.num_elements, 0, 0..1);
+            render_pass.set_bind_group(1, &self.lights.bind_group, &[]);
+            render_pass.draw_indexed(0..mesh.num_elements, 0, 0..self.lights.lights.len() as u32);
         }
 
         drop(render_pass);
 
+        match self.post_processing.input() {
+            Some(input) => {
+                self.tonemapping.draw(&mut encoder, input);
+                self.post_processing.draw(&mut encoder, view);
+            }
+            None => self.tonemapping.draw(&mut encoder, view),
+        }
+
         let command_buffer = encoder.finish();
 
         self.queue.submit([command_buffer]);
+    }
+}
 
-        output.present();
+/// Scene loaded by [`State::new`], the first command line argument on native targets or the
+/// `scene` query parameter on the web, `cube.ron` by default
+fn scene_file() -> String {
+    cfg_if::cfg_if! {
+        if #[cfg(target_arch = "wasm32")] {
+            let search = web_sys::window()
+                .and_then(|window| window.location().search().ok())
+                .unwrap_or_default();
+            let scene_file = search
+                .trim_start_matches('?')
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("scene="))
+                .map(String::from);
+        } else {
+            let scene_file = arguments().scene_file;
+        }
+    }
 
-        Ok(())
+    scene_file.unwrap_or_else(|| String::from("cube.ron"))
+}
+
+/// Command line of native targets, `[scene] [--watch <dir>] [--shaders <dir>]`
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Default)]
+struct Arguments {
+    scene_file: Option<String>,
+    /// Directory to read the assets from and reload them when they change
+    watch: Option<std::path::PathBuf>,
+    /// Directory to read the shaders from and reload them when they change
+    shaders: Option<std::path::PathBuf>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn arguments() -> Arguments {
+    let mut arguments = Arguments::default();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--watch" => arguments.watch = args.next().map(std::path::PathBuf::from),
+            "--shaders" => arguments.shaders = args.next().map(std::path::PathBuf::from),
+            _ => arguments.scene_file = Some(arg),
+        }
     }
+
+    arguments
+}
+
+fn create_multisampled_texture(
+    device: &wgpu::Device,
+    width: u32,
+    height: u32,
+    sample_count: u32,
+) -> Option<texture::Texture> {
+    (sample_count > 1).then(|| {
+        texture::Texture::create_multisampled_target(
+            device,
+            width,
+            height,
+            texture::Texture::HDR_FORMAT,
+            sample_count,
+            "multisampled_texture",
+        )
+    })
 }
 
 async fn run(event_loop: EventLoop<()>, window: Window) {
-    let mut state = State::new(&window).await;
+    #[cfg(not(target_arch = "wasm32"))]
+    let arguments = arguments();
+
+    #[cfg(not(target_arch = "wasm32"))]
+    if let Some(dir) = &arguments.watch {
+        resources::set_asset_dir(dir);
+    }
+
+    let mut state = match State::new(&window, &RendererConfig::default()).await {
+        Ok(state) => state,
+        Err(e) => {
+            log::error!("Could not initialize the renderer: {}", e);
+            return;
+        }
+    };
+
+    #[cfg(not(target_arch = "wasm32"))]
+    if arguments.watch.is_some() {
+        if let Err(e) = state.watch() {
+            log::error!("Could not watch the assets: {}", e);
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    if let Some(dir) = &arguments.shaders {
+        if let Err(e) = state.watch_shaders(dir) {
+            log::error!("Could not watch the shaders:\n{}", e);
+        }
+    }
 
     let mut last_render_time = instant::Instant::now();
 
@@ -219,6 +1142,108 @@ async fn run(event_loop: EventLoop<()>, window: Window) {
                         },
                     ..
                 } => elwt.exit(),
+                WindowEvent::KeyboardInput {
+                    event:
+                        KeyEvent {
+                            state: ElementState::Pressed,
+                            logical_key: Key::Named(NamedKey::F2),
+                            ..
+                        },
+                    ..
+                } => state.cycle_present_mode(),
+                WindowEvent::KeyboardInput {
+                    event:
+                        KeyEvent {
+                            state: ElementState::Pressed,
+                            logical_key: Key::Named(NamedKey::F3),
+                            ..
+                        },
+                    ..
+                } => state.cycle_tonemapping(),
+                WindowEvent::KeyboardInput {
+                    event:
+                        KeyEvent {
+                            state: ElementState::Pressed,
+                            logical_key: Key::Named(NamedKey::F4),
+                            ..
+                        },
+                    ..
+                } => state.scale_exposure(0.5),
+                WindowEvent::KeyboardInput {
+                    event:
+                        KeyEvent {
+                            state: ElementState::Pressed,
+                            logical_key: Key::Named(NamedKey::F5),
+                            ..
+                        },
+                    ..
+                } => state.scale_exposure(2.0),
+                WindowEvent::KeyboardInput {
+                    event:
+                        KeyEvent {
+                            state: ElementState::Pressed,
+                            logical_key: Key::Named(NamedKey::F6),
+                            ..
+                        },
+                    ..
+                } => state.cycle_sample_count(),
+                WindowEvent::KeyboardInput {
+                    event:
+                        KeyEvent {
+                            state: ElementState::Pressed,
+                            logical_key: Key::Named(NamedKey::F7),
+                            ..
+                        },
+                    ..
+                } => state.toggle_post_processing(),
+                WindowEvent::KeyboardInput {
+                    event:
+                        KeyEvent {
+                            state: ElementState::Pressed,
+                            logical_key: Key::Named(NamedKey::F8),
+                            ..
+                        },
+                    ..
+                } => state.cycle_ssao_quality(),
+                WindowEvent::KeyboardInput {
+                    event:
+                        KeyEvent {
+                            state: ElementState::Pressed,
+                            logical_key: Key::Named(NamedKey::F9),
+                            ..
+                        },
+                    ..
+                } => state.toggle_normal_maps(),
+                WindowEvent::KeyboardInput {
+                    event:
+                        KeyEvent {
+                            state: ElementState::Pressed,
+                            logical_key: Key::Named(NamedKey::F10),
+                            ..
+                        },
+                    ..
+                } => state.toggle_shadows(),
+                #[cfg(not(target_arch = "wasm32"))]
+                WindowEvent::KeyboardInput {
+                    event:
+                        KeyEvent {
+                            state: ElementState::Pressed,
+                            logical_key: Key::Named(NamedKey::F12),
+                            ..
+                        },
+                    ..
+                } => {
+                    let timestamp = std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs();
+                    let path = format!("screenshot-{}.png", timestamp);
+
+                    match state.screenshot(&path) {
+                        Ok(()) => log::info!("Saved screenshot to {}", path),
+                        Err(e) => log::error!("Could not save screenshot: {}", e),
+                    }
+                }
                 WindowEvent::Resized(physical_size) => {
                     cfg_if::cfg_if! {
                         if #[cfg(not(target_arch = "wasm32"))]{
@@ -1,18 +1,50 @@
-use std::{marker::PhantomData, mem};
+use std::marker::PhantomData;
 
 use cgmath::*;
 use instant::Duration;
 use wgpu::util::DeviceExt;
 
-use crate::texture;
+use crate::environment;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::reflection;
+
+/// Lights a uniform buffer holds, must match the array length in [`Lights::declaration`]
+pub const MAX_LIGHTS: usize = 16;
+
+/// Lights that cannot be put in a buffer
+#[derive(Debug, thiserror::Error)]
+pub enum LightsError {
+    #[error("{0} lights, at most {MAX_LIGHTS} fit in a uniform buffer")]
+    TooMany(usize),
+    #[error("the shadow caster is light {index} but there are {count} lights")]
+    ShadowCaster { index: usize, count: usize },
+}
+
+/// How a light spreads its light into the scene
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    /// Shines in every direction from its position
+    Point,
+    /// Shines along `direction` everywhere, like the sun, the position only places the gizmo
+    Directional { direction: Vector3<f32> },
+    /// Shines along `direction` in a cone, fading out between the inner and outer angle
+    Spot {
+        direction: Vector3<f32>,
+        inner_angle: Rad<f32>,
+        outer_angle: Rad<f32>,
+    },
+}
 
 pub struct Light {
+    pub kind: Kind,
     pub position: Point3<f32>,
     pub color: [f32; 3],
+    pub intensity: f32,
+    /// Distance at which the light has faded out completely, ignored by directional lights
+    pub range: f32,
+    /// Constant, linear and quadratic falloff with the distance
+    pub attenuation: [f32; 3],
     pub controller: Controller,
-    pub buffer: wgpu::Buffer,
-    pub bind_group_layout: wgpu::BindGroupLayout,
-    pub bind_group: wgpu::BindGroup,
     hidden: PhantomData<()>,
 }
 
@@ -20,6 +52,10 @@ pub struct LightBuilder<P, C, L> {
     position: P,
     color: C,
     control: L,
+    kind: Kind,
+    intensity: f32,
+    range: f32,
+    attenuation: [f32; 3],
 }
 
 impl<P, C, L> LightBuilder<P, C, L> {
@@ -28,6 +64,10 @@ impl<P, C, L> LightBuilder<P, C, L> {
             position: position.into(),
             color: self.color,
             control: self.control,
+            kind: self.kind,
+            intensity: self.intensity,
+            range: self.range,
+            attenuation: self.attenuation,
         }
     }
 
@@ -36,6 +76,10 @@ impl<P, C, L> LightBuilder<P, C, L> {
             position: self.position,
             color,
             control: self.control,
+            kind: self.kind,
+            intensity: self.intensity,
+            range: self.range,
+            attenuation: self.attenuation,
         }
     }
 
@@ -44,55 +88,65 @@ impl<P, C, L> LightBuilder<P, C, L> {
             position: self.position,
             color: self.color,
             control: Controller::new(angular_velocity),
+            kind: self.kind,
+            intensity: self.intensity,
+            range: self.range,
+            attenuation: self.attenuation,
         }
     }
-}
 
-impl LightBuilder<Point3<f32>, [f32; 3], Controller> {
-    pub fn finalize(self, device: &wgpu::Device) -> Light {
-        let uniform = FlatLight {
-            position: self.position.into(),
-            _padding: 0,
-            color: self.color,
-            _padding2: 0,
+    /// Makes this a directional light shining along `direction`
+    pub fn directional<V: Into<Vector3<f32>>>(mut self, direction: V) -> Self {
+        self.kind = Kind::Directional {
+            direction: direction.into().normalize(),
         };
+        self
+    }
 
-        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Light VB"),
-            contents: bytemuck::cast_slice(&[uniform]),
-            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-        });
+    /// Makes this a spot light shining along `direction` in a cone of `outer_angle` from its axis
+    pub fn spot<V: Into<Vector3<f32>>, A: Into<Rad<f32>>>(
+        mut self,
+        direction: V,
+        inner_angle: A,
+        outer_angle: A,
+    ) -> Self {
+        self.kind = Kind::Spot {
+            direction: direction.into().normalize(),
+            inner_angle: inner_angle.into(),
+            outer_angle: outer_angle.into(),
+        };
+        self
+    }
 
-        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-            entries: &[wgpu::BindGroupLayoutEntry {
-                binding: 0,
-                visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
-                ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Uniform,
-                    has_dynamic_offset: false,
-                    min_binding_size: None,
-                },
-                count: None,
-            }],
-            label: None,
-        });
+    /// Scales the colour, 1.0 by default
+    pub fn intensity(mut self, intensity: f32) -> Self {
+        self.intensity = intensity;
+        self
+    }
 
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: buffer.as_entire_binding(),
-            }],
-            label: None,
-        });
+    /// Distance at which the light has faded out, 100.0 by default
+    pub fn range(mut self, range: f32) -> Self {
+        self.range = range;
+        self
+    }
+
+    /// Divides the light by `constant + linear * d + quadratic * d²`, no falloff by default
+    pub fn attenuation(mut self, constant: f32, linear: f32, quadratic: f32) -> Self {
+        self.attenuation = [constant, linear, quadratic];
+        self
+    }
+}
 
+impl LightBuilder<Point3<f32>, [f32; 3], Controller> {
+    pub fn finalize(self) -> Light {
         Light {
+            kind: self.kind,
             position: self.position,
             color: self.color,
+            intensity: self.intensity,
+            range: self.range,
+            attenuation: self.attenuation,
             controller: self.control,
-            bind_group_layout,
-            bind_group,
-            buffer,
             hidden: PhantomData,
         }
     }
@@ -104,41 +158,377 @@ impl Light {
             position: None,
             color: None,
             control: None,
+            kind: Kind::Point,
+            intensity: 1.0,
+            range: 100.0,
+            attenuation: [1.0, 0.0, 0.0],
         }
     }
 
-    pub fn update(&mut self, dt: Duration, queue: &wgpu::Queue) {
-        let old_position: cgmath::Vector3<_> = self.position.to_vec();
-
-        self.position = Point3::from_vec(
-            cgmath::Quaternion::from_axis_angle(
-                (0.0, 1.0, 0.0).into(),
-                cgmath::Deg(self.controller.angular_velocity * dt.as_secs_f32()),
-            ) * old_position,
+    /// Orbits the light around the y axis, turning its direction with it
+    pub fn update(&mut self, dt: Duration) {
+        let rotation = Quaternion::from_axis_angle(
+            Vector3::unit_y(),
+            Deg(self.controller.angular_velocity * dt.as_secs_f32()),
         );
 
-        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[self.flattened()]));
+        self.position = Point3::from_vec(rotation * self.position.to_vec());
+
+        match &mut self.kind {
+            Kind::Point => {}
+            Kind::Directional { direction } | Kind::Spot { direction, .. } => {
+                *direction = rotation * *direction;
+            }
+        }
     }
 
     fn flattened(&self) -> FlatLight {
+        let (kind, direction, inner_cos, outer_cos) = match self.kind {
+            Kind::Point => (0, Vector3::zero(), 0.0, 0.0),
+            Kind::Directional { direction } => (1, direction, 0.0, 0.0),
+            Kind::Spot {
+                direction,
+                inner_angle,
+                outer_angle,
+            } => (2, direction, inner_angle.cos(), outer_angle.cos()),
+        };
+
         FlatLight {
             position: self.position.into(),
-            _padding: 0,
+            kind,
             color: self.color,
-            _padding2: 0,
+            intensity: self.intensity,
+            direction: direction.into(),
+            range: self.range,
+            attenuation: self.attenuation,
+            inner_cos,
+            outer_cos,
+            _padding: [0; 3],
+        }
+    }
+}
+
+/// Every light in the scene, in one buffer the model shader loops over, alongside the
+/// environment maps lighting the models from all around
+pub struct Lights {
+    pub lights: Vec<Light>,
+    /// Index of the light the shadow map follows
+    pub shadow_caster: Option<usize>,
+    environment_intensity: f32,
+    max_reflection_lod: f32,
+    storage: bool,
+    pub buffer: wgpu::Buffer,
+    pub bind_group_layout: wgpu::BindGroupLayout,
+    pub bind_group: wgpu::BindGroup,
+}
+
+pub struct LightsBuilder {
+    lights: Vec<Light>,
+    shadow_caster: Option<usize>,
+    storage: bool,
+}
+
+impl LightsBuilder {
+    pub fn light(mut self, light: Light) -> Self {
+        self.lights.push(light);
+        self
+    }
+
+    /// Casts shadows from the light at `index`, the first point light by default, or the first
+    /// light without point lights
+    pub fn shadow_caster(mut self, index: usize) -> Self {
+        self.shadow_caster = Some(index);
+        self
+    }
+
+    /// Stores the lights in a storage buffer rather than a uniform array of [`MAX_LIGHTS`],
+    /// see [`crate::init::supports_vertex_storage`]
+    pub fn storage(mut self, storage: bool) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// Fails without storage buffers when there are more than [`MAX_LIGHTS`] lights, or when the
+    /// shadow caster is not one of them
+    pub fn finalize(
+        self,
+        device: &wgpu::Device,
+        environment: &environment::Environment,
+    ) -> Result<Lights, LightsError> {
+        if !self.storage && self.lights.len() > MAX_LIGHTS {
+            return Err(LightsError::TooMany(self.lights.len()));
+        }
+        if let Some(index) = self
+            .shadow_caster
+            .filter(|&index| index >= self.lights.len())
+        {
+            return Err(LightsError::ShadowCaster {
+                index,
+                count: self.lights.len(),
+            });
+        }
+
+        let shadow_caster = self
+            .shadow_caster
+            .or_else(|| {
+                self.lights
+                    .iter()
+                    .position(|light| light.kind == Kind::Point)
+            })
+            .or((!self.lights.is_empty()).then_some(0));
+
+        let (usage, ty, capacity) = if self.storage {
+            (
+                wgpu::BufferUsages::STORAGE,
+                wgpu::BufferBindingType::Storage { read_only: true },
+                self.lights.len().max(1),
+            )
+        } else {
+            (
+                wgpu::BufferUsages::UNIFORM,
+                wgpu::BufferBindingType::Uniform,
+                MAX_LIGHTS,
+            )
+        };
+
+        let header = FlatLights::new(
+            &self.lights,
+            shadow_caster,
+            environment.intensity,
+            environment.max_reflection_lod(),
+        );
+
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Lights Buffer"),
+            contents: &flatten(header, &self.lights, capacity),
+            usage: usage | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let environment_map = |binding, view_dimension| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                view_dimension,
+                multisampled: false,
+            },
+            count: None,
+        };
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                environment_map(1, wgpu::TextureViewDimension::Cube), // irradiance
+                environment_map(2, wgpu::TextureViewDimension::Cube), // prefiltered
+                environment_map(3, wgpu::TextureViewDimension::D2),   // BRDF lookup table
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+            label: Some("Lights bind group layout"),
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&environment.irradiance.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&environment.prefiltered.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(&environment.brdf_lut.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: wgpu::BindingResource::Sampler(&environment.prefiltered.sampler),
+                },
+            ],
+            label: Some("Lights bind group"),
+        });
+
+        Ok(Lights {
+            lights: self.lights,
+            shadow_caster,
+            environment_intensity: environment.intensity,
+            max_reflection_lod: environment.max_reflection_lod(),
+            storage: self.storage,
+            buffer,
+            bind_group_layout,
+            bind_group,
+        })
+    }
+}
+
+impl Lights {
+    pub fn build() -> LightsBuilder {
+        LightsBuilder {
+            lights: Vec::new(),
+            shadow_caster: None,
+            storage: false,
+        }
+    }
+
+    pub fn update(&mut self, dt: Duration, queue: &wgpu::Queue) {
+        for light in &mut self.lights {
+            light.update(dt);
+        }
+
+        let header = FlatLights::new(
+            &self.lights,
+            self.shadow_caster,
+            self.environment_intensity,
+            self.max_reflection_lod,
+        );
+
+        queue.write_buffer(
+            &self.buffer,
+            0,
+            &flatten(header, &self.lights, self.lights.len()),
+        );
+    }
+
+    /// Light casting shadows, if there is one
+    pub fn shadow_caster(&self) -> Option<&Light> {
+        self.shadow_caster.map(|index| &self.lights[index])
+    }
+
+    /// WGSL declaring the `Light` struct, the `lights` binding and the environment maps of
+    /// `@group(group)`, to append to the shaders using this bind group before preprocessing them
+    pub fn declaration(&self, group: u32) -> String {
+        declaration(group, self.storage)
+    }
+}
+
+/// [`Lights::declaration`] of lights in a storage buffer or in a uniform array
+pub fn declaration(group: u32, storage: bool) -> String {
+    let (address_space, array) = if storage {
+        ("storage, read", "array<Light>".to_string())
+    } else {
+        ("uniform", format!("array<Light, {}>", MAX_LIGHTS))
+    };
+
+    format!(
+        "
+#include \"lights.wgsl\"
+
+struct Lights {{
+    count: u32,
+    shadow_caster: u32,
+    environment_intensity: f32,
+    max_reflection_lod: f32,
+    lights: {array},
+}}
+
+@group({group}) @binding(0) var<{address_space}> lights: Lights;
+@group({group}) @binding(1) var t_irradiance: texture_cube<f32>;
+@group({group}) @binding(2) var t_prefiltered: texture_cube<f32>;
+@group({group}) @binding(3) var t_brdf_lut: texture_2d<f32>;
+@group({group}) @binding(4) var s_environment: sampler;
+"
+    )
+}
+
+/// Buffer contents, the header followed by `capacity` lights
+fn flatten(header: FlatLights, lights: &[Light], capacity: usize) -> Vec<u8> {
+    let mut flat_lights: Vec<FlatLight> = lights.iter().map(Light::flattened).collect();
+    flat_lights.resize(capacity.max(lights.len()), bytemuck::Zeroable::zeroed());
+
+    let mut contents = bytemuck::bytes_of(&header).to_vec();
+    contents.extend_from_slice(bytemuck::cast_slice(&flat_lights));
+    contents
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct FlatLights {
+    count: u32,
+    // u32::MAX without a shadow caster
+    shadow_caster: u32,
+    environment_intensity: f32,
+    // prefiltered level of the roughest reflections
+    max_reflection_lod: f32,
+}
+
+impl FlatLights {
+    fn new(
+        lights: &[Light],
+        shadow_caster: Option<usize>,
+        environment_intensity: f32,
+        max_reflection_lod: f32,
+    ) -> Self {
+        Self {
+            count: lights.len() as u32,
+            shadow_caster: shadow_caster.map_or(u32::MAX, |index| index as u32),
+            environment_intensity,
+            max_reflection_lod,
         }
     }
 }
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
-struct FlatLight {
-    pub position: [f32; 3],
-    // Due to uniforms requiring 16 byte (4 float) spacing, we need to use a padding field here
-    pub _padding: u32, // could use [f32;4] instead
-    pub color: [f32; 3],
-    // Due to uniforms requiring 16 byte (4 float) spacing, we need to use a padding field here
-    pub _padding2: u32,
+pub(crate) struct FlatLight {
+    position: [f32; 3],
+    kind: u32,
+    color: [f32; 3],
+    intensity: f32,
+    direction: [f32; 3],
+    range: f32,
+    attenuation: [f32; 3],
+    inner_cos: f32,
+    outer_cos: f32,
+    // array elements are padded to 16 bytes
+    _padding: [u32; 3],
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl FlatLights {
+    pub(crate) fn host_layout() -> reflection::HostLayout {
+        reflection::host_layout!(FlatLights {
+            count,
+            shadow_caster,
+            environment_intensity,
+            max_reflection_lod,
+        })
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl FlatLight {
+    pub(crate) fn host_layout() -> reflection::HostLayout {
+        reflection::host_layout!(FlatLight {
+            position,
+            kind,
+            color,
+            intensity,
+            direction,
+            range,
+            attenuation,
+            inner_cos,
+            outer_cos,
+        })
+    }
 }
 
 pub struct Controller {
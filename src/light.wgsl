@@ -1,17 +1,9 @@
 
-struct Camera {
-    view_pos: vec4<f32>,
-    view_proj: mat4x4<f32>,
-}
+#include "camera.wgsl"
 
 @group(0) @binding(0) var<uniform> camera: Camera;
 
-struct Light {
-    position: vec3<f32>,
-    color: vec3<f32>,
-}
-
-@group(1) @binding(0) var<uniform> light: Light;
+// `Light`, the `lights` binding and the environment maps of group 1 are appended by `light::Lights::declaration`
 
 struct VertexInput {
     @location(0) position: vec3<f32>,
@@ -25,7 +17,10 @@ struct VertexOutput {
 @vertex
 fn vs_main(
     model: VertexInput,
+    // one gizmo instance per light
+    @builtin(instance_index) index: u32,
 ) -> VertexOutput {
+    let light = lights.lights[index];
     let scale = 0.25;
     var out: VertexOutput;
     out.clip_position = camera.view_proj * vec4<f32>(model.position * scale + light.position, 1.0);
@@ -1,3 +1,9 @@
+use std::rc::Rc;
+
+use wgpu::util::DeviceExt;
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::reflection;
 use crate::texture;
 
 #[repr(C)]
@@ -6,8 +12,20 @@ pub struct ModelVertex {
     pub position: [f32; 3],
     pub tex_coords: [f32; 2],
     pub normal: [f32; 3],
-    pub tangent: [f32; 3],
-    pub bitangent: [f32; 3],
+    /// MikkTSpace tangent, `w` is the handedness of the bitangent `cross(normal, tangent) * w`
+    pub tangent: [f32; 4],
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl ModelVertex {
+    pub(crate) fn host_layout() -> reflection::HostLayout {
+        reflection::host_layout!(ModelVertex {
+            position,
+            tex_coords,
+            normal,
+            tangent
+        })
+    }
 }
 
 pub struct Mesh {
@@ -18,10 +36,68 @@ pub struct Mesh {
     pub material: usize,
 }
 
+/// Factors multiplying the material maps, alpha of `base_color` is the opacity
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct FlatMaterial {
+    pub base_color: [f32; 4],
+    pub emissive: [f32; 3],
+    pub metallic: f32,
+    pub roughness: f32,
+    /// How much of the occlusion map applies, 0.0 ignores it
+    pub occlusion_strength: f32,
+    /// Scales the x and y of the normal map
+    pub normal_scale: f32,
+    // uniforms are padded to 16 bytes
+    pub _padding: u32,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl FlatMaterial {
+    pub(crate) fn host_layout() -> reflection::HostLayout {
+        reflection::host_layout!(FlatMaterial {
+            base_color,
+            emissive,
+            metallic,
+            roughness,
+            occlusion_strength,
+            normal_scale,
+        })
+    }
+}
+
+impl Default for FlatMaterial {
+    fn default() -> Self {
+        Self {
+            base_color: [1.0; 4],
+            emissive: [0.0; 3],
+            metallic: 0.0,
+            roughness: 0.5,
+            occlusion_strength: 1.0,
+            normal_scale: 1.0,
+            _padding: 0,
+        }
+    }
+}
+
+/// Maps of a metallic-roughness material. Metallic is read from the blue channel and roughness
+/// from the green one, so a glTF metallic-roughness map can fill both, shared rather than
+/// uploaded twice.
+pub struct MaterialTextures {
+    pub base_color: texture::Texture,
+    pub normal: texture::Texture,
+    pub metallic: Rc<texture::Texture>,
+    pub roughness: Rc<texture::Texture>,
+    /// Ambient occlusion in the red channel
+    pub occlusion: texture::Texture,
+    pub emissive: texture::Texture,
+}
+
 pub struct Material {
     pub name: String,
-    pub diffuse_texture: texture::Texture,
-    pub normal_texture: texture::Texture,
+    pub textures: MaterialTextures,
+    pub factors: FlatMaterial,
+    pub buffer: wgpu::Buffer,
     pub bind_group: wgpu::BindGroup,
 }
 
@@ -29,43 +105,68 @@ pub struct Model {
     pub meshes: Vec<Mesh>,
     pub materials: Vec<Material>,
     pub materials_layout: wgpu::BindGroupLayout,
+    /// Files the model was loaded from, with its material libraries, buffers and textures. A
+    /// change to any of them reloads the whole model
+    pub files: Vec<String>,
 }
 
 impl Material {
     pub fn new(
         device: &wgpu::Device,
         name: &str,
-        diffuse_texture: texture::Texture,
-        normal_texture: texture::Texture,
+        textures: MaterialTextures,
+        factors: FlatMaterial,
         layout: &wgpu::BindGroupLayout,
     ) -> Self {
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(name),
+            contents: bytemuck::cast_slice(&[factors]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        // each map is bound as a texture followed by its sampler, in the order of the layout
+        let maps = [
+            &textures.base_color,
+            &textures.normal,
+            &textures.metallic,
+            &textures.roughness,
+            &textures.occlusion,
+            &textures.emissive,
+        ];
+
+        let mut entries = maps
+            .iter()
+            .enumerate()
+            .flat_map(|(i, texture)| {
+                [
+                    wgpu::BindGroupEntry {
+                        binding: i as u32 * 2,
+                        resource: wgpu::BindingResource::TextureView(&texture.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: i as u32 * 2 + 1,
+                        resource: wgpu::BindingResource::Sampler(&texture.sampler),
+                    },
+                ]
+            })
+            .collect::<Vec<_>>();
+
+        entries.push(wgpu::BindGroupEntry {
+            binding: maps.len() as u32 * 2,
+            resource: buffer.as_entire_binding(),
+        });
+
         let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 2,
-                    resource: wgpu::BindingResource::TextureView(&normal_texture.view),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 3,
-                    resource: wgpu::BindingResource::Sampler(&normal_texture.sampler),
-                },
-            ],
+            entries: &entries,
             label: Some(name),
         });
 
         Self {
             name: String::from(name),
-            diffuse_texture,
-            normal_texture,
+            textures,
+            factors,
+            buffer,
             bind_group,
         }
     }
@@ -1,54 +1,134 @@
 use std::mem;
 
-use crate::{light, model, texture, transforms, view};
+use crate::preprocessor::{Expanded, PreprocessError};
+use crate::{environment, light, model, shaders, shadows, texture, transforms, view};
 
+/// Layout of [`model::ModelVertex`] in the vertex buffer
+pub fn model_vertex_layout() -> wgpu::VertexBufferLayout<'static> {
+    const ATTRIBUTES: [wgpu::VertexAttribute; 4] = [
+        wgpu::VertexAttribute {
+            offset: 0,
+            shader_location: 0, // position
+            format: wgpu::VertexFormat::Float32x3,
+        },
+        wgpu::VertexAttribute {
+            offset: mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
+            shader_location: 1, // tex_coords
+            format: wgpu::VertexFormat::Float32x2,
+        },
+        wgpu::VertexAttribute {
+            offset: mem::size_of::<[f32; 5]>() as wgpu::BufferAddress,
+            shader_location: 2, // normal
+            format: wgpu::VertexFormat::Float32x3,
+        },
+        wgpu::VertexAttribute {
+            offset: mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
+            shader_location: 3, // tangent and bitangent sign
+            format: wgpu::VertexFormat::Float32x4,
+        },
+    ];
+
+    wgpu::VertexBufferLayout {
+        array_stride: mem::size_of::<model::ModelVertex>() as wgpu::BufferAddress,
+        step_mode: wgpu::VertexStepMode::Vertex,
+        attributes: &ATTRIBUTES,
+    }
+}
+
+/// Layout of [`transforms::FlatTransform`] in the instance buffer
+pub fn instances_layout() -> wgpu::VertexBufferLayout<'static> {
+    const ATTRIBUTES: [wgpu::VertexAttribute; 7] = [
+        wgpu::VertexAttribute {
+            offset: 0,
+            shader_location: 5, // 1st row of  model transformation matrix
+            format: wgpu::VertexFormat::Float32x4,
+        },
+        wgpu::VertexAttribute {
+            offset: mem::size_of::<[f32; 4]>() as wgpu::BufferAddress,
+            shader_location: 6, // 2nd row of model transformation matrix
+            format: wgpu::VertexFormat::Float32x4,
+        },
+        wgpu::VertexAttribute {
+            offset: mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
+            shader_location: 7, // 3rd row of model transformation matrix
+            format: wgpu::VertexFormat::Float32x4,
+        },
+        wgpu::VertexAttribute {
+            offset: mem::size_of::<[f32; 12]>() as wgpu::BufferAddress,
+            shader_location: 8, // 4th row of model transformation matrix
+            format: wgpu::VertexFormat::Float32x4,
+        },
+        wgpu::VertexAttribute {
+            offset: mem::size_of::<[f32; 16]>() as wgpu::BufferAddress,
+            shader_location: 9, // 1st row of normal transformation matrix
+            format: wgpu::VertexFormat::Float32x3,
+        },
+        wgpu::VertexAttribute {
+            offset: mem::size_of::<[f32; 19]>() as wgpu::BufferAddress,
+            shader_location: 10, // 2nd row of normal transformation matrix
+            format: wgpu::VertexFormat::Float32x3,
+        },
+        wgpu::VertexAttribute {
+            offset: mem::size_of::<[f32; 22]>() as wgpu::BufferAddress,
+            shader_location: 11, // 3rd row of normal transformation matrix
+            format: wgpu::VertexFormat::Float32x3,
+        },
+    ];
+
+    wgpu::VertexBufferLayout {
+        array_stride: mem::size_of::<transforms::FlatTransform>() as wgpu::BufferAddress,
+        step_mode: wgpu::VertexStepMode::Instance,
+        attributes: &ATTRIBUTES,
+    }
+}
+
+/// Preprocessed light gizmo shader with the light declarations it uses
+pub fn light_shader_source(
+    lights: &light::Lights,
+    shaders: &shaders::Shaders,
+) -> Result<Expanded, PreprocessError> {
+    // appended so diagnostics keep the lines of the file
+    let source = shaders.light.clone() + &lights.declaration(1);
+    shaders.preprocessor().process(&source, shaders::LIGHT_FILE)
+}
+
+/// Preprocessed model shader with the light declarations it uses, the variant with `features`
+pub fn model_shader_source(
+    lights: &light::Lights,
+    shaders: &shaders::Shaders,
+    features: shaders::Features,
+) -> Result<Expanded, PreprocessError> {
+    let source = shaders.model.clone() + &lights.declaration(2);
+    features
+        .define(shaders.preprocessor())
+        .process(&source, shaders::MODEL_FILE)
+}
+
+/// Preprocesses a shader baked into the binary, whose directives are known to be valid
+fn baked_shader_source(source: &str, file: &str) -> String {
+    shaders::preprocess(source, file)
+        .unwrap_or_else(|e| panic!("{}", e))
+        .source
+}
+
+/// Draws the lights with `source` from [`light_shader_source`]
 pub fn create_light_render_pipeline(
     device: &wgpu::Device,
-    config: &wgpu::SurfaceConfiguration,
+    format: wgpu::TextureFormat,
+    sample_count: u32,
     view: &view::View,
-    light: &light::Light,
+    lights: &light::Lights,
+    source: &str,
 ) -> wgpu::RenderPipeline {
     let bind_groups_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
         label: Some("Light Pipeline Layout"),
-        bind_group_layouts: &[&view.bind_group_layout, &light.bind_group_layout],
+        bind_group_layouts: &[&view.bind_group_layout, &lights.bind_group_layout],
         push_constant_ranges: &[],
     });
 
-    let vertex_layout = wgpu::VertexBufferLayout {
-        array_stride: mem::size_of::<model::ModelVertex>() as wgpu::BufferAddress,
-        step_mode: wgpu::VertexStepMode::Vertex,
-        attributes: &[
-            wgpu::VertexAttribute {
-                offset: 0,
-                shader_location: 0,
-                format: wgpu::VertexFormat::Float32x3,
-            },
-            wgpu::VertexAttribute {
-                offset: mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
-                shader_location: 1,
-                format: wgpu::VertexFormat::Float32x2,
-            },
-            wgpu::VertexAttribute {
-                offset: mem::size_of::<[f32; 5]>() as wgpu::BufferAddress,
-                shader_location: 2,
-                format: wgpu::VertexFormat::Float32x3,
-            },
-            wgpu::VertexAttribute {
-                offset: mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
-                shader_location: 3,
-                format: wgpu::VertexFormat::Float32x3,
-            },
-            wgpu::VertexAttribute {
-                offset: mem::size_of::<[f32; 11]>() as wgpu::BufferAddress,
-                shader_location: 4,
-                format: wgpu::VertexFormat::Float32x3,
-            },
-        ],
-    };
-
     let shader = wgpu::ShaderModuleDescriptor {
         label: Some("Light Shader"),
-        source: wgpu::ShaderSource::Wgsl(include_str!("light.wgsl").into()),
+        source: wgpu::ShaderSource::Wgsl(source.into()),
     };
 
     let shader = device.create_shader_module(shader);
@@ -59,13 +139,13 @@ pub fn create_light_render_pipeline(
         vertex: wgpu::VertexState {
             module: &shader,
             entry_point: "vs_main",
-            buffers: &[vertex_layout],
+            buffers: &[model_vertex_layout()],
         },
         fragment: Some(wgpu::FragmentState {
             module: &shader,
             entry_point: "fs_main",
             targets: &[Some(wgpu::ColorTargetState {
-                format: config.format,
+                format,
                 blend: Some(wgpu::BlendState {
                     color: wgpu::BlendComponent {
                         src_factor: wgpu::BlendFactor::SrcAlpha,
@@ -94,7 +174,7 @@ pub fn create_light_render_pipeline(
             bias: wgpu::DepthBiasState::default(),
         }),
         multisample: wgpu::MultisampleState {
-            count: 1,
+            count: sample_count,
             mask: !0,
             alpha_to_coverage_enabled: false,
         },
@@ -102,19 +182,25 @@ pub fn create_light_render_pipeline(
     })
 }
 
+/// Draws the models with `source` from [`model_shader_source`]
+#[allow(clippy::too_many_arguments)]
 pub fn create_model_render_pipeline(
     device: &wgpu::Device,
-    config: &wgpu::SurfaceConfiguration,
+    format: wgpu::TextureFormat,
+    sample_count: u32,
     model: &model::Model,
     view: &view::View,
-    light: &light::Light,
+    lights: &light::Lights,
+    shadow_map: &shadows::ShadowMap,
+    source: &str,
 ) -> wgpu::RenderPipeline {
     let render_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
         label: Some("Render Pipeline Layout"),
         bind_group_layouts: &[
-            &model.materials_layout,  // group(0)
-            &view.bind_group_layout,  // group(1)
-            &light.bind_group_layout, // group(2)
+            &model.materials_layout,       // group(0)
+            &view.bind_group_layout,       // group(1)
+            &lights.bind_group_layout,     // group(2)
+            &shadow_map.bind_group_layout, // group(3)
         ],
         // render_pass.set_bind_group(0, &material.bind_group, &[]);
         // render_pass.set_bind_group(1, &self.camera_bind_group, &[]);
@@ -122,83 +208,9 @@ pub fn create_model_render_pipeline(
         push_constant_ranges: &[],
     });
 
-    let vertex_layout = wgpu::VertexBufferLayout {
-        array_stride: mem::size_of::<model::ModelVertex>() as wgpu::BufferAddress,
-        step_mode: wgpu::VertexStepMode::Vertex,
-        attributes: &[
-            wgpu::VertexAttribute {
-                offset: 0,
-                shader_location: 0, // position
-                format: wgpu::VertexFormat::Float32x3,
-            },
-            wgpu::VertexAttribute {
-                offset: mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
-                shader_location: 1, // tex_coords
-                format: wgpu::VertexFormat::Float32x2,
-            },
-            wgpu::VertexAttribute {
-                offset: mem::size_of::<[f32; 5]>() as wgpu::BufferAddress,
-                shader_location: 2, // normal
-                format: wgpu::VertexFormat::Float32x3,
-            },
-            wgpu::VertexAttribute {
-                offset: mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
-                shader_location: 3, // tangent
-                format: wgpu::VertexFormat::Float32x3,
-            },
-            wgpu::VertexAttribute {
-                offset: mem::size_of::<[f32; 11]>() as wgpu::BufferAddress,
-                shader_location: 4, // bitangent
-                format: wgpu::VertexFormat::Float32x3,
-            },
-        ],
-    };
-
-    let instances_layout = wgpu::VertexBufferLayout {
-        array_stride: mem::size_of::<transforms::FlatTransform>() as wgpu::BufferAddress,
-        step_mode: wgpu::VertexStepMode::Instance,
-        attributes: &[
-            wgpu::VertexAttribute {
-                offset: 0,
-                shader_location: 5, // 1st row of  model transformation matrix
-                format: wgpu::VertexFormat::Float32x4,
-            },
-            wgpu::VertexAttribute {
-                offset: mem::size_of::<[f32; 4]>() as wgpu::BufferAddress,
-                shader_location: 6, // 2nd row of model transformation matrix
-                format: wgpu::VertexFormat::Float32x4,
-            },
-            wgpu::VertexAttribute {
-                offset: mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
-                shader_location: 7, // 3rd row of model transformation matrix
-                format: wgpu::VertexFormat::Float32x4,
-            },
-            wgpu::VertexAttribute {
-                offset: mem::size_of::<[f32; 12]>() as wgpu::BufferAddress,
-                shader_location: 8, // 4th row of model transformation matrix
-                format: wgpu::VertexFormat::Float32x4,
-            },
-            wgpu::VertexAttribute {
-                offset: mem::size_of::<[f32; 16]>() as wgpu::BufferAddress,
-                shader_location: 9, // 1st row of normal transformation matrix
-                format: wgpu::VertexFormat::Float32x3,
-            },
-            wgpu::VertexAttribute {
-                offset: mem::size_of::<[f32; 19]>() as wgpu::BufferAddress,
-                shader_location: 10, // 2nd row of normal transformation matrix
-                format: wgpu::VertexFormat::Float32x3,
-            },
-            wgpu::VertexAttribute {
-                offset: mem::size_of::<[f32; 22]>() as wgpu::BufferAddress,
-                shader_location: 11, // 3rd row of normal transformation matrix
-                format: wgpu::VertexFormat::Float32x3,
-            },
-        ],
-    };
-
     let shader = wgpu::ShaderModuleDescriptor {
         label: Some("Normal Shader"),
-        source: wgpu::ShaderSource::Wgsl(include_str!("shader.wgsl").into()),
+        source: wgpu::ShaderSource::Wgsl(source.into()),
     };
 
     let shader = device.create_shader_module(shader);
@@ -209,13 +221,13 @@ pub fn create_model_render_pipeline(
         vertex: wgpu::VertexState {
             module: &shader,
             entry_point: "vs_main",
-            buffers: &[vertex_layout, instances_layout],
+            buffers: &[model_vertex_layout(), instances_layout()],
         },
         fragment: Some(wgpu::FragmentState {
             module: &shader,
             entry_point: "fs_main",
             targets: &[Some(wgpu::ColorTargetState {
-                format: config.format,
+                format,
                 blend: Some(wgpu::BlendState {
                     color: wgpu::BlendComponent {
                         src_factor: wgpu::BlendFactor::SrcAlpha,
@@ -244,7 +256,170 @@ pub fn create_model_render_pipeline(
             bias: wgpu::DepthBiasState::default(),
         }),
         multisample: wgpu::MultisampleState {
-            count: 1,
+            count: sample_count,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        },
+        multiview: None,
+    })
+}
+
+/// Depth only pipeline rendering the instances into a face of the shadow map
+pub fn create_shadow_render_pipeline(
+    device: &wgpu::Device,
+    shadow_map: &shadows::ShadowMap,
+) -> wgpu::RenderPipeline {
+    let bind_groups_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("Shadow Pipeline Layout"),
+        bind_group_layouts: &[&shadow_map.face_bind_group_layout],
+        push_constant_ranges: &[],
+    });
+
+    let shader = wgpu::ShaderModuleDescriptor {
+        label: Some("Shadow Shader"),
+        source: wgpu::ShaderSource::Wgsl(
+            baked_shader_source(include_str!("shadow.wgsl"), "shadow.wgsl").into(),
+        ),
+    };
+
+    let shader = device.create_shader_module(shader);
+
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("Shadow Pipeline"),
+        layout: Some(&bind_groups_layout),
+        vertex: wgpu::VertexState {
+            module: &shader,
+            entry_point: "vs_main",
+            buffers: &[model_vertex_layout(), instances_layout()],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: &shader,
+            entry_point: "fs_main",
+            targets: &[],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            // the faces are flipped vertically, which reverses the winding
+            front_face: wgpu::FrontFace::Cw,
+            cull_mode: Some(wgpu::Face::Back),
+            polygon_mode: wgpu::PolygonMode::Fill,
+            unclipped_depth: false,
+            conservative: false,
+        },
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: texture::Texture::DEPTH_FORMAT,
+            depth_write_enabled: true,
+            depth_compare: wgpu::CompareFunction::Less,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        multisample: wgpu::MultisampleState::default(),
+        multiview: None,
+    })
+}
+
+/// Depth only pipeline rendering the instances from the camera, without MSAA, for the ambient occlusion
+pub fn create_depth_prepass_pipeline(
+    device: &wgpu::Device,
+    view: &view::View,
+) -> wgpu::RenderPipeline {
+    let bind_groups_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("Depth Prepass Pipeline Layout"),
+        bind_group_layouts: &[&view.bind_group_layout],
+        push_constant_ranges: &[],
+    });
+
+    let shader = wgpu::ShaderModuleDescriptor {
+        label: Some("Depth Prepass Shader"),
+        source: wgpu::ShaderSource::Wgsl(
+            baked_shader_source(include_str!("prepass.wgsl"), "prepass.wgsl").into(),
+        ),
+    };
+
+    let shader = device.create_shader_module(shader);
+
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("Depth Prepass Pipeline"),
+        layout: Some(&bind_groups_layout),
+        vertex: wgpu::VertexState {
+            module: &shader,
+            entry_point: "vs_main",
+            buffers: &[model_vertex_layout(), instances_layout()],
+        },
+        fragment: None,
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: Some(wgpu::Face::Back),
+            polygon_mode: wgpu::PolygonMode::Fill,
+            unclipped_depth: false,
+            conservative: false,
+        },
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: texture::Texture::DEPTH_FORMAT,
+            depth_write_enabled: true,
+            depth_compare: wgpu::CompareFunction::Less,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        multisample: wgpu::MultisampleState::default(),
+        multiview: None,
+    })
+}
+
+/// Draws the environment behind the scene, first and without touching the depth
+pub fn create_skybox_render_pipeline(
+    device: &wgpu::Device,
+    format: wgpu::TextureFormat,
+    sample_count: u32,
+    view: &view::View,
+    environment: &environment::Environment,
+) -> wgpu::RenderPipeline {
+    let bind_groups_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("Skybox Pipeline Layout"),
+        bind_group_layouts: &[&view.bind_group_layout, &environment.bind_group_layout],
+        push_constant_ranges: &[],
+    });
+
+    let shader = wgpu::ShaderModuleDescriptor {
+        label: Some("Skybox Shader"),
+        source: wgpu::ShaderSource::Wgsl(
+            baked_shader_source(include_str!("skybox.wgsl"), "skybox.wgsl").into(),
+        ),
+    };
+
+    let shader = device.create_shader_module(shader);
+
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("Skybox Pipeline"),
+        layout: Some(&bind_groups_layout),
+        vertex: wgpu::VertexState {
+            module: &shader,
+            entry_point: "vs_main",
+            buffers: &[],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: &shader,
+            entry_point: "fs_main",
+            targets: &[Some(format.into())],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            // the camera is inside the cube
+            cull_mode: None,
+            ..Default::default()
+        },
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: texture::Texture::DEPTH_FORMAT,
+            depth_write_enabled: false,
+            depth_compare: wgpu::CompareFunction::Always,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        multisample: wgpu::MultisampleState {
+            count: sample_count,
             mask: !0,
             alpha_to_coverage_enabled: false,
         },
@@ -0,0 +1,698 @@
+use wgpu::util::DeviceExt;
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::reflection;
+use crate::texture;
+
+/// Full screen effect applied to the tonemapped frame, with its parameters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Effect {
+    /// Fast approximate anti-aliasing, pixels contrasting less than `edge_threshold` of their
+    /// brightest neighbour are kept, the others are blurred along the edge up to `span_max` pixels
+    Fxaa { edge_threshold: f32, span_max: f32 },
+    /// Glow around the colours of the HDR frame brighter than `threshold`, picked before
+    /// tonemapping and added to the tonemapped frame
+    Bloom { threshold: f32, intensity: f32 },
+    /// Darkens the corners from `radius` over `smoothness`, 1 being the distance to a corner
+    Vignette {
+        intensity: f32,
+        radius: f32,
+        smoothness: f32,
+    },
+    /// Remaps the colours through the lookup table, `intensity` blends with the original ones
+    ColorGrading { intensity: f32 },
+    /// Raises the colours to the power of 1 / `gamma`
+    Gamma { gamma: f32 },
+}
+
+impl Effect {
+    fn name(&self) -> &'static str {
+        match self {
+            Effect::Fxaa { .. } => "FXAA",
+            Effect::Bloom { .. } => "Bloom",
+            Effect::Vignette { .. } => "Vignette",
+            Effect::ColorGrading { .. } => "Color Grading",
+            Effect::Gamma { .. } => "Gamma",
+        }
+    }
+
+    fn shader(&self) -> &'static str {
+        match self {
+            Effect::Fxaa { .. } => include_str!("fxaa.wgsl"),
+            Effect::Bloom { .. } => include_str!("bloom.wgsl"),
+            Effect::Vignette { .. } => include_str!("vignette.wgsl"),
+            Effect::ColorGrading { .. } => include_str!("color_grading.wgsl"),
+            Effect::Gamma { .. } => include_str!("gamma.wgsl"),
+        }
+    }
+
+    /// Shader of the effect after the shared declarations, gamma encoding with `encode_srgb`
+    pub(crate) fn source(&self, encode_srgb: bool) -> String {
+        format!(
+            "const ENCODE_SRGB: bool = {};\n{}\n{}",
+            encode_srgb,
+            include_str!("postprocessing.wgsl"),
+            self.shader()
+        )
+    }
+
+    /// Texture the effect reads besides the frame, bound next to its parameters
+    fn texture_dimension(&self) -> Option<wgpu::TextureViewDimension> {
+        match self {
+            Effect::Bloom { .. } => Some(wgpu::TextureViewDimension::D2),
+            Effect::ColorGrading { .. } => Some(wgpu::TextureViewDimension::D3),
+            _ => None,
+        }
+    }
+}
+
+/// Effects applied between the tonemapping and the surface, the tonemapping pass renders into
+/// `input()` and each effect reads the frame left by the previous one
+pub struct PostProcessing {
+    /// Effects in the order they apply, call `update` after changing them
+    pub effects: Vec<Effect>,
+    pub enabled: bool,
+    format: wgpu::TextureFormat,
+    lut: texture::Texture,
+    input_layout: wgpu::BindGroupLayout,
+    /// Frames the effects render into in turn, in the output format
+    frames: [texture::Texture; 2],
+    inputs: [wgpu::BindGroup; 2],
+    /// Scene before tonemapping, the bloom picks its bright parts from it
+    hdr_input: wgpu::BindGroup,
+    passes: Vec<Pass>,
+}
+
+pub struct PostProcessingBuilder<E, L> {
+    effects: E,
+    lut: L,
+}
+
+impl<E, L> PostProcessingBuilder<E, L> {
+    pub fn effects(self, effects: Vec<Effect>) -> PostProcessingBuilder<Vec<Effect>, L> {
+        PostProcessingBuilder {
+            effects,
+            lut: self.lut,
+        }
+    }
+
+    /// Lookup table of the colour grading effect
+    pub fn lut(self, lut: texture::Texture) -> PostProcessingBuilder<E, texture::Texture> {
+        PostProcessingBuilder {
+            effects: self.effects,
+            lut,
+        }
+    }
+}
+
+impl PostProcessingBuilder<Vec<Effect>, texture::Texture> {
+    /// Creates the frames at the size and in the format of the output
+    pub fn finalize(
+        self,
+        device: &wgpu::Device,
+        hdr_texture: &texture::Texture,
+        width: u32,
+        height: u32,
+        format: wgpu::TextureFormat,
+    ) -> PostProcessing {
+        let input_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Post-processing input bind group layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+        });
+
+        let (frames, inputs) =
+            create_frames(device, &input_layout, width, height, format, "post_frame");
+        let hdr_input = create_input(device, &input_layout, hdr_texture);
+
+        let passes = self
+            .effects
+            .iter()
+            .map(|effect| {
+                Pass::new(
+                    device,
+                    *effect,
+                    &input_layout,
+                    &self.lut,
+                    format,
+                    width,
+                    height,
+                )
+            })
+            .collect();
+
+        PostProcessing {
+            effects: self.effects,
+            enabled: true,
+            format,
+            lut: self.lut,
+            input_layout,
+            frames,
+            inputs,
+            hdr_input,
+            passes,
+        }
+    }
+}
+
+impl PostProcessing {
+    pub fn build() -> PostProcessingBuilder<Option<Vec<Effect>>, Option<texture::Texture>> {
+        PostProcessingBuilder {
+            effects: None,
+            lut: None,
+        }
+    }
+
+    /// Writes the parameters of the effects, the passes of the effects replaced by another kind
+    /// are rebuilt
+    pub fn update(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        let (width, height) = (
+            self.frames[0].texture.width(),
+            self.frames[0].texture.height(),
+        );
+
+        self.passes.truncate(self.effects.len());
+
+        for (i, effect) in self.effects.iter().enumerate() {
+            match self.passes.get_mut(i) {
+                Some(pass)
+                    if std::mem::discriminant(&pass.effect) == std::mem::discriminant(effect) =>
+                {
+                    pass.effect = *effect;
+                    queue.write_buffer(
+                        &pass.buffer,
+                        0,
+                        bytemuck::cast_slice(&[FlatEffect::new(effect)]),
+                    );
+                }
+                _ => {
+                    let pass = Pass::new(
+                        device,
+                        *effect,
+                        &self.input_layout,
+                        &self.lut,
+                        self.format,
+                        width,
+                        height,
+                    );
+
+                    if i < self.passes.len() {
+                        self.passes[i] = pass;
+                    } else {
+                        self.passes.push(pass);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn resize(
+        &mut self,
+        device: &wgpu::Device,
+        hdr_texture: &texture::Texture,
+        width: u32,
+        height: u32,
+    ) {
+        self.hdr_input = create_input(device, &self.input_layout, hdr_texture);
+        (self.frames, self.inputs) = create_frames(
+            device,
+            &self.input_layout,
+            width,
+            height,
+            self.format,
+            "post_frame",
+        );
+
+        for pass in &mut self.passes {
+            pass.resize(device, &self.input_layout, width, height);
+        }
+    }
+
+    /// Frame the tonemapping should render into, `None` when no effect applies and it can render
+    /// to the output directly
+    pub fn input(&self) -> Option<&wgpu::TextureView> {
+        (self.enabled && !self.passes.is_empty()).then_some(&self.frames[0].view)
+    }
+
+    /// Applies the effects to the frame rendered into `input()`, the last one writes to `output`
+    pub fn draw(&self, encoder: &mut wgpu::CommandEncoder, output: &wgpu::TextureView) {
+        if self.input().is_none() {
+            return;
+        }
+
+        let last = self.passes.len() - 1;
+
+        for (i, pass) in self.passes.iter().enumerate() {
+            let target = if i == last {
+                output
+            } else {
+                &self.frames[(i + 1) % 2].view
+            };
+
+            pass.draw(encoder, &self.inputs[i % 2], &self.hdr_input, target);
+        }
+    }
+}
+
+/// Pipeline and parameters of an effect
+struct Pass {
+    effect: Effect,
+    buffer: wgpu::Buffer,
+    layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    pipeline: wgpu::RenderPipeline,
+    bloom: Option<Bloom>,
+}
+
+impl Pass {
+    fn new(
+        device: &wgpu::Device,
+        effect: Effect,
+        input_layout: &wgpu::BindGroupLayout,
+        lut: &texture::Texture,
+        format: wgpu::TextureFormat,
+        width: u32,
+        height: u32,
+    ) -> Self {
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{} Buffer", effect.name())),
+            contents: bytemuck::cast_slice(&[FlatEffect::new(&effect)]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        // the frames hold gamma encoded colours when the output does not encode them on write
+        let source = effect.source(!format.is_srgb());
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some(&format!("{} Shader", effect.name())),
+            source: wgpu::ShaderSource::Wgsl(source.into()),
+        });
+
+        let layout = create_params_layout(device, effect.texture_dimension());
+
+        let pipeline = create_pipeline(
+            device,
+            &shader,
+            "fs_main",
+            &[input_layout, &layout],
+            format,
+            effect.name(),
+        );
+
+        let bloom = matches!(effect, Effect::Bloom { .. })
+            .then(|| Bloom::new(device, &shader, input_layout, &buffer, width, height));
+
+        let texture = match &bloom {
+            Some(bloom) => Some(&bloom.textures[0]),
+            None => effect.texture_dimension().map(|_| lut),
+        };
+
+        let bind_group = create_params_bind_group(device, &layout, &buffer, texture);
+
+        Self {
+            effect,
+            buffer,
+            layout,
+            bind_group,
+            pipeline,
+            bloom,
+        }
+    }
+
+    fn resize(
+        &mut self,
+        device: &wgpu::Device,
+        input_layout: &wgpu::BindGroupLayout,
+        width: u32,
+        height: u32,
+    ) {
+        if let Some(bloom) = &mut self.bloom {
+            bloom.resize(device, input_layout, width, height);
+            self.bind_group = create_params_bind_group(
+                device,
+                &self.layout,
+                &self.buffer,
+                Some(&bloom.textures[0]),
+            );
+        }
+    }
+
+    fn draw(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        input: &wgpu::BindGroup,
+        hdr_input: &wgpu::BindGroup,
+        target: &wgpu::TextureView,
+    ) {
+        if let Some(bloom) = &self.bloom {
+            bloom.draw(encoder, hdr_input);
+        }
+
+        draw_fullscreen(
+            encoder,
+            self.effect.name(),
+            &self.pipeline,
+            [input, &self.bind_group],
+            target,
+        );
+    }
+}
+
+/// Quarter size targets the bright parts of the frame are blurred in, the result is left in the
+/// first one
+struct Bloom {
+    textures: [texture::Texture; 2],
+    inputs: [wgpu::BindGroup; 2],
+    params_bind_group: wgpu::BindGroup,
+    threshold_pipeline: wgpu::RenderPipeline,
+    horizontal_pipeline: wgpu::RenderPipeline,
+    vertical_pipeline: wgpu::RenderPipeline,
+}
+
+impl Bloom {
+    fn new(
+        device: &wgpu::Device,
+        shader: &wgpu::ShaderModule,
+        input_layout: &wgpu::BindGroupLayout,
+        buffer: &wgpu::Buffer,
+        width: u32,
+        height: u32,
+    ) -> Self {
+        // the blur passes render into the texture the composite pass reads, they only get the parameters
+        let params_layout = create_params_layout(device, None);
+        let params_bind_group = create_params_bind_group(device, &params_layout, buffer, None);
+
+        let layouts = [input_layout, &params_layout];
+        let format = texture::Texture::HDR_FORMAT;
+
+        let (textures, inputs) = create_bloom_textures(device, input_layout, width, height);
+
+        Self {
+            textures,
+            inputs,
+            params_bind_group,
+            threshold_pipeline: create_pipeline(
+                device,
+                shader,
+                "fs_threshold",
+                &layouts,
+                format,
+                "Bloom Threshold",
+            ),
+            horizontal_pipeline: create_pipeline(
+                device,
+                shader,
+                "fs_blur_horizontal",
+                &layouts,
+                format,
+                "Bloom Horizontal Blur",
+            ),
+            vertical_pipeline: create_pipeline(
+                device,
+                shader,
+                "fs_blur_vertical",
+                &layouts,
+                format,
+                "Bloom Vertical Blur",
+            ),
+        }
+    }
+
+    fn resize(
+        &mut self,
+        device: &wgpu::Device,
+        input_layout: &wgpu::BindGroupLayout,
+        width: u32,
+        height: u32,
+    ) {
+        (self.textures, self.inputs) = create_bloom_textures(device, input_layout, width, height);
+    }
+
+    fn draw(&self, encoder: &mut wgpu::CommandEncoder, hdr_input: &wgpu::BindGroup) {
+        draw_fullscreen(
+            encoder,
+            "Bloom Threshold",
+            &self.threshold_pipeline,
+            [hdr_input, &self.params_bind_group],
+            &self.textures[0].view,
+        );
+        draw_fullscreen(
+            encoder,
+            "Bloom Horizontal Blur",
+            &self.horizontal_pipeline,
+            [&self.inputs[0], &self.params_bind_group],
+            &self.textures[1].view,
+        );
+        draw_fullscreen(
+            encoder,
+            "Bloom Vertical Blur",
+            &self.vertical_pipeline,
+            [&self.inputs[1], &self.params_bind_group],
+            &self.textures[0].view,
+        );
+    }
+}
+
+fn create_bloom_textures(
+    device: &wgpu::Device,
+    input_layout: &wgpu::BindGroupLayout,
+    width: u32,
+    height: u32,
+) -> ([texture::Texture; 2], [wgpu::BindGroup; 2]) {
+    create_frames(
+        device,
+        input_layout,
+        (width / 4).max(1),
+        (height / 4).max(1),
+        texture::Texture::HDR_FORMAT,
+        "bloom_texture",
+    )
+}
+
+/// Pair of render targets with the bind groups reading them
+fn create_frames(
+    device: &wgpu::Device,
+    input_layout: &wgpu::BindGroupLayout,
+    width: u32,
+    height: u32,
+    format: wgpu::TextureFormat,
+    label: &str,
+) -> ([texture::Texture; 2], [wgpu::BindGroup; 2]) {
+    let frames = [0, 1]
+        .map(|_| texture::Texture::create_render_target(device, width, height, format, label));
+
+    let inputs = [&frames[0], &frames[1]].map(|frame| create_input(device, input_layout, frame));
+
+    (frames, inputs)
+}
+
+fn create_input(
+    device: &wgpu::Device,
+    input_layout: &wgpu::BindGroupLayout,
+    frame: &texture::Texture,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("Post-processing input bind group"),
+        layout: input_layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&frame.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Sampler(&frame.sampler),
+            },
+        ],
+    })
+}
+
+/// Parameters of an effect at binding 0, followed by a texture and its sampler if it reads one
+fn create_params_layout(
+    device: &wgpu::Device,
+    texture: Option<wgpu::TextureViewDimension>,
+) -> wgpu::BindGroupLayout {
+    let mut entries = vec![wgpu::BindGroupLayoutEntry {
+        binding: 0,
+        visibility: wgpu::ShaderStages::FRAGMENT,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Uniform,
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }];
+
+    if let Some(view_dimension) = texture {
+        entries.push(wgpu::BindGroupLayoutEntry {
+            binding: 1,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                view_dimension,
+                multisampled: false,
+            },
+            count: None,
+        });
+        entries.push(wgpu::BindGroupLayoutEntry {
+            binding: 2,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+            count: None,
+        });
+    }
+
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: Some("Post-processing params bind group layout"),
+        entries: &entries,
+    })
+}
+
+fn create_params_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    buffer: &wgpu::Buffer,
+    texture: Option<&texture::Texture>,
+) -> wgpu::BindGroup {
+    let mut entries = vec![wgpu::BindGroupEntry {
+        binding: 0,
+        resource: buffer.as_entire_binding(),
+    }];
+
+    if let Some(texture) = texture {
+        entries.push(wgpu::BindGroupEntry {
+            binding: 1,
+            resource: wgpu::BindingResource::TextureView(&texture.view),
+        });
+        entries.push(wgpu::BindGroupEntry {
+            binding: 2,
+            resource: wgpu::BindingResource::Sampler(&texture.sampler),
+        });
+    }
+
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("Post-processing params bind group"),
+        layout,
+        entries: &entries,
+    })
+}
+
+fn create_pipeline(
+    device: &wgpu::Device,
+    shader: &wgpu::ShaderModule,
+    entry_point: &str,
+    bind_group_layouts: &[&wgpu::BindGroupLayout],
+    format: wgpu::TextureFormat,
+    name: &str,
+) -> wgpu::RenderPipeline {
+    let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some(&format!("{} Pipeline Layout", name)),
+        bind_group_layouts,
+        push_constant_ranges: &[],
+    });
+
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(&format!("{} Pipeline", name)),
+        layout: Some(&pipeline_layout),
+        vertex: wgpu::VertexState {
+            module: shader,
+            entry_point: "vs_main",
+            buffers: &[],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader,
+            entry_point,
+            targets: &[Some(format.into())],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            ..Default::default()
+        },
+        depth_stencil: None,
+        multisample: wgpu::MultisampleState::default(),
+        multiview: None,
+    })
+}
+
+fn draw_fullscreen(
+    encoder: &mut wgpu::CommandEncoder,
+    name: &str,
+    pipeline: &wgpu::RenderPipeline,
+    bind_groups: [&wgpu::BindGroup; 2],
+    target: &wgpu::TextureView,
+) {
+    let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+        label: Some(&format!("{} Pass", name)),
+        color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+            view: target,
+            resolve_target: None,
+            ops: wgpu::Operations {
+                load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                store: wgpu::StoreOp::Store,
+            },
+        })],
+        depth_stencil_attachment: None,
+        timestamp_writes: None,
+        occlusion_query_set: None,
+    });
+
+    render_pass.set_pipeline(pipeline);
+    for (index, bind_group) in bind_groups.into_iter().enumerate() {
+        render_pass.set_bind_group(index as u32, bind_group, &[]);
+    }
+    render_pass.draw(0..3, 0..1);
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct FlatEffect {
+    // each shader names the parameters it uses, the rest is padding
+    params: [f32; 4],
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl FlatEffect {
+    pub(crate) fn host_layout() -> reflection::HostLayout {
+        reflection::host_layout!(FlatEffect { params })
+    }
+}
+
+impl FlatEffect {
+    fn new(effect: &Effect) -> Self {
+        let params = match *effect {
+            Effect::Fxaa {
+                edge_threshold,
+                span_max,
+            } => [edge_threshold, span_max, 0.0, 0.0],
+            Effect::Bloom {
+                threshold,
+                intensity,
+            } => [threshold, intensity, 0.0, 0.0],
+            Effect::Vignette {
+                intensity,
+                radius,
+                smoothness,
+            } => [intensity, radius, smoothness, 0.0],
+            Effect::ColorGrading { intensity } => [intensity, 0.0, 0.0, 0.0],
+            Effect::Gamma { gamma } => [gamma, 0.0, 0.0, 0.0],
+        };
+
+        Self { params }
+    }
+}
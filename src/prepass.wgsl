@@ -0,0 +1,26 @@
+// Depth only pass of the models from the camera, read by the ambient occlusion
+
+#include "camera.wgsl"
+
+@group(0) @binding(0) var<uniform> camera: Camera;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+}
+
+#include "instance.wgsl"
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+    instance: InstanceInput
+) -> @builtin(position) vec4<f32> {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+
+    return camera.view_proj * model_matrix * vec4<f32>(model.position, 1.0);
+}
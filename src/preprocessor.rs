@@ -0,0 +1,408 @@
+use std::collections::{HashMap, HashSet};
+
+/// Error of [`Preprocessor::process`], with the file and line of the directive
+#[derive(Debug, thiserror::Error)]
+pub enum PreprocessError {
+    #[error("{file}:{line}: no snippet {name:?} to include")]
+    UnknownInclude {
+        file: String,
+        line: usize,
+        name: String,
+    },
+    #[error("{file}:{line}: {name:?} includes itself")]
+    RecursiveInclude {
+        file: String,
+        line: usize,
+        name: String,
+    },
+    #[error("{file}:{line}: unknown directive #{directive}")]
+    UnknownDirective {
+        file: String,
+        line: usize,
+        directive: String,
+    },
+    #[error("{file}:{line}: #{directive} needs a name")]
+    MissingName {
+        file: String,
+        line: usize,
+        directive: String,
+    },
+    #[error("{file}:{line}: #{directive} without an #ifdef")]
+    Unmatched {
+        file: String,
+        line: usize,
+        directive: String,
+    },
+    #[error("{file}:{line}: #ifdef without an #endif")]
+    Unclosed { file: String, line: usize },
+}
+
+/// Expands the directives of WGSL sources, each on a line of its own:
+///
+/// - `#include "name"` pastes the snippet registered as `name`
+/// - `#define NAME` turns a feature on for the rest of the source
+/// - `#ifdef NAME`, `#ifndef NAME`, `#else` and `#endif` keep lines depending on the features
+///
+/// Directives and the lines left out become empty lines, the included snippets shift the lines
+/// after them, [`Expanded::origin`] maps them back
+#[derive(Debug, Clone, Default)]
+pub struct Preprocessor {
+    includes: HashMap<String, String>,
+    defines: HashSet<String>,
+}
+
+/// Source expanded by [`Preprocessor::process`]
+#[derive(Debug, Clone)]
+pub struct Expanded {
+    pub source: String,
+    /// File and line each line of `source` comes from
+    origins: Vec<(String, usize)>,
+}
+
+impl Expanded {
+    /// File and line the line `line` of `source` comes from, counting from 1
+    pub fn origin(&self, line: usize) -> Option<(&str, usize)> {
+        let (file, line) = self.origins.get(line.checked_sub(1)?)?;
+        Some((file, *line))
+    }
+
+    fn push_line(&mut self, file: &str, line: usize) {
+        self.source.push('\n');
+        self.origins.push((file.to_string(), line));
+    }
+}
+
+/// `#ifdef` or `#ifndef` block being expanded
+struct Block {
+    line: usize,
+    /// Whether the lines around the block are kept
+    enclosing: bool,
+    condition: bool,
+    in_else: bool,
+}
+
+impl Block {
+    fn active(&self) -> bool {
+        self.enclosing && self.condition != self.in_else
+    }
+}
+
+impl Preprocessor {
+    /// Makes `source` available to `#include "name"`
+    pub fn include(mut self, name: &str, source: &str) -> Self {
+        self.includes.insert(name.to_string(), source.to_string());
+        self
+    }
+
+    /// Turns the feature `name` on, as if the source started with `#define name`
+    pub fn define(mut self, name: &str) -> Self {
+        self.defines.insert(name.to_string());
+        self
+    }
+
+    /// Expands `source`, `file` names it in errors and in the origins of the lines
+    pub fn process(&self, source: &str, file: &str) -> Result<Expanded, PreprocessError> {
+        let mut output = Expanded {
+            source: String::with_capacity(source.len()),
+            origins: Vec::new(),
+        };
+        let mut defines = self.defines.clone();
+        let mut including = vec![file.to_string()];
+
+        self.expand(source, file, &mut defines, &mut including, &mut output)?;
+
+        Ok(output)
+    }
+
+    fn expand(
+        &self,
+        source: &str,
+        file: &str,
+        defines: &mut HashSet<String>,
+        including: &mut Vec<String>,
+        output: &mut Expanded,
+    ) -> Result<(), PreprocessError> {
+        let mut blocks: Vec<Block> = Vec::new();
+
+        for (i, text) in source.lines().enumerate() {
+            let line = i + 1;
+            let active = blocks.last().is_none_or(Block::active);
+
+            let Some(directive) = text.trim_start().strip_prefix('#') else {
+                if active {
+                    output.source.push_str(text);
+                }
+                output.push_line(file, line);
+                continue;
+            };
+
+            let mut words = directive.split_whitespace();
+            let directive = words.next().unwrap_or_default();
+            let name = words.next().map(|name| name.trim_matches('"'));
+
+            let missing_name = || PreprocessError::MissingName {
+                file: file.to_string(),
+                line,
+                directive: directive.to_string(),
+            };
+            let unmatched = || PreprocessError::Unmatched {
+                file: file.to_string(),
+                line,
+                directive: directive.to_string(),
+            };
+
+            match directive {
+                "ifdef" | "ifndef" => {
+                    let name = name.ok_or_else(missing_name)?;
+                    blocks.push(Block {
+                        line,
+                        enclosing: active,
+                        condition: defines.contains(name) == (directive == "ifdef"),
+                        in_else: false,
+                    });
+                }
+                "else" => {
+                    let block = blocks
+                        .last_mut()
+                        .filter(|block| !block.in_else)
+                        .ok_or_else(unmatched)?;
+                    block.in_else = true;
+                }
+                "endif" => {
+                    blocks.pop().ok_or_else(unmatched)?;
+                }
+                "define" => {
+                    let name = name.ok_or_else(missing_name)?;
+                    if active {
+                        defines.insert(name.to_string());
+                    }
+                }
+                "include" => {
+                    let name = name.ok_or_else(missing_name)?;
+                    if active {
+                        if including.iter().any(|file| file == name) {
+                            return Err(PreprocessError::RecursiveInclude {
+                                file: file.to_string(),
+                                line,
+                                name: name.to_string(),
+                            });
+                        }
+
+                        let snippet = self.includes.get(name).ok_or_else(|| {
+                            PreprocessError::UnknownInclude {
+                                file: file.to_string(),
+                                line,
+                                name: name.to_string(),
+                            }
+                        })?;
+
+                        including.push(name.to_string());
+                        self.expand(snippet, name, defines, including, output)?;
+                        including.pop();
+                        continue;
+                    }
+                }
+                _ => {
+                    return Err(PreprocessError::UnknownDirective {
+                        file: file.to_string(),
+                        line,
+                        directive: directive.to_string(),
+                    })
+                }
+            }
+
+            output.push_line(file, line);
+        }
+
+        match blocks.last() {
+            Some(block) => Err(PreprocessError::Unclosed {
+                file: file.to_string(),
+                line: block.line,
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(preprocessor: &Preprocessor, source: &str) -> Result<String, PreprocessError> {
+        preprocessor
+            .process(source, "main.wgsl")
+            .map(|expanded| expanded.source)
+    }
+
+    /// Lines kept by the preprocessor, without the empty ones
+    fn kept(preprocessor: &Preprocessor, source: &str) -> Vec<String> {
+        process(preprocessor, source)
+            .unwrap()
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn keeps_line_numbers_outside_of_the_includes() {
+        let source = "a\n#ifdef A\nb\n#endif\nc\n";
+        assert_eq!(
+            process(&Preprocessor::default(), source).unwrap(),
+            "a\n\n\n\nc\n"
+        );
+    }
+
+    #[test]
+    fn nests_blocks() {
+        let source = "\
+#ifdef A
+a
+#ifndef B
+not b
+#else
+b
+#endif
+#else
+not a
+#ifdef B
+only b
+#endif
+#endif
+";
+        let preprocessor = Preprocessor::default().define("A");
+        assert_eq!(kept(&preprocessor, source), ["a", "not b"]);
+        assert_eq!(kept(&preprocessor.define("B"), source), ["a", "b"]);
+        assert_eq!(
+            kept(&Preprocessor::default().define("B"), source),
+            ["not a", "only b"]
+        );
+        assert_eq!(kept(&Preprocessor::default(), source), ["not a"]);
+    }
+
+    #[test]
+    fn ignores_defines_in_inactive_blocks() {
+        let source = "\
+#ifdef A
+#define B
+#endif
+#ifdef B
+b
+#endif
+#define C
+#ifdef C
+c
+#endif
+";
+        assert_eq!(kept(&Preprocessor::default(), source), ["c"]);
+        assert_eq!(
+            kept(&Preprocessor::default().define("A"), source),
+            ["b", "c"]
+        );
+    }
+
+    #[test]
+    fn includes_snippets_with_their_defines() {
+        let preprocessor = Preprocessor::default()
+            .include("outer.wgsl", "outer\n#include \"inner.wgsl\"\n")
+            .include("inner.wgsl", "#define INNER\ninner\n");
+        let source = "#include \"outer.wgsl\"\n#ifdef INNER\nafter\n#endif\n";
+
+        let expanded = preprocessor.process(source, "main.wgsl").unwrap();
+        let kept = expanded
+            .source
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.is_empty())
+            .map(|(i, line)| (line, expanded.origin(i + 1).unwrap()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            kept,
+            [
+                ("outer", ("outer.wgsl", 1)),
+                ("inner", ("inner.wgsl", 2)),
+                ("after", ("main.wgsl", 3)),
+            ]
+        );
+        assert_eq!(expanded.origin(0), None);
+    }
+
+    #[test]
+    fn skips_includes_in_inactive_blocks() {
+        let source = "#ifdef A\n#include \"missing.wgsl\"\n#endif\n";
+        assert!(process(&Preprocessor::default(), source).is_ok());
+    }
+
+    #[test]
+    fn rejects_unknown_includes() {
+        let error = process(&Preprocessor::default(), "\n#include \"missing.wgsl\"\n");
+        assert!(matches!(
+            error,
+            Err(PreprocessError::UnknownInclude { file, line: 2, name })
+                if file == "main.wgsl" && name == "missing.wgsl"
+        ));
+    }
+
+    #[test]
+    fn rejects_recursive_includes() {
+        let preprocessor = Preprocessor::default()
+            .include("a.wgsl", "#include \"b.wgsl\"\n")
+            .include("b.wgsl", "\n#include \"a.wgsl\"\n");
+        let error = process(&preprocessor, "#include \"a.wgsl\"\n");
+        assert!(matches!(
+            error,
+            Err(PreprocessError::RecursiveInclude { file, line: 2, name })
+                if file == "b.wgsl" && name == "a.wgsl"
+        ));
+
+        let error = process(&Preprocessor::default(), "#include \"main.wgsl\"\n");
+        assert!(matches!(
+            error,
+            Err(PreprocessError::RecursiveInclude { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_unmatched_directives() {
+        for (source, directive, line) in [
+            ("#else\n", "else", 1),
+            ("#endif\n", "endif", 1),
+            ("#ifdef A\n#else\n#else\n#endif\n", "else", 3),
+            ("#ifdef A\n#endif\n#endif\n", "endif", 3),
+        ] {
+            let error = process(&Preprocessor::default(), source);
+            assert!(
+                matches!(
+                    &error,
+                    Err(PreprocessError::Unmatched { directive: d, line: l, .. })
+                        if d == directive && *l == line
+                ),
+                "{:?} gave {:?}",
+                source,
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_unclosed_blocks() {
+        let error = process(&Preprocessor::default(), "#ifdef A\n#ifndef B\n#endif\n");
+        assert!(matches!(
+            error,
+            Err(PreprocessError::Unclosed { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_directives() {
+        assert!(matches!(
+            process(&Preprocessor::default(), "#ifdef\n#endif\n"),
+            Err(PreprocessError::MissingName { line: 1, .. })
+        ));
+        assert!(matches!(
+            process(&Preprocessor::default(), "#pragma once\n"),
+            Err(PreprocessError::UnknownDirective { line: 1, .. })
+        ));
+    }
+}
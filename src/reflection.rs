@@ -0,0 +1,646 @@
+use std::collections::BTreeMap;
+
+use naga::proc::Layouter;
+
+use crate::postprocessing::Effect;
+use crate::preprocessor::Expanded;
+use crate::shaders::{self, Shaders};
+use crate::{
+    environment, light, model, pipelines, postprocessing, shadows, ssao, tonemapping, transforms,
+    view,
+};
+
+/// Layout of a `#[repr(C)]` struct the CPU writes into a buffer, see [`host_layout`]
+#[derive(Debug)]
+pub struct HostLayout {
+    pub name: &'static str,
+    pub size: u32,
+    /// Fields in memory order, without the padding
+    pub fields: Vec<HostField>,
+}
+
+#[derive(Debug)]
+pub struct HostField {
+    pub name: &'static str,
+    pub offset: u32,
+    pub size: u32,
+}
+
+/// [`HostLayout`] of `Struct { field, ... }`, listing the fields that are not padding
+macro_rules! host_layout {
+    ($ty:ident { $($field:ident),* $(,)? }) => {
+        $crate::reflection::HostLayout {
+            name: stringify!($ty),
+            size: std::mem::size_of::<$ty>() as u32,
+            fields: vec![$($crate::reflection::HostField {
+                name: stringify!($field),
+                offset: std::mem::offset_of!($ty, $field) as u32,
+                size: $crate::reflection::field_size(|value: &$ty| &value.$field),
+            }),*],
+        }
+    };
+}
+pub(crate) use host_layout;
+
+pub(crate) fn field_size<T, F>(_field: fn(&T) -> &F) -> u32 {
+    std::mem::size_of::<F>() as u32
+}
+
+/// Checks the uniforms and vertex inputs of the model and light shaders of `shaders` against
+/// the Rust structs filling their buffers, the error lists every mismatch
+pub fn check_shaders(shaders: &Shaders) -> anyhow::Result<()> {
+    // the layouts do not depend on the features or on where the lights are stored
+    let model_source = shaders::Features::default()
+        .define(shaders.preprocessor())
+        .process(
+            &(shaders.model.clone() + &light::declaration(2, false)),
+            shaders::MODEL_FILE,
+        )?;
+    let light_source = shaders.preprocessor().process(
+        &(shaders.light.clone() + &light::declaration(1, false)),
+        shaders::LIGHT_FILE,
+    )?;
+
+    let mut mismatches = Vec::new();
+
+    let mut check = Check::new(&model_source, shaders::MODEL_FILE)?;
+    check.uniform("Camera", view::FlatView::host_layout());
+    check.uniform("Lights", light::FlatLights::host_layout());
+    check.uniform("Light", light::FlatLight::host_layout());
+    check.uniform("Material", model::FlatMaterial::host_layout());
+    check.uniform("Shadow", shadows::FlatShadow::host_layout());
+    check.vertex_inputs("vs_main", &model_buffers());
+    mismatches.append(&mut check.mismatches);
+
+    let mut check = Check::new(&light_source, shaders::LIGHT_FILE)?;
+    check.uniform("Camera", view::FlatView::host_layout());
+    check.uniform("Lights", light::FlatLights::host_layout());
+    check.uniform("Light", light::FlatLight::host_layout());
+    check.vertex_inputs("vs_main", &[model_vertex_buffer()]);
+    mismatches.append(&mut check.mismatches);
+
+    if !mismatches.is_empty() {
+        anyhow::bail!("{}", mismatches.join("\n"));
+    }
+
+    Ok(())
+}
+
+/// [`check_shaders`] for the shaders baked into the binary that can not be reloaded
+pub fn check_baked_shaders() -> anyhow::Result<()> {
+    let mut mismatches = Vec::new();
+
+    let mut check = Check::baked("prepass.wgsl", include_str!("prepass.wgsl"))?;
+    check.vertex_inputs("vs_main", &model_buffers());
+    mismatches.append(&mut check.mismatches);
+
+    let mut check = Check::baked("shadow.wgsl", include_str!("shadow.wgsl"))?;
+    check.uniform("ShadowFace", shadows::FlatShadowFace::host_layout());
+    check.vertex_inputs("vs_main", &model_buffers());
+    mismatches.append(&mut check.mismatches);
+
+    let mut check = Check::baked("skybox.wgsl", include_str!("skybox.wgsl"))?;
+    check.uniform("Camera", view::FlatView::host_layout());
+    mismatches.append(&mut check.mismatches);
+
+    let mut check = Check::baked("ssao.wgsl", include_str!("ssao.wgsl"))?;
+    check.uniform("Ssao", ssao::FlatSsao::host_layout());
+    mismatches.append(&mut check.mismatches);
+
+    let mut check = Check::baked("tonemapping.wgsl", include_str!("tonemapping.wgsl"))?;
+    check.uniform("Tonemapping", tonemapping::FlatTonemapping::host_layout());
+    mismatches.append(&mut check.mismatches);
+
+    let mut check = Check::baked("environment.wgsl", include_str!("environment.wgsl"))?;
+    check.uniform("Params", environment::FlatParams::host_layout());
+    mismatches.append(&mut check.mismatches);
+
+    // the parameters of every effect are read from the start of the same buffer
+    for (file, name, effect) in [
+        (
+            "fxaa.wgsl",
+            "Fxaa",
+            Effect::Fxaa {
+                edge_threshold: 0.0,
+                span_max: 0.0,
+            },
+        ),
+        (
+            "bloom.wgsl",
+            "Bloom",
+            Effect::Bloom {
+                threshold: 0.0,
+                intensity: 0.0,
+            },
+        ),
+        (
+            "vignette.wgsl",
+            "Vignette",
+            Effect::Vignette {
+                intensity: 0.0,
+                radius: 0.0,
+                smoothness: 0.0,
+            },
+        ),
+        (
+            "color_grading.wgsl",
+            "ColorGrading",
+            Effect::ColorGrading { intensity: 0.0 },
+        ),
+        ("gamma.wgsl", "Gamma", Effect::Gamma { gamma: 0.0 }),
+    ] {
+        let mut check = Check::baked(file, &effect.source(false))?;
+        check.parameters(name, postprocessing::FlatEffect::host_layout());
+        mismatches.append(&mut check.mismatches);
+    }
+
+    if !mismatches.is_empty() {
+        anyhow::bail!("{}", mismatches.join("\n"));
+    }
+
+    Ok(())
+}
+
+/// Vertex buffers of the model pipelines, see [`pipelines::model_vertex_layout`]
+fn model_buffers() -> [(wgpu::VertexBufferLayout<'static>, HostLayout); 2] {
+    [
+        model_vertex_buffer(),
+        (
+            pipelines::instances_layout(),
+            transforms::FlatTransform::host_layout(),
+        ),
+    ]
+}
+
+fn model_vertex_buffer() -> (wgpu::VertexBufferLayout<'static>, HostLayout) {
+    (
+        pipelines::model_vertex_layout(),
+        model::ModelVertex::host_layout(),
+    )
+}
+
+/// Mismatches found in a shader module
+struct Check {
+    file: String,
+    module: naga::Module,
+    layouter: Layouter,
+    mismatches: Vec<String>,
+}
+
+impl Check {
+    fn new(source: &Expanded, file: &str) -> anyhow::Result<Self> {
+        let module = shaders::validate(source, file)?;
+
+        let mut layouter = Layouter::default();
+        layouter.update(module.to_ctx())?;
+
+        Ok(Self {
+            file: file.to_string(),
+            module,
+            layouter,
+            mismatches: Vec::new(),
+        })
+    }
+
+    /// Check of a shader baked into the binary
+    fn baked(file: &str, source: &str) -> anyhow::Result<Self> {
+        Self::new(&shaders::preprocess(source, file)?, file)
+    }
+
+    fn mismatch(&mut self, message: String) {
+        self.mismatches.push(format!("{}: {}", self.file, message));
+    }
+
+    /// Compares the members of the WGSL struct `name` with the fields of `host` in order. A
+    /// member left after them must be the array the CPU writes right after the struct, like
+    /// the lights after their count
+    fn uniform(&mut self, name: &str, host: HostLayout) {
+        let Some((handle, ty)) = self
+            .module
+            .types
+            .iter()
+            .find(|(_, ty)| ty.name.as_deref() == Some(name))
+        else {
+            return self.mismatch(format!("no struct {}", name));
+        };
+        let naga::TypeInner::Struct { members, span } = &ty.inner else {
+            return self.mismatch(format!("{} is not a struct", name));
+        };
+        let (members, span, alignment) = (members.clone(), *span, self.layouter[handle].alignment);
+
+        if members.len() < host.fields.len() {
+            self.mismatch(format!(
+                "struct {} has {} members, {} has {} fields",
+                name,
+                members.len(),
+                host.name,
+                host.fields.len()
+            ));
+        }
+
+        for (member, field) in members.iter().zip(&host.fields) {
+            let member_name = member.name.as_deref().unwrap_or_default();
+            let member_size = self.layouter[member.ty].size;
+
+            if member.offset != field.offset {
+                self.mismatch(format!(
+                    "{}.{} is at offset {}, {}::{} at {}",
+                    name, member_name, member.offset, host.name, field.name, field.offset
+                ));
+            }
+            if member_size != field.size {
+                self.mismatch(format!(
+                    "{}.{} takes {} bytes, {}::{} {}",
+                    name, member_name, member_size, host.name, field.name, field.size
+                ));
+            }
+        }
+
+        match &members[host.fields.len().min(members.len())..] {
+            // the CPU may pad a uniform to the next 16 bytes, the least WebGL binds
+            [] => {
+                if span != host.size && span.next_multiple_of(16) != host.size {
+                    self.mismatch(format!(
+                        "struct {} takes {} bytes, {} {}",
+                        name, span, host.name, host.size
+                    ));
+                }
+            }
+            [array]
+                if matches!(
+                    self.module.types[array.ty].inner,
+                    naga::TypeInner::Array { .. }
+                ) =>
+            {
+                if array.offset != host.size {
+                    self.mismatch(format!(
+                        "{}.{} is at offset {}, after the {} bytes of {}",
+                        name,
+                        array.name.as_deref().unwrap_or_default(),
+                        array.offset,
+                        host.size,
+                        host.name
+                    ));
+                }
+            }
+            rest => {
+                let names = rest
+                    .iter()
+                    .map(|member| member.name.as_deref().unwrap_or_default())
+                    .collect::<Vec<_>>();
+                self.mismatch(format!(
+                    "{}.{} is not written by {}",
+                    name,
+                    names.join(", "),
+                    host.name
+                ));
+            }
+        }
+
+        // arrays of the struct step by its size on the CPU
+        if !alignment.is_aligned(host.size) {
+            self.mismatch(format!(
+                "struct {} is aligned to {} bytes, {} takes {}",
+                name, alignment, host.name, host.size
+            ));
+        }
+    }
+
+    /// The WGSL struct `name` must read 32-bit floats from the start of `host`, like the
+    /// parameters of the effects
+    fn parameters(&mut self, name: &str, host: HostLayout) {
+        let Some(ty) = self
+            .module
+            .types
+            .iter()
+            .find_map(|(_, ty)| (ty.name.as_deref() == Some(name)).then_some(ty))
+        else {
+            return self.mismatch(format!("no struct {}", name));
+        };
+        let naga::TypeInner::Struct { members, span } = &ty.inner else {
+            return self.mismatch(format!("{} is not a struct", name));
+        };
+        let (members, span) = (members.clone(), *span);
+
+        for (i, member) in members.iter().enumerate() {
+            let member_name = member.name.as_deref().unwrap_or_default();
+            let is_float = matches!(
+                self.module.types[member.ty].inner,
+                naga::TypeInner::Scalar {
+                    kind: naga::ScalarKind::Float,
+                    width: 4,
+                }
+            );
+
+            if !is_float {
+                self.mismatch(format!("{}.{} is not an f32", name, member_name));
+            }
+            if member.offset != i as u32 * 4 {
+                self.mismatch(format!(
+                    "{}.{} is at offset {}, the parameters of {} are packed",
+                    name, member_name, member.offset, host.name
+                ));
+            }
+        }
+
+        if span > host.size {
+            self.mismatch(format!(
+                "struct {} takes {} bytes, {} {}",
+                name, span, host.name, host.size
+            ));
+        }
+    }
+
+    /// Compares the located inputs of the vertex `entry_point` with the attributes of the
+    /// `buffers`, and the attributes with the fields of the structs filling the buffers
+    fn vertex_inputs(
+        &mut self,
+        entry_point: &str,
+        buffers: &[(wgpu::VertexBufferLayout<'static>, HostLayout)],
+    ) {
+        let Some(inputs) = self.inputs(entry_point) else {
+            return self.mismatch(format!("no vertex entry point {}", entry_point));
+        };
+
+        for (location, (name, format)) in &inputs {
+            let attribute = buffers
+                .iter()
+                .flat_map(|(layout, _)| layout.attributes)
+                .find(|attribute| attribute.shader_location == *location);
+
+            match (attribute, format) {
+                (None, _) => self.mismatch(format!(
+                    "input {} at location {} is not in the vertex buffers",
+                    name, location
+                )),
+                (Some(attribute), Some(format)) if attribute.format != *format => {
+                    self.mismatch(format!(
+                        "input {} at location {} is {:?}, the vertex buffer has {:?}",
+                        name, location, format, attribute.format
+                    ))
+                }
+                (Some(_), None) => self.mismatch(format!(
+                    "input {} at location {} is not a 32-bit scalar or vector",
+                    name, location
+                )),
+                _ => {}
+            }
+        }
+
+        for (layout, host) in buffers {
+            self.buffer(layout, host);
+        }
+    }
+
+    /// Located inputs of the vertex `entry_point`, with their name and format
+    fn inputs(
+        &self,
+        entry_point: &str,
+    ) -> Option<BTreeMap<u32, (String, Option<wgpu::VertexFormat>)>> {
+        let function = &self
+            .module
+            .entry_points
+            .iter()
+            .find(|ep| ep.name == entry_point && ep.stage == naga::ShaderStage::Vertex)?
+            .function;
+
+        let mut inputs = BTreeMap::new();
+        let mut add = |name: &Option<String>, ty, binding: &Option<naga::Binding>| {
+            if let Some(naga::Binding::Location { location, .. }) = binding {
+                let name = name.clone().unwrap_or_default();
+                inputs.insert(*location, (name, self.vertex_format(ty)));
+            }
+        };
+
+        for argument in &function.arguments {
+            match &self.module.types[argument.ty].inner {
+                naga::TypeInner::Struct { members, .. } => {
+                    for member in members {
+                        add(&member.name, member.ty, &member.binding);
+                    }
+                }
+                _ => add(&argument.name, argument.ty, &argument.binding),
+            }
+        }
+
+        Some(inputs)
+    }
+
+    fn vertex_format(&self, ty: naga::Handle<naga::Type>) -> Option<wgpu::VertexFormat> {
+        use naga::{ScalarKind, VectorSize};
+        use wgpu::VertexFormat;
+
+        let (kind, size) = match self.module.types[ty].inner {
+            naga::TypeInner::Scalar { kind, width: 4 } => (kind, None),
+            naga::TypeInner::Vector {
+                size,
+                kind,
+                width: 4,
+            } => (kind, Some(size)),
+            _ => return None,
+        };
+
+        Some(match (kind, size) {
+            (ScalarKind::Float, None) => VertexFormat::Float32,
+            (ScalarKind::Float, Some(VectorSize::Bi)) => VertexFormat::Float32x2,
+            (ScalarKind::Float, Some(VectorSize::Tri)) => VertexFormat::Float32x3,
+            (ScalarKind::Float, Some(VectorSize::Quad)) => VertexFormat::Float32x4,
+            (ScalarKind::Uint, None) => VertexFormat::Uint32,
+            (ScalarKind::Uint, Some(VectorSize::Bi)) => VertexFormat::Uint32x2,
+            (ScalarKind::Uint, Some(VectorSize::Tri)) => VertexFormat::Uint32x3,
+            (ScalarKind::Uint, Some(VectorSize::Quad)) => VertexFormat::Uint32x4,
+            (ScalarKind::Sint, None) => VertexFormat::Sint32,
+            (ScalarKind::Sint, Some(VectorSize::Bi)) => VertexFormat::Sint32x2,
+            (ScalarKind::Sint, Some(VectorSize::Tri)) => VertexFormat::Sint32x3,
+            (ScalarKind::Sint, Some(VectorSize::Quad)) => VertexFormat::Sint32x4,
+            _ => return None,
+        })
+    }
+
+    /// The attributes of `layout` must tile the fields of `host` without overlapping, a field
+    /// like a matrix can span several attributes
+    fn buffer(&mut self, layout: &wgpu::VertexBufferLayout, host: &HostLayout) {
+        if layout.array_stride != host.size as wgpu::BufferAddress {
+            self.mismatch(format!(
+                "vertex buffer of {} steps by {} bytes, {} takes {}",
+                host.name, layout.array_stride, host.name, host.size
+            ));
+        }
+
+        for field in &host.fields {
+            let start = field.offset as wgpu::BufferAddress;
+            let end = start + field.size as wgpu::BufferAddress;
+
+            let mut attributes = layout
+                .attributes
+                .iter()
+                .filter(|attribute| {
+                    attribute.offset < end && attribute.offset + attribute.format.size() > start
+                })
+                .collect::<Vec<_>>();
+            attributes.sort_by_key(|attribute| attribute.offset);
+
+            let mut covered = start;
+            for attribute in attributes {
+                let attribute_end = attribute.offset + attribute.format.size();
+                if attribute.offset != covered || attribute_end > end {
+                    self.mismatch(format!(
+                        "attribute at location {} covers bytes {}..{}, {}::{} expects it at {} within {}..{}",
+                        attribute.shader_location,
+                        attribute.offset,
+                        attribute_end,
+                        host.name,
+                        field.name,
+                        covered,
+                        start,
+                        end
+                    ));
+                }
+                covered = attribute_end;
+            }
+
+            if covered < end {
+                self.mismatch(format!(
+                    "bytes {}..{} of {}::{} are not in any attribute",
+                    covered, end, host.name, field.name
+                ));
+            }
+        }
+
+        for attribute in layout.attributes {
+            let in_field = host.fields.iter().any(|field| {
+                let start = field.offset as wgpu::BufferAddress;
+                attribute.offset >= start
+                    && attribute.offset < start + field.size as wgpu::BufferAddress
+            });
+            if !in_field {
+                self.mismatch(format!(
+                    "attribute at location {} at offset {} is outside the fields of {}",
+                    attribute.shader_location, attribute.offset, host.name
+                ));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preprocessor::Preprocessor;
+
+    #[repr(C)]
+    struct Params {
+        scale: f32,
+        // the vec4 after it is aligned to 16 bytes
+        _padding: [f32; 3],
+        offset: [f32; 4],
+    }
+
+    #[repr(C)]
+    struct Packed {
+        scale: f32,
+        offset: [f32; 4],
+    }
+
+    #[repr(C)]
+    struct Element {
+        color: [f32; 3],
+    }
+
+    #[repr(C)]
+    struct Vertex {
+        position: [f32; 3],
+    }
+
+    const PARAMS: &str = "struct Params { scale: f32, offset: vec4<f32> }
+        @group(0) @binding(0) var<uniform> params: Params;";
+
+    const ATTRIBUTES: [wgpu::VertexAttribute; 1] = wgpu::vertex_attr_array![0 => Float32x3];
+
+    fn check_source(source: &str) -> Check {
+        let expanded = Preprocessor::default()
+            .process(source, "test.wgsl")
+            .unwrap();
+        Check::new(&expanded, "test.wgsl").unwrap()
+    }
+
+    fn check_vertex_input(input: &str, array_stride: wgpu::BufferAddress) -> Vec<String> {
+        let mut check = check_source(&format!(
+            "struct VertexInput {{ {} }}
+            @vertex
+            fn vs_main(in: VertexInput) -> @builtin(position) vec4<f32> {{
+                return vec4<f32>(0.0);
+            }}",
+            input
+        ));
+
+        let layout = wgpu::VertexBufferLayout {
+            array_stride,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &ATTRIBUTES,
+        };
+        check.vertex_inputs("vs_main", &[(layout, host_layout!(Vertex { position }))]);
+        check.mismatches
+    }
+
+    #[test]
+    fn accepts_matching_layouts() {
+        let mut check = check_source(PARAMS);
+        check.uniform("Params", host_layout!(Params { scale, offset }));
+        assert!(check.mismatches.is_empty(), "{:?}", check.mismatches);
+
+        let mismatches = check_vertex_input("@location(0) position: vec3<f32>", 12);
+        assert!(mismatches.is_empty(), "{:?}", mismatches);
+    }
+
+    #[test]
+    fn reports_a_shifted_member() {
+        let mut check = check_source(PARAMS);
+        check.uniform("Params", host_layout!(Packed { scale, offset }));
+        assert_eq!(
+            check.mismatches,
+            [
+                "test.wgsl: Params.offset is at offset 16, Packed::offset at 4",
+                "test.wgsl: struct Params takes 32 bytes, Packed 20",
+                "test.wgsl: struct Params is aligned to 16 bytes, Packed takes 20",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_a_wrong_array_stride() {
+        let mut check = check_source(
+            "struct Element { color: vec3<f32> }
+            @group(0) @binding(0) var<uniform> elements: array<Element, 4>;",
+        );
+        check.uniform("Element", host_layout!(Element { color }));
+        assert_eq!(
+            check.mismatches,
+            [
+                "test.wgsl: struct Element takes 16 bytes, Element 12",
+                "test.wgsl: struct Element is aligned to 16 bytes, Element takes 12",
+            ]
+        );
+
+        assert_eq!(
+            check_vertex_input("@location(0) position: vec3<f32>", 16),
+            ["test.wgsl: vertex buffer of Vertex steps by 16 bytes, Vertex takes 12"]
+        );
+    }
+
+    #[test]
+    fn reports_a_vertex_input_with_the_wrong_format() {
+        assert_eq!(
+            check_vertex_input("@location(0) position: vec2<f32>", 12),
+            ["test.wgsl: input position at location 0 is Float32x2, the vertex buffer has Float32x3"]
+        );
+    }
+
+    #[test]
+    fn reports_a_vertex_input_at_the_wrong_location() {
+        assert_eq!(
+            check_vertex_input("@location(1) position: vec3<f32>", 12),
+            ["test.wgsl: input position at location 1 is not in the vertex buffers"]
+        );
+    }
+}
@@ -1,6 +1,9 @@
-use crate::{model, texture};
+use crate::{attributes, compressed, environment, model, scene, texture};
+use std::cell::RefCell;
 use std::io::{BufReader, Cursor};
+use std::rc::Rc;
 
+use base64::Engine;
 use wgpu::util::DeviceExt;
 
 use cfg_if::cfg_if;
@@ -17,19 +20,36 @@ fn format_url(file_name: &str) -> reqwest::Url {
     base.join(file_name).unwrap()
 }
 
+#[cfg(not(target_arch = "wasm32"))]
+static ASSET_DIR: std::sync::RwLock<Option<std::path::PathBuf>> = std::sync::RwLock::new(None);
+
+/// Reads the assets from `dir` rather than the copy of `resources/` made at build time
+#[cfg(not(target_arch = "wasm32"))]
+pub fn set_asset_dir<P: Into<std::path::PathBuf>>(dir: P) {
+    *ASSET_DIR.write().unwrap() = Some(dir.into());
+}
+
+/// Directory the assets are read from, see [`set_asset_dir`]
+#[cfg(not(target_arch = "wasm32"))]
+pub fn asset_dir() -> std::path::PathBuf {
+    ASSET_DIR
+        .read()
+        .unwrap()
+        .clone()
+        .unwrap_or_else(|| std::path::Path::new(env!("OUT_DIR")).join("resources"))
+}
+
 async fn load_string(file_name: &str) -> anyhow::Result<String> {
     cfg_if! {
         if #[cfg(target_arch = "wasm32")] {
             let url = format_url(file_name);
             let txt = reqwest::get(url)
                 .await?
+                .error_for_status()?
                 .text()
                 .await?;
         } else {
-            let path = std::path::Path::new(env!("OUT_DIR"))
-                .join("resources")
-                .join(file_name);
-            let txt = std::fs::read_to_string(path)?;
+            let txt = std::fs::read_to_string(asset_dir().join(file_name))?;
         }
     }
 
@@ -42,36 +62,343 @@ async fn load_binary(file_name: &str) -> anyhow::Result<Vec<u8>> {
             let url = format_url(file_name);
             let data = reqwest::get(url)
                 .await?
+                .error_for_status()?
                 .bytes()
                 .await?
                 .to_vec();
         } else {
-            let path = std::path::Path::new(env!("OUT_DIR"))
-                .join("resources")
-                .join(file_name);
-            let data = std::fs::read(path)?;
+            let data = std::fs::read(asset_dir().join(file_name))?;
         }
     }
 
     Ok(data)
 }
 
+/// Loads a texture, preferring the compressed variants of a container the device can sample
 async fn load_texture(
     file_name: &str,
     device: &wgpu::Device,
     queue: &wgpu::Queue,
     format: wgpu::TextureFormat,
 ) -> anyhow::Result<texture::Texture> {
-    let data = load_binary(file_name).await?;
+    let variants = compressed::variants(file_name, device.features());
+    let (last, preferred) = variants.split_last().unwrap();
+
+    for variant in preferred {
+        let Ok(data) = load_binary(variant).await else {
+            continue;
+        };
+
+        // a broken variant should not hide the ones after it
+        match texture::Texture::from_bytes(device, queue, &data, variant, format) {
+            Ok(texture) => return Ok(texture),
+            Err(e) => log::warn!("Could not load {}, trying the next variant: {}", variant, e),
+        }
+    }
+
+    let data = load_binary(last).await?;
     texture::Texture::from_bytes(device, queue, &data, file_name, format)
 }
 
+/// Loads the image an environment is baked from, an equirectangular panorama given one file
+/// or a cubemap given six, in +x, -x, +y, -y, +z, -z order
+pub async fn load_environment(
+    file_names: &[&str],
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+) -> anyhow::Result<environment::Source> {
+    // HDR and EXR images load as floats whatever the format asked for
+    let format = wgpu::TextureFormat::Rgba8UnormSrgb;
+
+    match file_names {
+        [panorama] => Ok(environment::Source::Equirectangular(
+            load_texture(panorama, device, queue, format).await?,
+        )),
+        [_, _, _, _, _, _] => {
+            let mut faces = Vec::new();
+            for face in file_names {
+                faces.push(load_texture(face, device, queue, format).await?);
+            }
+            Ok(environment::Source::Faces(faces))
+        }
+        _ => anyhow::bail!(
+            "an environment is one panorama or six cube faces, got {} files",
+            file_names.len()
+        ),
+    }
+}
+
+/// Loads a colour grading lookup table, a strip of squares like the ones exported by most
+/// grading tools
+pub async fn load_lut(
+    file_name: &str,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+) -> anyhow::Result<texture::Texture> {
+    let data = load_binary(file_name).await?;
+    let image = image::load_from_memory(&data)?;
+
+    texture::Texture::from_lut(device, queue, &image, Some(file_name))
+}
+
+/// Faces meeting at a sharper angle keep a hard edge when normals are generated
+const NORMAL_SMOOTHING_ANGLE: cgmath::Deg<f32> = cgmath::Deg(60.0);
+
+/// Loads a scene description, models and textures it names are loaded separately
+pub async fn load_scene(file_name: &str) -> anyhow::Result<scene::Scene> {
+    let text = load_string(file_name).await?;
+    let scene: scene::Scene = ron::from_str(&text)
+        .map_err(|e| anyhow::anyhow!("could not parse {}: {}", file_name, e))?;
+
+    anyhow::ensure!(!scene.models.is_empty(), "{} has no models", file_name);
+    if let Some(index) = scene.shadow_caster {
+        anyhow::ensure!(
+            index < scene.lights.len(),
+            "{} casts shadows from light {} but has {} lights",
+            file_name,
+            index,
+            scene.lights.len()
+        );
+    }
+
+    // an empty instance buffer cannot be bound and a zero axis has no direction to rotate about
+    for model in &scene.models {
+        match &model.instances {
+            scene::Instances::Field { rows, cols } => anyhow::ensure!(
+                *rows > 0 && *cols > 0,
+                "{}: {} has a field of {}x{} instances",
+                file_name,
+                model.file,
+                rows,
+                cols
+            ),
+            scene::Instances::List(instances) => {
+                anyhow::ensure!(
+                    !instances.is_empty(),
+                    "{}: {} has no instances",
+                    file_name,
+                    model.file
+                );
+                for (i, instance) in instances.iter().enumerate() {
+                    let (axis, _) = instance.rotation;
+                    anyhow::ensure!(
+                        axis.iter().any(|&c| c != 0.0),
+                        "{}: instance {} of {} rotates about a zero axis",
+                        file_name,
+                        i,
+                        model.file
+                    );
+                }
+            }
+        }
+    }
+
+    Ok(scene)
+}
+
+/// Resolves a path found inside `file_name`, like a texture or a buffer, relative to its directory
+fn relative_to(file_name: &str, path: &str) -> String {
+    match file_name.rfind('/') {
+        Some(i) => format!("{}/{}", &file_name[..i], path),
+        None => path.to_string(),
+    }
+}
+
 pub async fn load_model(
     file_name: &str,
     device: &wgpu::Device,
     queue: &wgpu::Queue,
+) -> anyhow::Result<model::Model> {
+    let extension = std::path::Path::new(file_name)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_lowercase);
+
+    match extension.as_deref() {
+        Some("gltf") | Some("glb") => load_gltf(file_name, device, queue).await,
+        _ => load_obj(file_name, device, queue).await,
+    }
+}
+
+/// Maps of [`model::MaterialTextures`], each bound as a texture followed by its sampler
+const MATERIAL_MAPS: u32 = 6;
+
+pub fn create_materials_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+    // base colour, normal, metallic, roughness, occlusion and emissive maps
+    let mut entries = (0..MATERIAL_MAPS)
+        .flat_map(|i| {
+            [
+                wgpu::BindGroupLayoutEntry {
+                    binding: i * 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: i * 2 + 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ]
+        })
+        .collect::<Vec<_>>();
+
+    // factors
+    entries.push(wgpu::BindGroupLayoutEntry {
+        binding: MATERIAL_MAPS * 2,
+        visibility: wgpu::ShaderStages::FRAGMENT,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Uniform,
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    });
+
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: Some("texture bind group layout"),
+        entries: &entries,
+    })
+}
+
+/// White map, leaving the material to its factors
+fn default_white_texture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+) -> anyhow::Result<texture::Texture> {
+    texture::Texture::from_color(
+        device,
+        queue,
+        [255, 255, 255, 255],
+        "default white",
+        wgpu::TextureFormat::Rgba8Unorm,
+    )
+}
+
+/// Flat normal pointing out of the surface
+fn default_normal_texture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+) -> anyhow::Result<texture::Texture> {
+    texture::Texture::from_color(
+        device,
+        queue,
+        [128, 128, 255, 255],
+        "default normal",
+        wgpu::TextureFormat::Rgba8Unorm,
+    )
+}
+
+/// Plain white dielectric material with flat normals
+fn create_default_material(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout,
+) -> anyhow::Result<model::Material> {
+    let textures = model::MaterialTextures {
+        base_color: default_white_texture(device, queue)?,
+        normal: default_normal_texture(device, queue)?,
+        metallic: Rc::new(default_white_texture(device, queue)?),
+        roughness: Rc::new(default_white_texture(device, queue)?),
+        occlusion: default_white_texture(device, queue)?,
+        emissive: default_white_texture(device, queue)?,
+    };
+
+    Ok(model::Material::new(
+        device,
+        "default material",
+        textures,
+        model::FlatMaterial::default(),
+        layout,
+    ))
+}
+
+/// Reads a parameter of `N` numbers tobj leaves unparsed, like the PBR extensions `Pr` and `Pm`
+fn mtl_param<const N: usize>(material: &tobj::Material, key: &str) -> Option<[f32; N]> {
+    let values = material
+        .unknown_param
+        .get(key)?
+        .split_whitespace()
+        .map(str::parse)
+        .collect::<Result<Vec<f32>, _>>()
+        .ok()?;
+
+    values.try_into().ok()
+}
+
+/// Loads a texture referenced by a model material, falling back to `default` when there is none or it can not be loaded
+async fn load_material_texture(
+    model_file: &str,
+    texture_file: Option<&str>,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    format: wgpu::TextureFormat,
+    default: fn(&wgpu::Device, &wgpu::Queue) -> anyhow::Result<texture::Texture>,
+    files: &mut Vec<String>,
+) -> anyhow::Result<texture::Texture> {
+    let Some(texture_file) = texture_file else {
+        return default(device, queue);
+    };
+
+    let file_name = relative_to(model_file, texture_file);
+
+    // watched even when missing, so the model reloads once the texture or a variant appears
+    files.extend(compressed::variants(&file_name, device.features()));
+
+    match load_texture(&file_name, device, queue, format).await {
+        Ok(texture) => Ok(texture),
+        Err(e) => {
+            log::warn!(
+                "Could not load texture {}, using a default: {}",
+                file_name,
+                e
+            );
+            default(device, queue)
+        }
+    }
+}
+
+fn create_mesh(
+    device: &wgpu::Device,
+    name: &str,
+    vertices: &[model::ModelVertex],
+    indices: &[u32],
+    material: usize,
+) -> model::Mesh {
+    let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some(&format!("{:?} Vertex Buffer", name)),
+        contents: bytemuck::cast_slice(vertices),
+        usage: wgpu::BufferUsages::VERTEX,
+    });
+
+    let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some(&format!("{:?} Index Buffer", name)),
+        contents: bytemuck::cast_slice(indices),
+        usage: wgpu::BufferUsages::INDEX,
+    });
+
+    model::Mesh {
+        name: name.to_string(),
+        vertex_buffer,
+        index_buffer,
+        num_elements: indices.len() as u32,
+        material,
+    }
+}
+
+async fn load_obj(
+    file_name: &str,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
 ) -> anyhow::Result<model::Model> {
     let obj_text = load_string(file_name).await?;
+    // material libraries are read from the callback of tobj
+    let files = RefCell::new(vec![file_name.to_string()]);
     let obj_cursor = Cursor::new(obj_text);
     let mut obj_reader = BufReader::new(obj_cursor);
 
@@ -83,83 +410,144 @@ pub async fn load_model(
 
             ..Default::default()
         },
-        |p| async move {
-            let mat_text = load_string(&p).await.unwrap();
-            tobj::load_mtl_buf(&mut BufReader::new(Cursor::new(mat_text)))
+        |p| {
+            let files = &files;
+            async move {
+                let path = relative_to(file_name, &p);
+                files.borrow_mut().push(path.clone());
+                match load_string(&path).await {
+                    Ok(mat_text) => tobj::load_mtl_buf(&mut BufReader::new(Cursor::new(mat_text))),
+                    Err(e) => {
+                        log::warn!("Could not load material library {}: {}", p, e);
+                        Err(tobj::LoadError::OpenFileFailed)
+                    }
+                }
+            }
         },
     )
     .await?;
 
-    let materials_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-        label: Some("texture bind group layout"),
-        entries: &[
-            // diffuse map
-            wgpu::BindGroupLayoutEntry {
-                binding: 0,
-                visibility: wgpu::ShaderStages::FRAGMENT,
-                ty: wgpu::BindingType::Texture {
-                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                    view_dimension: wgpu::TextureViewDimension::D2,
-                    multisampled: false,
-                },
-                count: None,
-            },
-            wgpu::BindGroupLayoutEntry {
-                binding: 1,
-                visibility: wgpu::ShaderStages::FRAGMENT,
-                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                count: None,
-            },
-            // normal map
-            wgpu::BindGroupLayoutEntry {
-                binding: 2,
-                visibility: wgpu::ShaderStages::FRAGMENT,
-                ty: wgpu::BindingType::Texture {
-                    multisampled: false,
-                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                    view_dimension: wgpu::TextureViewDimension::D2,
-                },
-                count: None,
-            },
-            wgpu::BindGroupLayoutEntry {
-                binding: 3,
-                visibility: wgpu::ShaderStages::FRAGMENT,
-                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                count: None,
-            },
-        ],
-    });
+    let mut files = files.into_inner();
+
+    let materials_layout = create_materials_layout(device);
+
+    // an OBJ without a material library still renders, with the default material
+    let obj_materials = obj_materials.unwrap_or_default();
 
     let mut materials = Vec::new();
-    for m in obj_materials? {
-        let diffuse_texture = load_texture(
-            &m.diffuse_texture.unwrap(),
-            device,
-            queue,
-            wgpu::TextureFormat::Rgba8UnormSrgb, // diffusion textures
-        )
-        .await?;
+    for m in obj_materials {
+        let map = |key: &str| m.unknown_param.get(key).map(String::as_str);
 
-        let normal_texture = load_texture(
-            &m.normal_texture.unwrap(),
-            device,
-            queue,
-            wgpu::TextureFormat::Rgba8Unorm, // normal textures
-        )
-        .await?;
+        let textures = model::MaterialTextures {
+            base_color: load_material_texture(
+                file_name,
+                m.diffuse_texture.as_deref(),
+                device,
+                queue,
+                wgpu::TextureFormat::Rgba8UnormSrgb,
+                default_white_texture,
+                &mut files,
+            )
+            .await?,
+            normal: load_material_texture(
+                file_name,
+                m.normal_texture.as_deref(),
+                device,
+                queue,
+                wgpu::TextureFormat::Rgba8Unorm,
+                default_normal_texture,
+                &mut files,
+            )
+            .await?,
+            metallic: Rc::new(
+                load_material_texture(
+                    file_name,
+                    map("map_Pm"),
+                    device,
+                    queue,
+                    wgpu::TextureFormat::Rgba8Unorm,
+                    default_white_texture,
+                    &mut files,
+                )
+                .await?,
+            ),
+            roughness: Rc::new(
+                load_material_texture(
+                    file_name,
+                    map("map_Pr"),
+                    device,
+                    queue,
+                    wgpu::TextureFormat::Rgba8Unorm,
+                    default_white_texture,
+                    &mut files,
+                )
+                .await?,
+            ),
+            occlusion: default_white_texture(device, queue)?,
+            emissive: load_material_texture(
+                file_name,
+                map("map_Ke"),
+                device,
+                queue,
+                wgpu::TextureFormat::Rgba8UnormSrgb,
+                default_white_texture,
+                &mut files,
+            )
+            .await?,
+        };
+
+        // without the PBR extensions, the Blinn-Phong exponent gives an equivalent GGX roughness
+        // and a dimmer specular colour `Ks` spreads the highlight towards fully rough
+        let roughness = match mtl_param(&m, "Pr") {
+            Some([roughness]) => roughness,
+            None => {
+                let roughness = m
+                    .shininess
+                    .map_or(model::FlatMaterial::default().roughness, |shininess| {
+                        (2.0 / (shininess + 2.0)).powf(0.25)
+                    });
+                let strength = m
+                    .specular
+                    .map_or(1.0, |ks| ks.into_iter().fold(0.0, f32::max));
+                1.0 - strength.clamp(0.0, 1.0) * (1.0 - roughness)
+            }
+        };
+
+        // an emissive map without a colour shines at full strength
+        let emissive = mtl_param(&m, "Ke").unwrap_or(if map("map_Ke").is_some() {
+            [1.0; 3]
+        } else {
+            [0.0; 3]
+        });
+
+        let [r, g, b] = m.diffuse.unwrap_or([1.0; 3]);
+        let factors = model::FlatMaterial {
+            base_color: [r, g, b, m.dissolve.unwrap_or(1.0)],
+            emissive,
+            metallic: mtl_param(&m, "Pm").map_or(0.0, |[metallic]| metallic),
+            roughness,
+            ..Default::default()
+        };
 
         materials.push(model::Material::new(
             device,
             &m.name,
-            diffuse_texture,
-            normal_texture,
+            textures,
+            factors,
             &materials_layout,
         ))
     }
 
+    // meshes without a material use a default material, appended after the others
+    let default_material = materials.len();
+    materials.push(create_default_material(device, queue, &materials_layout)?);
+
     let meshes = models
         .into_iter()
         .map(|m| {
+            let has_tex_coords = !m.mesh.texcoords.is_empty();
+            let has_normals = !m.mesh.normals.is_empty();
+
             let mut vertices = (0..m.mesh.positions.len() / 3)
                 .map(|i| model::ModelVertex {
                     position: [
@@ -167,104 +555,48 @@ pub async fn load_model(
                         m.mesh.positions[i * 3 + 1],
                         m.mesh.positions[i * 3 + 2],
                     ],
-                    tex_coords: [m.mesh.texcoords[i * 2], m.mesh.texcoords[i * 2 + 1]],
-                    normal: [
-                        m.mesh.normals[i * 3],
-                        m.mesh.normals[i * 3 + 1],
-                        m.mesh.normals[i * 3 + 2],
-                    ],
-                    tangent: [0.0; 3],
-                    bitangent: [0.0; 3],
+                    tex_coords: if has_tex_coords {
+                        // OBJ texture coordinates start at the bottom left, wgpu ones at the top left
+                        [m.mesh.texcoords[i * 2], 1.0 - m.mesh.texcoords[i * 2 + 1]]
+                    } else {
+                        [0.0; 2]
+                    },
+                    normal: if has_normals {
+                        [
+                            m.mesh.normals[i * 3],
+                            m.mesh.normals[i * 3 + 1],
+                            m.mesh.normals[i * 3 + 2],
+                        ]
+                    } else {
+                        [0.0; 3]
+                    },
+                    tangent: [0.0; 4],
                 })
                 .collect::<Vec<_>>();
 
-            let indices = &m.mesh.indices;
-            let mut triangles_included = vec![0; vertices.len()];
-
-            // Calculate tangents and bitangents. We're going to
-            // use the triangles, so we need to loop through the
-            // indices in chunks of 3
-            for c in indices.chunks(3) {
-                let v0 = vertices[c[0] as usize];
-                let v1 = vertices[c[1] as usize];
-                let v2 = vertices[c[2] as usize];
-
-                let pos0: cgmath::Vector3<_> = v0.position.into();
-                let pos1: cgmath::Vector3<_> = v1.position.into();
-                let pos2: cgmath::Vector3<_> = v2.position.into();
-
-                let uv0: cgmath::Vector2<_> = v0.tex_coords.into();
-                let uv1: cgmath::Vector2<_> = v1.tex_coords.into();
-                let uv2: cgmath::Vector2<_> = v2.tex_coords.into();
-
-                // Calculate the edges of the triangle
-                let delta_pos1 = pos1 - pos0;
-                let delta_pos2 = pos2 - pos0;
-
-                // This will give us a direction to calculate the
-                // tangent and bitangent
-                let delta_uv1 = uv1 - uv0;
-                let delta_uv2 = uv2 - uv0;
-
-                // Solving the following system of equations will
-                // give us the tangent and bitangent.
-                //     delta_pos1 = delta_uv1.x * T + delta_u.y * B
-                //     delta_pos2 = delta_uv2.x * T + delta_uv2.y * B
-                // Luckily, the place I found this equation provided
-                // the solution!
-                let r = 1.0 / (delta_uv1.x * delta_uv2.y - delta_uv1.y * delta_uv2.x);
-                let tangent = (delta_pos1 * delta_uv2.y - delta_pos2 * delta_uv1.y) * r;
-                // We flip the bitangent to enable right-handed normal
-                // maps with wgpu texture coordinate system
-                let bitangent = (delta_pos2 * delta_uv1.x - delta_pos1 * delta_uv2.x) * -r;
-
-                // We'll use the same tangent/bitangent for each vertex in the triangle
-                vertices[c[0] as usize].tangent =
-                    (tangent + cgmath::Vector3::from(vertices[c[0] as usize].tangent)).into();
-                vertices[c[1] as usize].tangent =
-                    (tangent + cgmath::Vector3::from(vertices[c[1] as usize].tangent)).into();
-                vertices[c[2] as usize].tangent =
-                    (tangent + cgmath::Vector3::from(vertices[c[2] as usize].tangent)).into();
-                vertices[c[0] as usize].bitangent =
-                    (bitangent + cgmath::Vector3::from(vertices[c[0] as usize].bitangent)).into();
-                vertices[c[1] as usize].bitangent =
-                    (bitangent + cgmath::Vector3::from(vertices[c[1] as usize].bitangent)).into();
-                vertices[c[2] as usize].bitangent =
-                    (bitangent + cgmath::Vector3::from(vertices[c[2] as usize].bitangent)).into();
-
-                // Used to average the tangents/bitangents
-                triangles_included[c[0] as usize] += 1;
-                triangles_included[c[1] as usize] += 1;
-                triangles_included[c[2] as usize] += 1;
-            }
+            let mut indices = m.mesh.indices;
 
-            // Average the tangents/bitangents
-            for (i, n) in triangles_included.into_iter().enumerate() {
-                let denom = 1.0 / n as f32;
-                let v = &mut vertices[i];
-                v.tangent = (cgmath::Vector3::from(v.tangent) * denom).into();
-                v.bitangent = (cgmath::Vector3::from(v.bitangent) * denom).into();
+            if !has_normals {
+                attributes::generate_normals(&mut vertices, &mut indices, NORMAL_SMOOTHING_ANGLE);
             }
 
-            let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Vertex Buffer", file_name)),
-                contents: bytemuck::cast_slice(&vertices),
-                usage: wgpu::BufferUsages::VERTEX,
-            });
-
-            let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Index Buffer", file_name)),
-                contents: bytemuck::cast_slice(&m.mesh.indices),
-                usage: wgpu::BufferUsages::INDEX,
-            });
-
-            model::Mesh {
-                name: file_name.to_string(),
-                vertex_buffer,
-                index_buffer,
-                num_elements: m.mesh.indices.len() as u32,
-                material: m.mesh.material_id.unwrap_or(0),
+            if !has_tex_coords {
+                attributes::generate_tex_coords(
+                    &mut vertices,
+                    &mut indices,
+                    attributes::UvProjection::Box,
+                );
             }
+
+            attributes::generate_tangents(&mut vertices, &mut indices);
+
+            create_mesh(
+                device,
+                file_name,
+                &vertices,
+                &indices,
+                m.mesh.material_id.unwrap_or(default_material),
+            )
         })
         .collect::<Vec<_>>();
 
@@ -272,5 +604,435 @@ pub async fn load_model(
         meshes,
         materials,
         materials_layout,
+        files,
+    })
+}
+
+/// Loads a buffer or an image referenced by a glTF file, either embedded as a data URI or as a separate file
+async fn load_gltf_uri(
+    file_name: &str,
+    uri: &str,
+    files: &mut Vec<String>,
+) -> anyhow::Result<Vec<u8>> {
+    match uri.strip_prefix("data:") {
+        Some(data) => {
+            let (_, encoded) = data
+                .split_once(";base64,")
+                .ok_or_else(|| anyhow::anyhow!("{} has a data URI without base64", file_name))?;
+
+            Ok(base64::engine::general_purpose::STANDARD.decode(encoded)?)
+        }
+        None => {
+            let path = relative_to(file_name, uri);
+            files.push(path.clone());
+            load_binary(&path).await
+        }
+    }
+}
+
+async fn load_gltf_texture(
+    file_name: &str,
+    buffers: &[Vec<u8>],
+    texture: gltf::Texture<'_>,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    format: wgpu::TextureFormat,
+    files: &mut Vec<String>,
+) -> anyhow::Result<texture::Texture> {
+    let image = texture.source();
+
+    let data = match image.source() {
+        gltf::image::Source::View { view, .. } => {
+            let range = view.offset()..view.offset() + view.length();
+            buffers
+                .get(view.buffer().index())
+                .and_then(|buffer| buffer.get(range.clone()))
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "image view {:?} is outside buffer {} of {}",
+                        range,
+                        view.buffer().index(),
+                        file_name
+                    )
+                })?
+                .to_vec()
+        }
+        gltf::image::Source::Uri { uri, .. } => load_gltf_uri(file_name, uri, files).await?,
+    };
+
+    let label = image.name().unwrap_or(file_name);
+
+    texture::Texture::from_bytes(device, queue, &data, label, format)
+}
+
+/// Loads a texture referenced by a glTF material, or `default` when there is none
+#[allow(clippy::too_many_arguments)]
+async fn load_gltf_material_texture(
+    file_name: &str,
+    buffers: &[Vec<u8>],
+    texture: Option<gltf::Texture<'_>>,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    format: wgpu::TextureFormat,
+    default: fn(&wgpu::Device, &wgpu::Queue) -> anyhow::Result<texture::Texture>,
+    files: &mut Vec<String>,
+) -> anyhow::Result<texture::Texture> {
+    match texture {
+        Some(texture) => {
+            load_gltf_texture(file_name, buffers, texture, device, queue, format, files).await
+        }
+        None => default(device, queue),
+    }
+}
+
+async fn load_gltf_material(
+    file_name: &str,
+    buffers: &[Vec<u8>],
+    material: gltf::Material<'_>,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout,
+    files: &mut Vec<String>,
+) -> anyhow::Result<model::Material> {
+    let name = material.name().unwrap_or(file_name);
+    let pbr = material.pbr_metallic_roughness();
+
+    // the combined map fills both slots, blue is metallic and green roughness
+    let metallic_roughness = Rc::new(
+        load_gltf_material_texture(
+            file_name,
+            buffers,
+            pbr.metallic_roughness_texture().map(|info| info.texture()),
+            device,
+            queue,
+            wgpu::TextureFormat::Rgba8Unorm,
+            default_white_texture,
+            files,
+        )
+        .await?,
+    );
+
+    let textures = model::MaterialTextures {
+        base_color: load_gltf_material_texture(
+            file_name,
+            buffers,
+            pbr.base_color_texture().map(|info| info.texture()),
+            device,
+            queue,
+            wgpu::TextureFormat::Rgba8UnormSrgb,
+            default_white_texture,
+            files,
+        )
+        .await?,
+        normal: load_gltf_material_texture(
+            file_name,
+            buffers,
+            material.normal_texture().map(|info| info.texture()),
+            device,
+            queue,
+            wgpu::TextureFormat::Rgba8Unorm,
+            default_normal_texture,
+            files,
+        )
+        .await?,
+        metallic: Rc::clone(&metallic_roughness),
+        roughness: metallic_roughness,
+        occlusion: load_gltf_material_texture(
+            file_name,
+            buffers,
+            material.occlusion_texture().map(|info| info.texture()),
+            device,
+            queue,
+            wgpu::TextureFormat::Rgba8Unorm,
+            default_white_texture,
+            files,
+        )
+        .await?,
+        emissive: load_gltf_material_texture(
+            file_name,
+            buffers,
+            material.emissive_texture().map(|info| info.texture()),
+            device,
+            queue,
+            wgpu::TextureFormat::Rgba8UnormSrgb,
+            default_white_texture,
+            files,
+        )
+        .await?,
+    };
+
+    let factors = model::FlatMaterial {
+        base_color: pbr.base_color_factor(),
+        emissive: material.emissive_factor(),
+        metallic: pbr.metallic_factor(),
+        roughness: pbr.roughness_factor(),
+        occlusion_strength: material
+            .occlusion_texture()
+            .map_or(1.0, |info| info.strength()),
+        normal_scale: material.normal_texture().map_or(1.0, |info| info.scale()),
+        _padding: 0,
+    };
+
+    Ok(model::Material::new(
+        device, name, textures, factors, layout,
+    ))
+}
+
+fn load_gltf_primitive(
+    file_name: &str,
+    mesh_name: &str,
+    primitive: gltf::Primitive<'_>,
+    buffers: &[Vec<u8>],
+    transform: cgmath::Matrix4<f32>,
+    default_material: usize,
+    device: &wgpu::Device,
+) -> anyhow::Result<Option<model::Mesh>> {
+    use cgmath::{InnerSpace, Matrix, SquareMatrix, Transform};
+
+    if primitive.mode() != gltf::mesh::Mode::Triangles {
+        log::warn!(
+            "{} has a primitive with {:?} mode, only triangles are supported",
+            file_name,
+            primitive.mode()
+        );
+        return Ok(None);
+    }
+
+    let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(Vec::as_slice));
+
+    let positions = reader
+        .read_positions()
+        .ok_or_else(|| anyhow::anyhow!("{} has a primitive without positions", file_name))?
+        .collect::<Vec<_>>();
+
+    let normals = reader
+        .read_normals()
+        .map(|normals| normals.collect::<Vec<_>>());
+
+    let tex_coords = reader
+        .read_tex_coords(0)
+        .map(|tex_coords| tex_coords.into_f32().collect::<Vec<_>>());
+
+    // the attributes are read by the index of the position
+    for (attribute, count) in [
+        ("NORMAL", normals.as_ref().map(Vec::len)),
+        ("TEXCOORD_0", tex_coords.as_ref().map(Vec::len)),
+    ] {
+        if let Some(count) = count.filter(|&count| count < positions.len()) {
+            anyhow::bail!(
+                "{}: mesh {} has {} {} values for {} positions",
+                file_name,
+                mesh_name,
+                count,
+                attribute,
+                positions.len()
+            );
+        }
+    }
+
+    let linear = cgmath::Matrix3::from_cols(
+        transform.x.truncate(),
+        transform.y.truncate(),
+        transform.z.truncate(),
+    );
+    let normal_matrix = linear.invert().unwrap_or(linear).transpose();
+
+    let mut vertices = positions
+        .into_iter()
+        .enumerate()
+        .map(|(i, position)| model::ModelVertex {
+            position: transform
+                .transform_point(cgmath::Point3::from(position))
+                .into(),
+            tex_coords: tex_coords.as_ref().map_or([0.0; 2], |t| t[i]),
+            normal: normals.as_ref().map_or([0.0; 3], |n| {
+                (normal_matrix * cgmath::Vector3::from(n[i]))
+                    .normalize()
+                    .into()
+            }),
+            tangent: [0.0; 4],
+        })
+        .collect::<Vec<_>>();
+
+    let mut indices = match reader.read_indices() {
+        Some(indices) => indices.into_u32().collect::<Vec<_>>(),
+        None => (0..vertices.len() as u32).collect(),
+    };
+
+    if let Some(index) = indices
+        .iter()
+        .find(|&&index| index as usize >= vertices.len())
+    {
+        anyhow::bail!(
+            "{}: mesh {} has index {} past its {} vertices",
+            file_name,
+            mesh_name,
+            index,
+            vertices.len()
+        );
+    }
+
+    // mirroring transforms flip the winding order of the triangles
+    if linear.determinant() < 0.0 {
+        indices
+            .chunks_mut(3)
+            .for_each(|triangle| triangle.swap(1, 2));
+    }
+
+    // provided tangents are only valid alongside the normals and texture coordinates they were built from
+    let tangents = reader
+        .read_tangents()
+        .filter(|_| normals.is_some() && tex_coords.is_some());
+
+    // the specification asks for flat normals when they are not provided
+    if normals.is_none() {
+        attributes::generate_normals(&mut vertices, &mut indices, cgmath::Deg(0.0));
+    }
+
+    if tex_coords.is_none() {
+        attributes::generate_tex_coords(&mut vertices, &mut indices, attributes::UvProjection::Box);
+    }
+
+    match tangents {
+        Some(tangents) => {
+            // mirroring transforms also flip the handedness of the bitangent
+            let mirrored = if linear.determinant() < 0.0 {
+                -1.0
+            } else {
+                1.0
+            };
+
+            for (vertex, tangent) in vertices.iter_mut().zip(tangents) {
+                let [x, y, z, handedness] = tangent;
+                let tangent = (linear * cgmath::Vector3::new(x, y, z)).normalize();
+
+                vertex.tangent = tangent.extend(handedness * mirrored).into();
+            }
+        }
+        None => attributes::generate_tangents(&mut vertices, &mut indices),
+    }
+
+    Ok(Some(create_mesh(
+        device,
+        &format!("{} primitive {}", file_name, primitive.index()),
+        &vertices,
+        &indices,
+        primitive.material().index().unwrap_or(default_material),
+    )))
+}
+
+fn load_gltf_node(
+    file_name: &str,
+    node: gltf::Node<'_>,
+    parent_transform: cgmath::Matrix4<f32>,
+    buffers: &[Vec<u8>],
+    default_material: usize,
+    device: &wgpu::Device,
+    meshes: &mut Vec<model::Mesh>,
+) -> anyhow::Result<()> {
+    let transform = parent_transform * cgmath::Matrix4::from(node.transform().matrix());
+
+    if let Some(mesh) = node.mesh() {
+        let mesh_name = mesh
+            .name()
+            .map_or_else(|| mesh.index().to_string(), |name| format!("{:?}", name));
+
+        for primitive in mesh.primitives() {
+            if let Some(mesh) = load_gltf_primitive(
+                file_name,
+                &mesh_name,
+                primitive,
+                buffers,
+                transform,
+                default_material,
+                device,
+            )? {
+                meshes.push(mesh);
+            }
+        }
+    }
+
+    for child in node.children() {
+        load_gltf_node(
+            file_name,
+            child,
+            transform,
+            buffers,
+            default_material,
+            device,
+            meshes,
+        )?;
+    }
+
+    Ok(())
+}
+
+async fn load_gltf(
+    file_name: &str,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+) -> anyhow::Result<model::Model> {
+    use cgmath::SquareMatrix;
+
+    let data = load_binary(file_name).await?;
+    let mut files = vec![file_name.to_string()];
+    let gltf::Gltf { document, mut blob } = gltf::Gltf::from_slice(&data)?;
+
+    let mut buffers = Vec::new();
+    for buffer in document.buffers() {
+        let data = match buffer.source() {
+            gltf::buffer::Source::Bin => blob
+                .take()
+                .ok_or_else(|| anyhow::anyhow!("{} has no binary chunk", file_name))?,
+            gltf::buffer::Source::Uri(uri) => load_gltf_uri(file_name, uri, &mut files).await?,
+        };
+        buffers.push(data);
+    }
+
+    let materials_layout = create_materials_layout(device);
+
+    let mut materials = Vec::new();
+    for material in document.materials() {
+        materials.push(
+            load_gltf_material(
+                file_name,
+                &buffers,
+                material,
+                device,
+                queue,
+                &materials_layout,
+                &mut files,
+            )
+            .await?,
+        );
+    }
+
+    // primitives without a material use a default material, appended after the others
+    let default_material = materials.len();
+    materials.push(create_default_material(device, queue, &materials_layout)?);
+
+    let scene = document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+        .ok_or_else(|| anyhow::anyhow!("{} has no scenes", file_name))?;
+
+    let mut meshes = Vec::new();
+    for node in scene.nodes() {
+        load_gltf_node(
+            file_name,
+            node,
+            cgmath::Matrix4::identity(),
+            &buffers,
+            default_material,
+            device,
+            &mut meshes,
+        )?;
+    }
+
+    Ok(model::Model {
+        meshes,
+        materials,
+        materials_layout,
+        files,
     })
 }
@@ -0,0 +1,251 @@
+use cgmath::{Deg, InnerSpace, Rotation3};
+use serde::Deserialize;
+
+use crate::{environment, light, model, resources, transforms, view};
+
+/// Everything drawn and where it is seen from, loaded from a RON file by
+/// [`crate::resources::load_scene`]
+#[derive(Debug, Clone, Deserialize)]
+pub struct Scene {
+    pub camera: Camera,
+    pub projection: Projection,
+    /// One equirectangular panorama or six cube faces, see [`crate::resources::load_environment`]
+    #[serde(default = "default_environment")]
+    pub environment: Vec<String>,
+    pub lights: Vec<Light>,
+    /// Index of the light casting shadows, the first point light by default, or the first light
+    /// without point lights
+    #[serde(default)]
+    pub shadow_caster: Option<usize>,
+    pub models: Vec<Model>,
+}
+
+fn default_environment() -> Vec<String> {
+    vec![String::from("sky.hdr")]
+}
+
+impl Scene {
+    /// Bakes the environment maps from the images of `environment`
+    pub async fn load_environment(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> anyhow::Result<environment::Environment> {
+        let file_names = self
+            .environment
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        let source = resources::load_environment(&file_names, device, queue).await?;
+
+        Ok(environment::Environment::build()
+            .resolution(512)
+            .intensity(1.0)
+            .finalize(device, queue, &source))
+    }
+
+    /// See [`light::LightsBuilder::storage`] for `storage`
+    pub fn create_lights(
+        &self,
+        device: &wgpu::Device,
+        environment: &environment::Environment,
+        storage: bool,
+    ) -> Result<light::Lights, light::LightsError> {
+        let mut lights = self
+            .lights
+            .iter()
+            .fold(light::Lights::build(), |lights, light| {
+                lights.light(light.to_light())
+            });
+        if let Some(shadow_caster) = self.shadow_caster {
+            lights = lights.shadow_caster(shadow_caster);
+        }
+
+        lights.storage(storage).finalize(device, environment)
+    }
+}
+
+/// Angles are in degrees, a yaw of -90 looks down -z
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Camera {
+    pub position: [f32; 3],
+    pub yaw: f32,
+    pub pitch: f32,
+}
+
+/// Perspective projection, `fovy` is the vertical field of view in degrees
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Projection {
+    pub fovy: f32,
+    pub znear: f32,
+    pub zfar: f32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub enum LightKind {
+    Point,
+    Directional {
+        direction: [f32; 3],
+    },
+    /// Angles from the axis of the cone, in degrees
+    Spot {
+        direction: [f32; 3],
+        inner_angle: f32,
+        outer_angle: f32,
+    },
+}
+
+/// Fields left out take the defaults of [`light::Light::build`]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Light {
+    pub kind: LightKind,
+    pub position: [f32; 3],
+    pub color: [f32; 3],
+    pub intensity: f32,
+    pub range: f32,
+    pub attenuation: [f32; 3],
+    /// Degrees per second the light turns around the y axis
+    pub orbit: f32,
+}
+
+impl Camera {
+    pub fn to_camera(&self) -> view::Camera {
+        view::Camera::new(self.position, Deg(self.yaw), Deg(self.pitch))
+    }
+}
+
+impl Projection {
+    pub fn to_projection(&self, width: u32, height: u32) -> view::Projection {
+        view::Projection::new(width, height, Deg(self.fovy), self.znear, self.zfar)
+    }
+}
+
+impl Default for Light {
+    fn default() -> Self {
+        Self {
+            kind: LightKind::Point,
+            position: [0.0; 3],
+            color: [1.0; 3],
+            intensity: 1.0,
+            range: 100.0,
+            attenuation: [1.0, 0.0, 0.0],
+            orbit: 0.0,
+        }
+    }
+}
+
+impl Light {
+    pub fn to_light(&self) -> light::Light {
+        let builder = light::Light::build()
+            .position(self.position)
+            .color(self.color)
+            .intensity(self.intensity)
+            .range(self.range)
+            .attenuation(
+                self.attenuation[0],
+                self.attenuation[1],
+                self.attenuation[2],
+            )
+            .controller(self.orbit);
+
+        match self.kind {
+            LightKind::Point => builder,
+            LightKind::Directional { direction } => builder.directional(direction),
+            LightKind::Spot {
+                direction,
+                inner_angle,
+                outer_angle,
+            } => builder.spot(direction, Deg(inner_angle), Deg(outer_angle)),
+        }
+        .finalize()
+    }
+}
+
+/// A model file drawn once per instance
+#[derive(Debug, Clone, Deserialize)]
+pub struct Model {
+    pub file: String,
+    pub instances: Instances,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub enum Instances {
+    /// Grid of instances turned away from the origin, see [`transforms::generate_transforms`]
+    Field {
+        rows: u32,
+        cols: u32,
+    },
+    List(Vec<Instance>),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Instance {
+    pub translation: [f32; 3],
+    /// Axis and angle in degrees
+    pub rotation: ([f32; 3], f32),
+    pub scale: f32,
+}
+
+impl Default for Instance {
+    fn default() -> Self {
+        Self {
+            translation: [0.0; 3],
+            rotation: ([0.0, 1.0, 0.0], 0.0),
+            scale: 1.0,
+        }
+    }
+}
+
+impl Instances {
+    pub fn to_transforms(&self) -> Vec<transforms::Transform> {
+        match self {
+            Instances::Field { rows, cols } => transforms::generate_transforms(*rows, *cols),
+            Instances::List(instances) => instances
+                .iter()
+                .map(|instance| {
+                    let (axis, angle) = instance.rotation;
+                    let axis = cgmath::Vector3::from(axis).normalize();
+
+                    transforms::Transform::new(
+                        instance.translation,
+                        cgmath::Quaternion::from_axis_angle(axis, Deg(angle)),
+                        instance.scale,
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// Instance buffer of the transforms
+    pub fn create_transforms(&self, device: &wgpu::Device) -> transforms::Transforms {
+        transforms::Transforms::build()
+            .transforms(self.to_transforms())
+            .finalize(device)
+    }
+}
+
+/// A loaded model with its instances
+pub struct Object {
+    /// File the model was loaded from
+    pub file: String,
+    pub model: model::Model,
+    pub instances: transforms::Transforms,
+}
+
+impl Object {
+    pub async fn load(
+        model: &Model,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> anyhow::Result<Self> {
+        let loaded = resources::load_model(&model.file, device, queue).await?;
+
+        Ok(Self {
+            file: model.file.clone(),
+            model: loaded,
+            instances: model.instances.create_transforms(device),
+        })
+    }
+}
@@ -1,50 +1,75 @@
+// Features toggled by `shaders::Features`: NORMAL_MAPS, SHADOWS
 
-
-@group(0) @binding(0) var t_diffuse: texture_2d<f32>;
-@group(0) @binding(1) var s_diffuse: sampler;
+@group(0) @binding(0) var t_base_color: texture_2d<f32>;
+@group(0) @binding(1) var s_base_color: sampler;
 @group(0) @binding(2) var t_normal: texture_2d<f32>;
 @group(0) @binding(3) var s_normal: sampler;
+@group(0) @binding(4) var t_metallic: texture_2d<f32>;
+@group(0) @binding(5) var s_metallic: sampler;
+@group(0) @binding(6) var t_roughness: texture_2d<f32>;
+@group(0) @binding(7) var s_roughness: sampler;
+@group(0) @binding(8) var t_occlusion: texture_2d<f32>;
+@group(0) @binding(9) var s_occlusion: sampler;
+@group(0) @binding(10) var t_emissive: texture_2d<f32>;
+@group(0) @binding(11) var s_emissive: sampler;
 
-struct CameraUniform {
-    view_pos: vec4<f32>,
-    view_proj: mat4x4<f32>,
-};
+struct Material {
+    base_color: vec4<f32>,
+    emissive: vec3<f32>,
+    metallic: f32,
+    roughness: f32,
+    occlusion_strength: f32,
+    normal_scale: f32,
+}
+
+@group(0) @binding(12) var<uniform> material: Material;
+
+#include "camera.wgsl"
 
-@group(1) @binding(0) var<uniform> camera: CameraUniform;
+@group(1) @binding(0) var<uniform> camera: Camera;
 
-struct Light {
-    position: vec3<f32>,
-    color: vec3<f32>,
+// `Light`, the `lights` binding and the environment maps of group 2 are appended by `light::Lights::declaration`
+
+// point lights cast into the cube, directional and spot lights into the projected map
+@group(3) @binding(0) var t_shadow: texture_depth_cube;
+@group(3) @binding(1) var s_shadow: sampler_comparison;
+@group(3) @binding(5) var t_projected_shadow: texture_depth_2d;
+
+struct Shadow {
+    view_proj: mat4x4<f32>,
+    origin: vec3<f32>,
+    bias: f32,
+    // distances of orthographic maps are measured along it, zero otherwise
+    direction: vec3<f32>,
+    texel_size: f32,
+    far: f32,
 }
 
-@group(2) @binding(0) var<uniform> light: Light;
+@group(3) @binding(2) var<uniform> shadow: Shadow;
+
+// screen-space ambient occlusion, 1 where nothing occludes
+@group(3) @binding(3) var t_ambient_occlusion: texture_2d<f32>;
+@group(3) @binding(4) var s_ambient_occlusion: sampler;
 
 struct VertexInput {
     @location(0) position: vec3<f32>,
     @location(1) tex_coords: vec2<f32>,
     @location(2) normal: vec3<f32>,
-    @location(3) tangent: vec3<f32>,
-    @location(4) bitangent: vec3<f32>,
+    @location(3) tangent: vec4<f32>,
 }
 
-struct InstanceInput {
-    @location(5) model_matrix_0: vec4<f32>,
-    @location(6) model_matrix_1: vec4<f32>,
-    @location(7) model_matrix_2: vec4<f32>,
-    @location(8) model_matrix_3: vec4<f32>,
-
-    @location(9) normal_matrix_0: vec3<f32>,
-    @location(10) normal_matrix_1: vec3<f32>,
-    @location(11) normal_matrix_2: vec3<f32>,
-};
+#include "instance.wgsl"
 
 
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
     @location(0) tex_coords: vec2<f32>,
-    @location(1) tangent_position: vec3<f32>,
-    @location(2) tangent_light_position: vec3<f32>,
-    @location(3) tangent_view_position: vec3<f32>,
+    @location(1) world_tangent: vec3<f32>,
+    @location(2) world_bitangent: vec3<f32>,
+    @location(3) world_normal: vec3<f32>,
+    @location(4) world_position: vec3<f32>,
+    // the fragment position without the viewport transform, to read the screen-space occlusion
+    @location(5) screen_position: vec4<f32>,
 };
 
 @vertex
@@ -66,51 +91,210 @@ fn vs_main(
         instance.normal_matrix_2,
     );
 
-    // Construct the tangent matrix
+    // Tangent space basis, the fragment stage takes the normal map into world space with it
     let world_normal = normalize(normal_matrix * model.normal);
-    let world_tangent = normalize(normal_matrix * model.tangent);
-    let world_bitangent = normalize(normal_matrix * model.bitangent);
-
-    let tangent_matrix = transpose(mat3x3<f32>(
-        world_tangent,
-        world_bitangent,
-        world_normal,
-    ));
+    let world_tangent = normalize(normal_matrix * model.tangent.xyz);
+    // MikkTSpace bitangent, w holds its handedness
+    let world_bitangent = cross(world_normal, world_tangent) * model.tangent.w;
 
     let world_position = model_matrix * vec4<f32>(model.position, 1.0);
 
     var out: VertexOutput;
     out.clip_position = camera.view_proj * world_position;
     out.tex_coords = model.tex_coords;
-    out.tangent_position = tangent_matrix * world_position.xyz;
-    out.tangent_view_position = tangent_matrix * camera.view_pos.xyz;
-    out.tangent_light_position = tangent_matrix * light.position;
+    out.world_tangent = world_tangent;
+    out.world_bitangent = world_bitangent;
+    out.world_normal = world_normal;
+    out.world_position = world_position.xyz;
+    out.screen_position = out.clip_position;
     return out;
 }
 
+// Fraction of a kernel around the direction to the fragment lit by the light, each tap filtered by the comparison sampler
+fn cube_shadow_factor(light: Light, world_position: vec3<f32>) -> f32 {
+    let to_fragment = world_position - light.position;
+    let distance = length(to_fragment);
+    let depth = distance / shadow.far - shadow.bias;
+
+    if depth > 1.0 {
+        return 1.0;
+    }
+
+    // kernel axes perpendicular to the direction, a texel apart on the cube face, which spans 2
+    // units at a distance of 1
+    let direction = to_fragment / distance;
+    var side = vec3<f32>(0.0, 1.0, 0.0);
+    if abs(direction.y) > 0.99 {
+        side = vec3<f32>(1.0, 0.0, 0.0);
+    }
+    let u = normalize(cross(direction, side)) * 2.0 * shadow.texel_size;
+    let v = cross(direction, u);
+
+    var lit = 0.0;
+    for (var y = -1; y <= 1; y++) {
+        for (var x = -1; x <= 1; x++) {
+            let offset = f32(x) * u + f32(y) * v;
+            lit += textureSampleCompareLevel(t_shadow, s_shadow, direction + offset, depth);
+        }
+    }
+
+    return lit / 9.0;
+}
 
+// Fraction of a 3x3 kernel around the fragment in the projected map lit by the light
+fn projected_shadow_factor(world_position: vec3<f32>) -> f32 {
+    let clip = shadow.view_proj * vec4<f32>(world_position, 1.0);
+    let uv = clip.xy / clip.w * vec2<f32>(0.5, -0.5) + 0.5;
+
+    let to_fragment = world_position - shadow.origin;
+    var distance = length(to_fragment);
+    if any(shadow.direction != vec3<f32>(0.0)) {
+        distance = dot(to_fragment, shadow.direction);
+    }
+    let depth = distance / shadow.far - shadow.bias;
+
+    // outside the map nothing is known to occlude the fragment
+    if clip.w <= 0.0 || any(uv < vec2<f32>(0.0)) || any(uv > vec2<f32>(1.0)) || depth > 1.0 {
+        return 1.0;
+    }
+
+    var lit = 0.0;
+    for (var y = -1; y <= 1; y++) {
+        for (var x = -1; x <= 1; x++) {
+            let offset = vec2<f32>(f32(x), f32(y)) * shadow.texel_size;
+            lit += textureSampleCompareLevel(t_projected_shadow, s_shadow, uv + offset, depth);
+        }
+    }
+
+    return lit / 9.0;
+}
+
+fn shadow_factor(light: Light, world_position: vec3<f32>) -> f32 {
+    if light.kind == LIGHT_POINT {
+        return cube_shadow_factor(light, world_position);
+    }
+    return projected_shadow_factor(world_position);
+}
+
+// Fading of a point or spot light over the distance, smoothly reaching zero at its range
+fn distance_falloff(light: Light, distance: f32) -> f32 {
+    let a = light.attenuation;
+    let window = clamp(1.0 - pow(distance / light.range, 4.0), 0.0, 1.0);
+    return window * window / (a.x + a.y * distance + a.z * distance * distance);
+}
+
+const PI: f32 = 3.14159265;
+
+// GGX / Trowbridge-Reitz distribution of the microfacet normals
+fn distribution_ggx(n_dot_h: f32, alpha: f32) -> f32 {
+    let alpha2 = alpha * alpha;
+    let d = n_dot_h * n_dot_h * (alpha2 - 1.0) + 1.0;
+    return alpha2 / (PI * d * d);
+}
+
+// Smith masking and shadowing with the Schlick-GGX approximation for direct light
+fn geometry_smith(n_dot_v: f32, n_dot_l: f32, roughness: f32) -> f32 {
+    let k = (roughness + 1.0) * (roughness + 1.0) / 8.0;
+    let g_v = n_dot_v / (n_dot_v * (1.0 - k) + k);
+    let g_l = n_dot_l / (n_dot_l * (1.0 - k) + k);
+    return g_v * g_l;
+}
+
+fn fresnel_schlick(cos_theta: f32, f0: vec3<f32>) -> vec3<f32> {
+    return f0 + (1.0 - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
+}
+
+// Fresnel averaged over the rough lobe, rough surfaces reflect less of the environment at grazing angles
+fn fresnel_schlick_roughness(cos_theta: f32, f0: vec3<f32>, roughness: f32) -> vec3<f32> {
+    return f0 + (max(vec3<f32>(1.0 - roughness), f0) - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
+}
 
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    let object_color = textureSample(t_diffuse, s_diffuse, in.tex_coords);
+    let base_color = textureSample(t_base_color, s_base_color, in.tex_coords) * material.base_color;
+    let metallic = textureSample(t_metallic, s_metallic, in.tex_coords).b * material.metallic;
+    // very low roughness makes the highlights alias
+    let roughness = clamp(textureSample(t_roughness, s_roughness, in.tex_coords).g * material.roughness, 0.045, 1.0);
+    let occlusion = mix(1.0, textureSample(t_occlusion, s_occlusion, in.tex_coords).r, material.occlusion_strength);
+    let emissive = textureSample(t_emissive, s_emissive, in.tex_coords).rgb * material.emissive;
+    let screen_coords = in.screen_position.xy / in.screen_position.w * vec2<f32>(0.5, -0.5) + 0.5;
+    let ambient_occlusion = textureSample(t_ambient_occlusion, s_ambient_occlusion, screen_coords).r;
+
+#ifdef NORMAL_MAPS
     let object_normal = textureSample(t_normal, s_normal, in.tex_coords);
+    let tangent_matrix = mat3x3<f32>(
+        normalize(in.world_tangent),
+        normalize(in.world_bitangent),
+        normalize(in.world_normal),
+    );
+    let tangent_normal = (object_normal.xyz * 2.0 - 1.0) * vec3<f32>(material.normal_scale, material.normal_scale, 1.0);
+    let normal = normalize(tangent_matrix * tangent_normal);
+#else
+    let normal = normalize(in.world_normal);
+#endif
+    let view_dir = normalize(camera.view_pos.xyz - in.world_position);
+    let n_dot_v = max(dot(normal, view_dir), 1e-4);
+
+    // dielectrics reflect 4% head on, metals tint the reflection with their colour
+    let f0 = mix(vec3<f32>(0.04), base_color.rgb, metallic);
+    let diffuse_color = base_color.rgb * (1.0 - metallic);
+    let alpha = roughness * roughness;
+
+    // light from the environment, split into the irradiance and the prefiltered reflections
+    let environment_fresnel = fresnel_schlick_roughness(n_dot_v, f0, roughness);
+    let irradiance = textureSample(t_irradiance, s_environment, normal).rgb;
+    let reflection_dir = reflect(-view_dir, normal);
+    let prefiltered = textureSampleLevel(t_prefiltered, s_environment, reflection_dir, roughness * lights.max_reflection_lod).rgb;
+    let brdf = textureSample(t_brdf_lut, s_environment, vec2<f32>(n_dot_v, roughness)).rg;
+    let ambient_color = ((1.0 - environment_fresnel) * diffuse_color * irradiance
+        + prefiltered * (environment_fresnel * brdf.x + brdf.y)) * occlusion * ambient_occlusion * lights.environment_intensity;
+
+    var lit_color = vec3<f32>(0.0);
+
+    for (var i = 0u; i < lights.count; i++) {
+        let light = lights.lights[i];
+        let radiance = light.color * light.intensity;
+
+        var light_dir = -light.direction;
+        var falloff = 1.0;
+        var cone = 1.0;
+
+        if light.kind != LIGHT_DIRECTIONAL {
+            let to_light = light.position - in.world_position;
+            let distance = length(to_light);
+            light_dir = to_light / distance;
+            falloff = distance_falloff(light, distance);
+        }
+
+        if light.kind == LIGHT_SPOT {
+            cone = smoothstep(light.outer_cos, light.inner_cos, dot(-light_dir, light.direction));
+        }
 
-    let ambient_strength = 0.1;
-    let ambient_color = ambient_strength * light.color ;
+        let n_dot_l = max(dot(normal, light_dir), 0.0);
+        if n_dot_l <= 0.0 {
+            continue;
+        }
 
-    let tangent_normal = object_normal.xyz * 2.0 - 1.0;
-    let light_dir = normalize(in.tangent_light_position - in.tangent_position);
-    let view_dir = normalize(in.tangent_view_position - in.tangent_position);
+        let half_dir = normalize(view_dir + light_dir);
+        let n_dot_h = max(dot(normal, half_dir), 0.0);
 
-    let half_dir = normalize(view_dir + light_dir);
+        let fresnel = fresnel_schlick(max(dot(half_dir, view_dir), 0.0), f0);
+        let specular = distribution_ggx(n_dot_h, alpha) * geometry_smith(n_dot_v, n_dot_l, roughness) * fresnel
+            / (4.0 * n_dot_v * n_dot_l);
+        // light reflected off the surface does not enter it to diffuse
+        let diffuse = (1.0 - fresnel) * diffuse_color / PI;
 
-    let diffuse_strength = max(dot(tangent_normal, light_dir), 0.0);
-    let diffuse_color = diffuse_strength * light.color;
+        var shadow = 1.0;
+#ifdef SHADOWS
+        if i == lights.shadow_caster {
+            shadow = shadow_factor(light, in.world_position);
+        }
+#endif
 
-    let specular_strength = pow(max(dot(tangent_normal, half_dir), 0.0), 32.0);
-    let specular_color = specular_strength * light.color;
+        lit_color += falloff * cone * shadow * (diffuse + specular) * radiance * n_dot_l;
+    }
 
-    let result = (ambient_color + diffuse_color + specular_color) * object_color.xyz;
+    let result = ambient_color + lit_color + emissive;
 
-    return vec4<f32>(result, object_color.a);
+    return vec4<f32>(result, base_color.a);
 }
@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+
+use crate::preprocessor::{Expanded, PreprocessError, Preprocessor};
+
+/// File of the model shader, relative to the shader directory
+pub const MODEL_FILE: &str = "shader.wgsl";
+/// File of the light gizmo shader, relative to the shader directory
+pub const LIGHT_FILE: &str = "light.wgsl";
+
+/// Snippets the shaders can `#include`, with the sources baked into the binary
+const INCLUDES: [(&str, &str); 3] = [
+    ("camera.wgsl", include_str!("camera.wgsl")),
+    ("instance.wgsl", include_str!("instance.wgsl")),
+    ("lights.wgsl", include_str!("lights.wgsl")),
+];
+
+/// Features of the model shader, toggled with `#ifdef`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Features {
+    /// Bends the normals with the normal maps of the materials, `NORMAL_MAPS`
+    pub normal_maps: bool,
+    /// Darkens what the shadow caster does not see, `SHADOWS`
+    pub shadows: bool,
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Self {
+            normal_maps: true,
+            shadows: true,
+        }
+    }
+}
+
+impl Features {
+    /// Defines the names of the enabled features
+    pub fn define(&self, mut preprocessor: Preprocessor) -> Preprocessor {
+        if self.normal_maps {
+            preprocessor = preprocessor.define("NORMAL_MAPS");
+        }
+        if self.shadows {
+            preprocessor = preprocessor.define("SHADOWS");
+        }
+        preprocessor
+    }
+}
+
+/// WGSL sources of the pipelines that can be reloaded while running, the light declarations
+/// are appended to them by [`crate::pipelines`]
+#[derive(Debug, Clone)]
+pub struct Shaders {
+    pub model: String,
+    pub light: String,
+    /// Snippets by file name
+    pub includes: HashMap<String, String>,
+}
+
+impl Default for Shaders {
+    /// The sources baked into the binary
+    fn default() -> Self {
+        Self {
+            model: include_str!("shader.wgsl").to_string(),
+            light: include_str!("light.wgsl").to_string(),
+            includes: INCLUDES
+                .iter()
+                .map(|(name, source)| (name.to_string(), source.to_string()))
+                .collect(),
+        }
+    }
+}
+
+impl Shaders {
+    /// Reads the sources from `dir`, like the `src` directory of the crate
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn load<P: AsRef<std::path::Path>>(dir: P) -> anyhow::Result<Self> {
+        let read = |file_name: &str| std::fs::read_to_string(dir.as_ref().join(file_name));
+
+        Ok(Self {
+            model: read(MODEL_FILE)?,
+            light: read(LIGHT_FILE)?,
+            includes: INCLUDES
+                .iter()
+                .map(|(name, _)| Ok((name.to_string(), read(name)?)))
+                .collect::<std::io::Result<_>>()?,
+        })
+    }
+
+    /// Whether `file_name` is one of the files [`Shaders::load`] reads
+    pub fn is_source(file_name: &str) -> bool {
+        file_name == MODEL_FILE
+            || file_name == LIGHT_FILE
+            || INCLUDES.iter().any(|(name, _)| *name == file_name)
+    }
+
+    /// Preprocessor that can include the snippets
+    pub fn preprocessor(&self) -> Preprocessor {
+        self.includes
+            .iter()
+            .fold(Preprocessor::default(), |preprocessor, (name, source)| {
+                preprocessor.include(name, source)
+            })
+    }
+}
+
+/// Expands a shader baked into the binary with the baked snippets
+pub fn preprocess(source: &str, file: &str) -> Result<Expanded, PreprocessError> {
+    Shaders::default().preprocessor().process(source, file)
+}
+
+/// Parses and validates WGSL with naga. The error starts with the file and line of the source
+/// it points at, followed by the diagnostic with the spans of the expanded source
+#[cfg(not(target_arch = "wasm32"))]
+pub fn validate(expanded: &Expanded, path: &str) -> anyhow::Result<naga::Module> {
+    let source = &expanded.source;
+    // the diagnostic shows the expanded lines, their numbers shift after the includes
+    let expanded_path = format!("{} (preprocessed)", path);
+
+    let error = |location: Option<naga::SourceLocation>, diagnostic: String| {
+        let origin = location.and_then(|location| {
+            let (file, line) = expanded.origin(location.line_number as usize)?;
+            Some(format!("{}:{}:{}", file, line, location.line_position))
+        });
+        match origin {
+            Some(origin) => anyhow::anyhow!("{}\n{}", origin, diagnostic),
+            None => anyhow::anyhow!(diagnostic),
+        }
+    };
+
+    let module = naga::front::wgsl::parse_str(source).map_err(|e| {
+        error(
+            e.location(source),
+            e.emit_to_string_with_path(source, &expanded_path),
+        )
+    })?;
+
+    naga::valid::Validator::new(
+        naga::valid::ValidationFlags::all(),
+        naga::valid::Capabilities::empty(),
+    )
+    .validate(&module)
+    .map_err(|e| {
+        error(
+            e.location(source),
+            e.emit_to_string_with_path(source, &expanded_path),
+        )
+    })?;
+
+    Ok(module)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate_error(snippet: &str, source: &str) -> String {
+        let expanded = Preprocessor::default()
+            .include("snippet.wgsl", snippet)
+            .process(source, "main.wgsl")
+            .unwrap();
+
+        validate(&expanded, "main.wgsl").unwrap_err().to_string()
+    }
+
+    #[test]
+    fn reports_lines_of_the_file_after_an_include() {
+        let error = validate_error(
+            "const A: f32 = 1.0;\nconst B: f32 = 2.0;\n",
+            "#define FEATURE\n#include \"snippet.wgsl\"\nconst C: f32 = A;\nconst D: f32 = missing;\n",
+        );
+        assert!(error.starts_with("main.wgsl:4:16\n"), "{}", error);
+    }
+
+    #[test]
+    fn reports_lines_of_the_snippets() {
+        let error = validate_error(
+            "const A: f32 = 1.0;\nconst B: f32 = 2.0 + missing;\n",
+            "\n#include \"snippet.wgsl\"\n",
+        );
+        assert!(error.starts_with("snippet.wgsl:2:22\n"), "{}", error);
+    }
+}
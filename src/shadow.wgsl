@@ -0,0 +1,53 @@
+// Depth only pass rendering the distance of the scene to the light, one cube face or the projected
+// map at a time
+
+struct ShadowFace {
+    view_proj: mat4x4<f32>,
+    origin: vec3<f32>,
+    far: f32,
+    // measured along for orthographic maps, zero otherwise
+    direction: vec3<f32>,
+}
+
+@group(0) @binding(0) var<uniform> face: ShadowFace;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+}
+
+#include "instance.wgsl"
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) world_position: vec3<f32>,
+};
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+    instance: InstanceInput
+) -> VertexOutput {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+
+    let world_position = model_matrix * vec4<f32>(model.position, 1.0);
+
+    var out: VertexOutput;
+    out.clip_position = face.view_proj * world_position;
+    out.world_position = world_position.xyz;
+    return out;
+}
+
+// the distance is stored instead of the projected depth, so every face compares alike
+@fragment
+fn fs_main(in: VertexOutput) -> @builtin(frag_depth) f32 {
+    let to_fragment = in.world_position - face.origin;
+    if any(face.direction != vec3<f32>(0.0)) {
+        return dot(to_fragment, face.direction) / face.far;
+    }
+    return length(to_fragment) / face.far;
+}
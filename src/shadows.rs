@@ -0,0 +1,494 @@
+use cgmath::*;
+use wgpu::util::DeviceExt;
+
+use crate::light::{Kind, Light};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::reflection;
+use crate::{texture, view::OPENGL_TO_WGPU_MATRIX};
+
+/// Distance from the light past which nothing casts shadows
+const FAR: f32 = 50.0;
+
+/// Half size of the area around the origin a directional light casts shadows in
+const DIRECTIONAL_EXTENT: f32 = 12.0;
+
+/// Cube faces in layer order, as the direction they look in and their up vector. These are the
+/// OpenGL conventions, the projection flips y so the faces land in the layers the way wgpu samples them.
+const FACES: [([f32; 3], [f32; 3]); 6] = [
+    ([1.0, 0.0, 0.0], [0.0, -1.0, 0.0]),
+    ([-1.0, 0.0, 0.0], [0.0, -1.0, 0.0]),
+    ([0.0, 1.0, 0.0], [0.0, 0.0, 1.0]),
+    ([0.0, -1.0, 0.0], [0.0, 0.0, -1.0]),
+    ([0.0, 0.0, 1.0], [0.0, -1.0, 0.0]),
+    ([0.0, 0.0, -1.0], [0.0, -1.0, 0.0]),
+];
+
+/// One face of the cube or the projected map, rendered in its own pass
+pub struct ShadowFace {
+    pub view: wgpu::TextureView,
+    pub buffer: wgpu::Buffer,
+    pub bind_group: wgpu::BindGroup,
+}
+
+/// Distance of the scene to the shadow caster, sampled by the model pass to find shadowed
+/// fragments. A point light renders into a cube around it, directional and spot lights into one
+/// projected map. Its bind group also carries the screen-space ambient occlusion
+pub struct ShadowMap {
+    pub resolution: u32,
+    pub bias: f32,
+    pub cube_texture: texture::Texture,
+    pub cube_faces: Vec<ShadowFace>,
+    pub projected_texture: texture::Texture,
+    pub projected_face: ShadowFace,
+    /// Whether the caster is a directional or spot light, drawn into the projected map
+    projected: bool,
+    pub face_bind_group_layout: wgpu::BindGroupLayout,
+    pub buffer: wgpu::Buffer,
+    pub bind_group_layout: wgpu::BindGroupLayout,
+    pub bind_group: wgpu::BindGroup,
+}
+
+pub struct ShadowMapBuilder<R, B> {
+    resolution: R,
+    bias: B,
+}
+
+impl<R, B> ShadowMapBuilder<R, B> {
+    /// Width and height of each cube face and of the projected map in texels
+    pub fn resolution(self, resolution: u32) -> ShadowMapBuilder<u32, B> {
+        ShadowMapBuilder {
+            resolution,
+            bias: self.bias,
+        }
+    }
+
+    /// Offset against shadow acne, as a fraction of the shadow range
+    pub fn bias(self, bias: f32) -> ShadowMapBuilder<R, f32> {
+        ShadowMapBuilder {
+            resolution: self.resolution,
+            bias,
+        }
+    }
+}
+
+impl ShadowMapBuilder<u32, f32> {
+    /// Follows `caster`, the shadow map is not sampled without one
+    pub fn finalize(
+        self,
+        device: &wgpu::Device,
+        caster: Option<&Light>,
+        ambient_occlusion: &texture::Texture,
+    ) -> ShadowMap {
+        let cube_texture =
+            texture::Texture::create_depth_cube_texture(device, self.resolution, "shadow_texture");
+        let projected_texture = texture::Texture::create_depth_texture(
+            device,
+            self.resolution,
+            self.resolution,
+            1,
+            "projected_shadow_texture",
+        );
+
+        let face_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("Shadow face bind group layout"),
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }],
+            });
+
+        let create_face = |view: wgpu::TextureView, uniform: FlatShadowFace| {
+            let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Shadow Face Buffer"),
+                contents: bytemuck::cast_slice(&[uniform]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            });
+
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("Shadow face bind group"),
+                layout: &face_bind_group_layout,
+                entries: &[wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: buffer.as_entire_binding(),
+                }],
+            });
+
+            ShadowFace {
+                view,
+                buffer,
+                bind_group,
+            }
+        };
+
+        let position = caster.map_or(Point3::origin(), |caster| caster.position);
+        let cube_faces = cube_faces(position)
+            .into_iter()
+            .enumerate()
+            .map(|(layer, uniform)| {
+                let view = cube_texture
+                    .texture
+                    .create_view(&wgpu::TextureViewDescriptor {
+                        label: Some("Shadow face view"),
+                        dimension: Some(wgpu::TextureViewDimension::D2),
+                        base_array_layer: layer as u32,
+                        array_layer_count: Some(1),
+                        ..Default::default()
+                    });
+
+                create_face(view, uniform)
+            })
+            .collect();
+
+        let projected = caster.and_then(projected_face);
+        let projected_face = create_face(
+            projected_texture
+                .texture
+                .create_view(&wgpu::TextureViewDescriptor::default()),
+            projected.unwrap_or_else(bytemuck::Zeroable::zeroed),
+        );
+
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Shadow Buffer"),
+            contents: bytemuck::cast_slice(&[FlatShadow::new(
+                self.resolution,
+                self.bias,
+                projected.as_ref(),
+            )]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Shadow bind group layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Depth,
+                        view_dimension: wgpu::TextureViewDimension::Cube,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Comparison),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Depth,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let bind_group = create_bind_group(
+            device,
+            &bind_group_layout,
+            [&cube_texture, &projected_texture],
+            &buffer,
+            ambient_occlusion,
+        );
+
+        ShadowMap {
+            resolution: self.resolution,
+            bias: self.bias,
+            cube_texture,
+            cube_faces,
+            projected_texture,
+            projected_face,
+            projected: projected.is_some(),
+            face_bind_group_layout,
+            buffer,
+            bind_group_layout,
+            bind_group,
+        }
+    }
+}
+
+impl ShadowMap {
+    pub fn build() -> ShadowMapBuilder<Option<u32>, Option<f32>> {
+        ShadowMapBuilder {
+            resolution: None,
+            bias: None,
+        }
+    }
+
+    /// Points the bind group at a new ambient occlusion texture, after it was recreated
+    pub fn set_ambient_occlusion(
+        &mut self,
+        device: &wgpu::Device,
+        ambient_occlusion: &texture::Texture,
+    ) {
+        self.bind_group = create_bind_group(
+            device,
+            &self.bind_group_layout,
+            [&self.cube_texture, &self.projected_texture],
+            &self.buffer,
+            ambient_occlusion,
+        );
+    }
+
+    /// Moves the faces to `caster`, choosing the map its kind renders into, and writes the bias
+    /// to the uniform buffer
+    pub fn update(&mut self, caster: &Light, queue: &wgpu::Queue) {
+        for (uniform, face) in cube_faces(caster.position).iter().zip(&self.cube_faces) {
+            queue.write_buffer(&face.buffer, 0, bytemuck::cast_slice(&[*uniform]));
+        }
+
+        let projected = projected_face(caster);
+        if let Some(uniform) = projected {
+            queue.write_buffer(
+                &self.projected_face.buffer,
+                0,
+                bytemuck::cast_slice(&[uniform]),
+            );
+        }
+        self.projected = projected.is_some();
+
+        queue.write_buffer(
+            &self.buffer,
+            0,
+            bytemuck::cast_slice(&[FlatShadow::new(
+                self.resolution,
+                self.bias,
+                projected.as_ref(),
+            )]),
+        );
+    }
+
+    /// Faces to render the scene into for the current caster
+    pub fn faces(&self) -> &[ShadowFace] {
+        if self.projected {
+            std::slice::from_ref(&self.projected_face)
+        } else {
+            &self.cube_faces
+        }
+    }
+}
+
+fn create_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    [cube_texture, projected_texture]: [&texture::Texture; 2],
+    buffer: &wgpu::Buffer,
+    ambient_occlusion: &texture::Texture,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("Shadow bind group"),
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&cube_texture.view),
+            },
+            // both maps compare the same way
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Sampler(&cube_texture.sampler),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: wgpu::BindingResource::TextureView(&ambient_occlusion.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 4,
+                resource: wgpu::BindingResource::Sampler(&ambient_occlusion.sampler),
+            },
+            wgpu::BindGroupEntry {
+                binding: 5,
+                resource: wgpu::BindingResource::TextureView(&projected_texture.view),
+            },
+        ],
+    })
+}
+
+fn cube_faces(position: Point3<f32>) -> [FlatShadowFace; 6] {
+    FACES.map(|(direction, up)| {
+        let view = Matrix4::look_to_rh(position, direction.into(), up.into());
+
+        let flip_y = Matrix4::from_nonuniform_scale(1.0, -1.0, 1.0);
+        let projection = perspective(Deg(90.0), 1.0, 0.1, FAR);
+
+        FlatShadowFace::new(
+            OPENGL_TO_WGPU_MATRIX * flip_y * projection * view,
+            position,
+            Vector3::zero(),
+        )
+    })
+}
+
+/// The single face of a directional or spot light, `None` for point lights
+fn projected_face(light: &Light) -> Option<FlatShadowFace> {
+    let up = |direction: Vector3<f32>| {
+        if direction.y.abs() > 0.99 {
+            Vector3::unit_z()
+        } else {
+            Vector3::unit_y()
+        }
+    };
+
+    match light.kind {
+        Kind::Point => None,
+        Kind::Directional { direction } => {
+            // far enough back for the scene around the origin to be in front of the light
+            let origin = Point3::from_vec(-direction * FAR / 2.0);
+            let view = Matrix4::look_to_rh(origin, direction, up(direction));
+            let projection = ortho(
+                -DIRECTIONAL_EXTENT,
+                DIRECTIONAL_EXTENT,
+                -DIRECTIONAL_EXTENT,
+                DIRECTIONAL_EXTENT,
+                0.0,
+                FAR,
+            );
+
+            Some(FlatShadowFace::new(
+                OPENGL_TO_WGPU_MATRIX * projection * view,
+                origin,
+                direction,
+            ))
+        }
+        Kind::Spot {
+            direction,
+            outer_angle,
+            ..
+        } => {
+            let view = Matrix4::look_to_rh(light.position, direction, up(direction));
+            // the frustum holds the whole cone, up to a limit for very wide ones
+            let fovy = Rad((outer_angle.0 * 2.0).min(Rad::from(Deg(170.0)).0));
+            let projection = perspective(fovy, 1.0, 0.1, FAR);
+
+            Some(FlatShadowFace::new(
+                OPENGL_TO_WGPU_MATRIX * projection * view,
+                light.position,
+                Vector3::zero(),
+            ))
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct FlatShadowFace {
+    view_proj: [[f32; 4]; 4],
+    // distances are measured from the origin, or along the direction when it is not zero
+    origin: [f32; 3],
+    far: f32,
+    direction: [f32; 3],
+    _padding: u32,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl FlatShadowFace {
+    pub(crate) fn host_layout() -> reflection::HostLayout {
+        reflection::host_layout!(FlatShadowFace {
+            view_proj,
+            origin,
+            far,
+            direction,
+        })
+    }
+}
+
+impl FlatShadowFace {
+    fn new(view_proj: Matrix4<f32>, origin: Point3<f32>, direction: Vector3<f32>) -> Self {
+        Self {
+            view_proj: view_proj.into(),
+            origin: origin.into(),
+            far: FAR,
+            direction: direction.into(),
+            _padding: 0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct FlatShadow {
+    // the projected face, the cube is looked up by direction
+    view_proj: [[f32; 4]; 4],
+    origin: [f32; 3],
+    bias: f32,
+    direction: [f32; 3],
+    // size of a texel at a distance of 1 from the centre of a face, the PCF kernel steps
+    texel_size: f32,
+    far: f32,
+    _padding: [u32; 3],
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl FlatShadow {
+    pub(crate) fn host_layout() -> reflection::HostLayout {
+        reflection::host_layout!(FlatShadow {
+            view_proj,
+            origin,
+            bias,
+            direction,
+            texel_size,
+            far,
+        })
+    }
+}
+
+impl FlatShadow {
+    fn new(resolution: u32, bias: f32, projected: Option<&FlatShadowFace>) -> Self {
+        let projected = projected
+            .copied()
+            .unwrap_or_else(bytemuck::Zeroable::zeroed);
+
+        Self {
+            view_proj: projected.view_proj,
+            origin: projected.origin,
+            bias,
+            direction: projected.direction,
+            texel_size: 1.0 / resolution as f32,
+            far: FAR,
+            _padding: [0; 3],
+        }
+    }
+}
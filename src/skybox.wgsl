@@ -0,0 +1,42 @@
+
+#include "camera.wgsl"
+
+@group(0) @binding(0) var<uniform> camera: Camera;
+
+@group(1) @binding(0) var t_skybox: texture_cube<f32>;
+@group(1) @binding(1) var s_skybox: sampler;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) direction: vec3<f32>,
+};
+
+// A unit cube around the camera, drawn before the scene which covers it
+@vertex
+fn vs_main(@builtin(vertex_index) vertex_index: u32) -> VertexOutput {
+    // two triangles per face, corners indexed by their x, y and z bits
+    var indices = array<u32, 36>(
+        1u, 5u, 7u, 1u, 7u, 3u, // +x
+        0u, 2u, 6u, 0u, 6u, 4u, // -x
+        2u, 3u, 7u, 2u, 7u, 6u, // +y
+        0u, 4u, 5u, 0u, 5u, 1u, // -y
+        4u, 6u, 7u, 4u, 7u, 5u, // +z
+        0u, 1u, 3u, 0u, 3u, 2u, // -z
+    );
+    let corner = indices[vertex_index];
+    let direction = vec3<f32>(
+        f32(corner & 1u),
+        f32((corner >> 1u) & 1u),
+        f32((corner >> 2u) & 1u),
+    ) * 2.0 - 1.0;
+
+    var out: VertexOutput;
+    out.clip_position = camera.view_proj * vec4<f32>(camera.view_pos.xyz + direction, 1.0);
+    out.direction = direction;
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    return vec4<f32>(textureSample(t_skybox, s_skybox, in.direction).rgb, 1.0);
+}
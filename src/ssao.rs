@@ -0,0 +1,495 @@
+use cgmath::SquareMatrix;
+use wgpu::util::DeviceExt;
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::reflection;
+use crate::{texture, view};
+
+/// A single channel is enough for the occlusion
+const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R8Unorm;
+/// Depth difference, in view space units, below which a sample does not occlude
+const BIAS: f32 = 0.025;
+
+/// Preset trading the quality of the ambient occlusion for speed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quality {
+    Off,
+    Low,
+    Medium,
+    High,
+}
+
+impl Quality {
+    pub fn next(self) -> Self {
+        match self {
+            Quality::Off => Quality::Low,
+            Quality::Low => Quality::Medium,
+            Quality::Medium => Quality::High,
+            Quality::High => Quality::Off,
+        }
+    }
+
+    /// Samples taken around each pixel
+    fn sample_count(self) -> u32 {
+        match self {
+            Quality::Off => 0,
+            Quality::Low => 8,
+            Quality::Medium => 16,
+            Quality::High => 32,
+        }
+    }
+
+    /// The occlusion is computed at the frame size divided by this
+    fn downscale(self) -> u32 {
+        match self {
+            Quality::High => 1,
+            _ => 2,
+        }
+    }
+}
+
+impl Default for Quality {
+    /// High on native targets, low on the web where integrated GPUs are common
+    fn default() -> Self {
+        cfg_if::cfg_if! {
+            if #[cfg(target_arch = "wasm32")] {
+                Quality::Low
+            } else {
+                Quality::High
+            }
+        }
+    }
+}
+
+/// Screen-space ambient occlusion, estimated from a depth prepass of the scene and blurred. The
+/// model pass darkens its ambient light with `texture`
+pub struct Ssao {
+    quality: Quality,
+    /// Distance around a point in which the scene occludes it
+    pub radius: f32,
+    /// Exponent applied to the visibility, above 1 darkens the occlusion
+    pub intensity: f32,
+    /// Single sampled depth of the scene, rendered by the prepass
+    pub depth_texture: texture::Texture,
+    /// Blurred occlusion, 1 where nothing occludes
+    pub texture: texture::Texture,
+    raw_texture: texture::Texture,
+    buffer: wgpu::Buffer,
+    bind_group_layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    blur_bind_group_layout: wgpu::BindGroupLayout,
+    blur_bind_group: wgpu::BindGroup,
+    pipeline: wgpu::RenderPipeline,
+    blur_pipeline: wgpu::RenderPipeline,
+}
+
+pub struct SsaoBuilder<Q, R> {
+    quality: Q,
+    radius: R,
+    intensity: f32,
+}
+
+impl<Q, R> SsaoBuilder<Q, R> {
+    pub fn quality(self, quality: Quality) -> SsaoBuilder<Quality, R> {
+        SsaoBuilder {
+            quality,
+            radius: self.radius,
+            intensity: self.intensity,
+        }
+    }
+
+    /// Distance around a point in which the scene occludes it
+    pub fn radius(self, radius: f32) -> SsaoBuilder<Q, f32> {
+        SsaoBuilder {
+            quality: self.quality,
+            radius,
+            intensity: self.intensity,
+        }
+    }
+
+    /// Exponent applied to the visibility, 1.0 by default
+    pub fn intensity(mut self, intensity: f32) -> Self {
+        self.intensity = intensity;
+        self
+    }
+}
+
+impl SsaoBuilder<Quality, f32> {
+    /// Creates the targets at the size of the frame
+    pub fn finalize(
+        self,
+        device: &wgpu::Device,
+        width: u32,
+        height: u32,
+        projection: &view::Projection,
+    ) -> Ssao {
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("SSAO Buffer"),
+            contents: bytemuck::cast_slice(&[FlatSsao::new(
+                projection,
+                self.radius,
+                self.intensity,
+                self.quality,
+            )]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("SSAO bind group layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        // GL backends can only load from depth textures bound as floats
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let blur_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("SSAO blur bind group layout"),
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        // read with loads, R8Unorm is filterable anyway
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                }],
+            });
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("SSAO Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("ssao.wgsl").into()),
+        });
+
+        let pipeline = create_pipeline(
+            device,
+            &shader,
+            "fs_main",
+            &[&bind_group_layout],
+            "SSAO Pipeline",
+        );
+
+        let blur_pipeline = create_pipeline(
+            device,
+            &shader,
+            "fs_blur",
+            &[&bind_group_layout, &blur_bind_group_layout],
+            "SSAO Blur Pipeline",
+        );
+
+        let targets = Targets::new(
+            device,
+            &buffer,
+            &bind_group_layout,
+            &blur_bind_group_layout,
+            width,
+            height,
+            self.quality,
+        );
+
+        Ssao {
+            quality: self.quality,
+            radius: self.radius,
+            intensity: self.intensity,
+            depth_texture: targets.depth_texture,
+            texture: targets.texture,
+            raw_texture: targets.raw_texture,
+            buffer,
+            bind_group_layout,
+            bind_group: targets.bind_group,
+            blur_bind_group_layout,
+            blur_bind_group: targets.blur_bind_group,
+            pipeline,
+            blur_pipeline,
+        }
+    }
+}
+
+impl Ssao {
+    pub fn build() -> SsaoBuilder<Option<Quality>, Option<f32>> {
+        SsaoBuilder {
+            quality: None,
+            radius: None,
+            intensity: 1.0,
+        }
+    }
+
+    pub fn quality(&self) -> Quality {
+        self.quality
+    }
+
+    /// Switches to another preset, recreating the targets, bind groups reading `texture` have to
+    /// be created again
+    pub fn set_quality(&mut self, device: &wgpu::Device, quality: Quality) {
+        self.quality = quality;
+        self.resize(
+            device,
+            self.depth_texture.texture.width(),
+            self.depth_texture.texture.height(),
+        );
+    }
+
+    /// Recreates the targets at the new frame size, bind groups reading `texture` have to be
+    /// created again
+    pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+        let targets = Targets::new(
+            device,
+            &self.buffer,
+            &self.bind_group_layout,
+            &self.blur_bind_group_layout,
+            width,
+            height,
+            self.quality,
+        );
+
+        self.depth_texture = targets.depth_texture;
+        self.texture = targets.texture;
+        self.raw_texture = targets.raw_texture;
+        self.bind_group = targets.bind_group;
+        self.blur_bind_group = targets.blur_bind_group;
+    }
+
+    /// Writes the projection and the parameters to the uniform buffer
+    pub fn update(&self, projection: &view::Projection, queue: &wgpu::Queue) {
+        queue.write_buffer(
+            &self.buffer,
+            0,
+            bytemuck::cast_slice(&[FlatSsao::new(
+                projection,
+                self.radius,
+                self.intensity,
+                self.quality,
+            )]),
+        );
+    }
+
+    /// Computes the occlusion from `depth_texture` into `texture`, the prepass must have run
+    /// unless the quality is `Off`
+    pub fn draw(&self, encoder: &mut wgpu::CommandEncoder) {
+        if self.quality == Quality::Off {
+            // nothing occludes
+            begin_pass(encoder, "SSAO Pass", &self.texture.view, wgpu::Color::WHITE);
+            return;
+        }
+
+        let mut render_pass = begin_pass(
+            encoder,
+            "SSAO Pass",
+            &self.raw_texture.view,
+            wgpu::Color::WHITE,
+        );
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, &self.bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+        drop(render_pass);
+
+        let mut render_pass = begin_pass(
+            encoder,
+            "SSAO Blur Pass",
+            &self.texture.view,
+            wgpu::Color::WHITE,
+        );
+        render_pass.set_pipeline(&self.blur_pipeline);
+        render_pass.set_bind_group(0, &self.bind_group, &[]);
+        render_pass.set_bind_group(1, &self.blur_bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
+
+/// Textures sized after the frame, with the bind groups reading them
+struct Targets {
+    depth_texture: texture::Texture,
+    texture: texture::Texture,
+    raw_texture: texture::Texture,
+    bind_group: wgpu::BindGroup,
+    blur_bind_group: wgpu::BindGroup,
+}
+
+impl Targets {
+    fn new(
+        device: &wgpu::Device,
+        buffer: &wgpu::Buffer,
+        bind_group_layout: &wgpu::BindGroupLayout,
+        blur_bind_group_layout: &wgpu::BindGroupLayout,
+        width: u32,
+        height: u32,
+        quality: Quality,
+    ) -> Self {
+        // multisampled depth can not be sampled on every backend, the prepass renders its own
+        let depth_texture =
+            texture::Texture::create_depth_texture(device, width, height, 1, "ssao_depth_texture");
+
+        let (occlusion_width, occlusion_height) = (
+            (width / quality.downscale()).max(1),
+            (height / quality.downscale()).max(1),
+        );
+
+        let texture = texture::Texture::create_render_target(
+            device,
+            occlusion_width,
+            occlusion_height,
+            FORMAT,
+            "ssao_texture",
+        );
+
+        let raw_texture = texture::Texture::create_render_target(
+            device,
+            occlusion_width,
+            occlusion_height,
+            FORMAT,
+            "ssao_raw_texture",
+        );
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("SSAO bind group"),
+            layout: bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&depth_texture.view),
+                },
+            ],
+        });
+
+        let blur_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("SSAO blur bind group"),
+            layout: blur_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&raw_texture.view),
+            }],
+        });
+
+        Self {
+            depth_texture,
+            texture,
+            raw_texture,
+            bind_group,
+            blur_bind_group,
+        }
+    }
+}
+
+fn begin_pass<'a>(
+    encoder: &'a mut wgpu::CommandEncoder,
+    label: &str,
+    view: &'a wgpu::TextureView,
+    clear: wgpu::Color,
+) -> wgpu::RenderPass<'a> {
+    encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+        label: Some(label),
+        color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+            view,
+            resolve_target: None,
+            ops: wgpu::Operations {
+                load: wgpu::LoadOp::Clear(clear),
+                store: wgpu::StoreOp::Store,
+            },
+        })],
+        depth_stencil_attachment: None,
+        timestamp_writes: None,
+        occlusion_query_set: None,
+    })
+}
+
+fn create_pipeline(
+    device: &wgpu::Device,
+    shader: &wgpu::ShaderModule,
+    entry_point: &str,
+    bind_group_layouts: &[&wgpu::BindGroupLayout],
+    label: &str,
+) -> wgpu::RenderPipeline {
+    let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some(label),
+        bind_group_layouts,
+        push_constant_ranges: &[],
+    });
+
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(label),
+        layout: Some(&pipeline_layout),
+        vertex: wgpu::VertexState {
+            module: shader,
+            entry_point: "vs_main",
+            buffers: &[],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader,
+            entry_point,
+            targets: &[Some(FORMAT.into())],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            ..Default::default()
+        },
+        depth_stencil: None,
+        multisample: wgpu::MultisampleState::default(),
+        multiview: None,
+    })
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct FlatSsao {
+    projection: [[f32; 4]; 4],
+    inverse_projection: [[f32; 4]; 4],
+    radius: f32,
+    intensity: f32,
+    bias: f32,
+    sample_count: u32,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl FlatSsao {
+    pub(crate) fn host_layout() -> reflection::HostLayout {
+        reflection::host_layout!(FlatSsao {
+            projection,
+            inverse_projection,
+            radius,
+            intensity,
+            bias,
+            sample_count,
+        })
+    }
+}
+
+impl FlatSsao {
+    fn new(projection: &view::Projection, radius: f32, intensity: f32, quality: Quality) -> Self {
+        let matrix = projection.calc_matrix();
+
+        Self {
+            projection: matrix.into(),
+            // perspective projections are always invertible
+            inverse_projection: matrix.invert().unwrap().into(),
+            radius,
+            intensity,
+            bias: BIAS,
+            sample_count: quality.sample_count(),
+        }
+    }
+}
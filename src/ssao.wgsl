@@ -0,0 +1,148 @@
+// Screen-space ambient occlusion, the share of the hemisphere above each pixel hidden by the
+// depth of the scene around it, then blurred to hide the sampling noise
+
+struct Ssao {
+    projection: mat4x4<f32>,
+    inverse_projection: mat4x4<f32>,
+    radius: f32,
+    intensity: f32,
+    bias: f32,
+    sample_count: u32,
+};
+
+@group(0) @binding(0) var<uniform> ssao: Ssao;
+@group(0) @binding(1) var t_depth: texture_2d<f32>;
+
+// occlusion before the blur
+@group(1) @binding(0) var t_occlusion: texture_2d<f32>;
+
+const PI: f32 = 3.14159265;
+const GOLDEN_ANGLE: f32 = 2.39996323;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+};
+
+// a single triangle covering the whole target
+@vertex
+fn vs_main(@builtin(vertex_index) vertex_index: u32) -> VertexOutput {
+    let x = f32((vertex_index << 1u) & 2u);
+    let y = f32(vertex_index & 2u);
+
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(x * 2.0 - 1.0, 1.0 - y * 2.0, 0.0, 1.0);
+    out.tex_coords = vec2<f32>(x, y);
+    return out;
+}
+
+fn depth_coords(tex_coords: vec2<f32>) -> vec2<i32> {
+    let size = vec2<i32>(textureDimensions(t_depth));
+    return clamp(vec2<i32>(tex_coords * vec2<f32>(size)), vec2<i32>(0), size - 1);
+}
+
+// View space position of the scene at a depth texel
+fn view_position(coords: vec2<i32>) -> vec3<f32> {
+    let size = vec2<i32>(textureDimensions(t_depth));
+    let clamped = clamp(coords, vec2<i32>(0), size - 1);
+
+    let tex_coords = (vec2<f32>(clamped) + 0.5) / vec2<f32>(size);
+    let depth = textureLoad(t_depth, clamped, 0).r;
+    let position = ssao.inverse_projection * vec4<f32>(tex_coords.x * 2.0 - 1.0, 1.0 - tex_coords.y * 2.0, depth, 1.0);
+    return position.xyz / position.w;
+}
+
+// Normal from the neighbours closest in depth on each axis, so it does not bend over edges
+fn view_normal(coords: vec2<i32>, center: vec3<f32>) -> vec3<f32> {
+    let left = view_position(coords - vec2<i32>(1, 0));
+    let right = view_position(coords + vec2<i32>(1, 0));
+    let up = view_position(coords - vec2<i32>(0, 1));
+    let down = view_position(coords + vec2<i32>(0, 1));
+
+    var dx = right - center;
+    if abs(center.z - left.z) < abs(right.z - center.z) {
+        dx = center - left;
+    }
+    var dy = down - center;
+    if abs(center.z - up.z) < abs(down.z - center.z) {
+        dy = center - up;
+    }
+
+    // rows grow downwards, this faces the camera
+    return normalize(cross(dy, dx));
+}
+
+fn interleaved_gradient_noise(pixel: vec2<f32>) -> f32 {
+    return fract(52.9829189 * fract(dot(pixel, vec2<f32>(0.06711056, 0.00583715))));
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let coords = depth_coords(in.tex_coords);
+
+    // nothing was drawn there
+    if textureLoad(t_depth, coords, 0).r >= 1.0 {
+        return vec4<f32>(1.0);
+    }
+
+    let position = view_position(coords);
+    let normal = view_normal(coords, position);
+
+    // the samples turn by a different angle on each pixel, the blur averages the pattern away
+    let rotation = interleaved_gradient_noise(in.clip_position.xy) * 2.0 * PI;
+    var up = vec3<f32>(0.0, 1.0, 0.0);
+    if abs(normal.y) > 0.99 {
+        up = vec3<f32>(1.0, 0.0, 0.0);
+    }
+    let tangent = normalize(cross(up, normal));
+    let bitangent = cross(normal, tangent);
+
+    var occlusion = 0.0;
+    for (var i = 0u; i < ssao.sample_count; i++) {
+        // spiral over the hemisphere, with distances spread by another sequence
+        let t = (f32(i) + 0.5) / f32(ssao.sample_count);
+        let phi = f32(i) * GOLDEN_ANGLE + rotation;
+        let sin_theta = sqrt(t);
+        let direction = (tangent * cos(phi) + bitangent * sin(phi)) * sin_theta + normal * sqrt(1.0 - t);
+        let distance = fract(f32(i) * 0.7548776662 + 0.5);
+        // more samples close to the pixel
+        let sample = position + direction * ssao.radius * mix(0.1, 1.0, distance * distance);
+
+        let clip = ssao.projection * vec4<f32>(sample, 1.0);
+        let scene = view_position(depth_coords(clip.xy / clip.w * vec2<f32>(0.5, -0.5) + 0.5));
+
+        // geometry far in front of the pixel does not shade it
+        let range = smoothstep(0.0, 1.0, ssao.radius / abs(position.z - scene.z));
+        if scene.z >= sample.z + ssao.bias {
+            occlusion += range;
+        }
+    }
+
+    let visibility = 1.0 - occlusion / f32(ssao.sample_count);
+    return vec4<f32>(pow(visibility, ssao.intensity), 0.0, 0.0, 1.0);
+}
+
+// 4x4 average of the occlusion, leaving out texels at another depth so it does not bleed over edges
+@fragment
+fn fs_blur(in: VertexOutput) -> @location(0) vec4<f32> {
+    let size = vec2<i32>(textureDimensions(t_occlusion));
+    let coords = vec2<i32>(in.clip_position.xy);
+    let depth = view_position(depth_coords(in.tex_coords)).z;
+
+    var occlusion = 0.0;
+    var weight = 0.0;
+    for (var y = -2; y < 2; y++) {
+        for (var x = -2; x < 2; x++) {
+            let sample_coords = clamp(coords + vec2<i32>(x, y), vec2<i32>(0), size - 1);
+            let sample_tex_coords = (vec2<f32>(sample_coords) + 0.5) / vec2<f32>(size);
+            let sample_depth = view_position(depth_coords(sample_tex_coords)).z;
+
+            if abs(sample_depth - depth) <= 0.1 * abs(depth) {
+                occlusion += textureLoad(t_occlusion, sample_coords, 0).r;
+                weight += 1.0;
+            }
+        }
+    }
+
+    return vec4<f32>(occlusion / max(weight, 1.0), 0.0, 0.0, 1.0);
+}
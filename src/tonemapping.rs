@@ -0,0 +1,233 @@
+use wgpu::util::DeviceExt;
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::reflection;
+use crate::texture;
+
+/// Curve compressing HDR colours into the displayable range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    Reinhard,
+    Aces,
+}
+
+impl Operator {
+    pub fn next(self) -> Self {
+        match self {
+            Operator::Reinhard => Operator::Aces,
+            Operator::Aces => Operator::Reinhard,
+        }
+    }
+}
+
+/// Resolves the HDR scene target onto the surface
+pub struct Tonemapping {
+    pub operator: Operator,
+    pub exposure: f32,
+    encode_srgb: bool,
+    buffer: wgpu::Buffer,
+    bind_group_layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    pipeline: wgpu::RenderPipeline,
+}
+
+impl Tonemapping {
+    pub fn new(
+        device: &wgpu::Device,
+        hdr_texture: &texture::Texture,
+        output_format: wgpu::TextureFormat,
+        operator: Operator,
+        exposure: f32,
+    ) -> Self {
+        // non sRGB surfaces need the shader to gamma encode
+        let encode_srgb = !output_format.is_srgb();
+
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Tonemapping Buffer"),
+            contents: bytemuck::cast_slice(&[FlatTonemapping::new(
+                operator,
+                exposure,
+                encode_srgb,
+            )]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Tonemapping bind group layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let bind_group = create_bind_group(device, &bind_group_layout, hdr_texture, &buffer);
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Tonemapping Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Tonemapping Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("tonemapping.wgsl").into()),
+        });
+
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Tonemapping Pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(output_format.into())],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                ..Default::default()
+            },
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+
+        Self {
+            operator,
+            exposure,
+            encode_srgb,
+            buffer,
+            bind_group_layout,
+            bind_group,
+            pipeline,
+        }
+    }
+
+    /// Writes the operator and exposure to the uniform buffer, call after changing them
+    pub fn update(&self, queue: &wgpu::Queue) {
+        queue.write_buffer(
+            &self.buffer,
+            0,
+            bytemuck::cast_slice(&[FlatTonemapping::new(
+                self.operator,
+                self.exposure,
+                self.encode_srgb,
+            )]),
+        );
+    }
+
+    /// Points the pass at a new HDR target, after a resize
+    pub fn resize(&mut self, device: &wgpu::Device, hdr_texture: &texture::Texture) {
+        self.bind_group =
+            create_bind_group(device, &self.bind_group_layout, hdr_texture, &self.buffer);
+    }
+
+    pub fn draw(&self, encoder: &mut wgpu::CommandEncoder, view: &wgpu::TextureView) {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Tonemapping Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, &self.bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
+
+fn create_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    hdr_texture: &texture::Texture,
+    buffer: &wgpu::Buffer,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("Tonemapping bind group"),
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&hdr_texture.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Sampler(&hdr_texture.sampler),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: buffer.as_entire_binding(),
+            },
+        ],
+    })
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct FlatTonemapping {
+    exposure: f32,
+    operator: u32,
+    encode_srgb: u32,
+    // uniforms are padded to 16 bytes on WebGL
+    _padding: u32,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl FlatTonemapping {
+    pub(crate) fn host_layout() -> reflection::HostLayout {
+        reflection::host_layout!(FlatTonemapping {
+            exposure,
+            operator,
+            encode_srgb,
+        })
+    }
+}
+
+impl FlatTonemapping {
+    fn new(operator: Operator, exposure: f32, encode_srgb: bool) -> Self {
+        Self {
+            exposure,
+            operator: match operator {
+                Operator::Reinhard => 0,
+                Operator::Aces => 1,
+            },
+            encode_srgb: encode_srgb as u32,
+            _padding: 0,
+        }
+    }
+}
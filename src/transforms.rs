@@ -2,6 +2,9 @@ use cgmath::prelude::*;
 use instant::Duration;
 use wgpu::util::DeviceExt;
 
+#[cfg(not(target_arch = "wasm32"))]
+use crate::reflection;
+
 #[repr(C)]
 #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct FlatTransform {
@@ -9,6 +12,16 @@ pub struct FlatTransform {
     normal_transform: [[f32; 3]; 3],
 }
 
+#[cfg(not(target_arch = "wasm32"))]
+impl FlatTransform {
+    pub(crate) fn host_layout() -> reflection::HostLayout {
+        reflection::host_layout!(FlatTransform {
+            model_transform,
+            normal_transform
+        })
+    }
+}
+
 pub struct Transform {
     translation: cgmath::Vector3<f32>,
     rotation: cgmath::Quaternion<f32>,
@@ -16,6 +29,18 @@ pub struct Transform {
 }
 
 impl Transform {
+    pub fn new<V: Into<cgmath::Vector3<f32>>>(
+        translation: V,
+        rotation: cgmath::Quaternion<f32>,
+        scale: f32,
+    ) -> Self {
+        Self {
+            translation: translation.into(),
+            rotation,
+            scale,
+        }
+    }
+
     fn fattened(&self) -> FlatTransform {
         let model = cgmath::Matrix4::from_translation(self.translation)
             * cgmath::Matrix4::from(self.rotation)
@@ -72,6 +97,10 @@ impl<T> TransformsBuilder<T> {
 
         TransformsBuilder { transforms }
     }
+
+    pub fn transforms(self, transforms: Vec<Transform>) -> TransformsBuilder<Vec<Transform>> {
+        TransformsBuilder { transforms }
+    }
 }
 
 impl TransformsBuilder<Vec<Transform>> {
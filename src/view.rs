@@ -4,6 +4,8 @@ use std::f32::consts::FRAC_PI_2;
 use wgpu::util::DeviceExt;
 
 use crate::controller::Controller;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::reflection;
 
 #[rustfmt::skip]
 pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
@@ -241,6 +243,16 @@ pub struct FlatView {
     view_proj: [[f32; 4]; 4],
 }
 
+#[cfg(not(target_arch = "wasm32"))]
+impl FlatView {
+    pub(crate) fn host_layout() -> reflection::HostLayout {
+        reflection::host_layout!(FlatView {
+            view_position,
+            view_proj
+        })
+    }
+}
+
 impl FlatView {
     fn new(camera: &Camera, projection: &Projection) -> Self {
         Self {
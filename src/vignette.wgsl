@@ -0,0 +1,20 @@
+// Darkens the frame towards its corners
+
+struct Vignette {
+    intensity: f32,
+    // distance from the centre where the darkening starts, 1 is a corner
+    radius: f32,
+    // distance over which it reaches full intensity
+    smoothness: f32,
+};
+@group(1) @binding(0) var<uniform> vignette: Vignette;
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = sample_input(in.tex_coords);
+
+    let distance = length(in.tex_coords - 0.5) * sqrt(2.0);
+    let darkening = smoothstep(vignette.radius, vignette.radius + vignette.smoothness, distance);
+
+    return output(vec4<f32>(color.rgb * (1.0 - darkening * vignette.intensity), color.a));
+}
@@ -0,0 +1,139 @@
+//! Renders fixed scenes on a software adapter and compares them against the
+//! reference images in `tests/golden`.
+//!
+//! Run with `UPDATE_GOLDEN=1 cargo test --test golden` to regenerate the references.
+
+use std::path::{Path, PathBuf};
+
+use image::{Rgba, RgbaImage};
+
+const WIDTH: u32 = 320;
+const HEIGHT: u32 = 240;
+
+/// Maximum difference allowed in any colour channel before a pixel counts as different
+const CHANNEL_TOLERANCE: u8 = 8;
+
+/// Fraction of pixels allowed to differ, absorbs rasterization differences between drivers
+const MAX_DIFFERENT_PIXELS: f32 = 0.005;
+
+fn golden_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("golden")
+}
+
+fn render(scene_file: &str, sample_count: u32) -> RgbaImage {
+    let mut state = pollster::block_on(wgpu_intro::State::new_headless(
+        WIDTH, HEIGHT, scene_file, true,
+    ))
+    .expect("could not initialize a software adapter");
+
+    assert_eq!(
+        state.set_sample_count(sample_count),
+        sample_count,
+        "the software adapter does not support {}x MSAA",
+        sample_count
+    );
+
+    let frame = state
+        .render_offscreen()
+        .expect("headless state renders offscreen");
+
+    frame
+        .to_image(state.device(), state.queue())
+        .expect("could not read back the frame")
+}
+
+/// Returns the number of differing pixels and an image highlighting them in red
+fn diff(actual: &RgbaImage, expected: &RgbaImage) -> (usize, RgbaImage) {
+    let mut different = 0;
+
+    let diff = RgbaImage::from_fn(actual.width(), actual.height(), |x, y| {
+        let a = actual.get_pixel(x, y);
+        let e = expected.get_pixel(x, y);
+
+        let within_tolerance =
+            a.0.iter()
+                .zip(e.0.iter())
+                .all(|(a, e)| a.abs_diff(*e) <= CHANNEL_TOLERANCE);
+
+        if within_tolerance {
+            // dimmed copy of the expected image for context
+            Rgba([e[0] / 4, e[1] / 4, e[2] / 4, 255])
+        } else {
+            different += 1;
+            Rgba([255, 0, 0, 255])
+        }
+    });
+
+    (different, diff)
+}
+
+fn check_golden(name: &str, scene_file: &str, sample_count: u32) {
+    let actual = render(scene_file, sample_count);
+    let reference = golden_dir().join(format!("{}.png", name));
+
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        actual.save(&reference).unwrap();
+        return;
+    }
+
+    let expected = image::open(&reference)
+        .unwrap_or_else(|e| panic!("could not open {}: {}", reference.display(), e))
+        .to_rgba8();
+
+    assert_eq!(
+        actual.dimensions(),
+        expected.dimensions(),
+        "{} has a different size than the rendered frame",
+        reference.display()
+    );
+
+    let (different, diff) = diff(&actual, &expected);
+    let allowed = (MAX_DIFFERENT_PIXELS * (WIDTH * HEIGHT) as f32) as usize;
+
+    if different > allowed {
+        let out_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("golden");
+        std::fs::create_dir_all(&out_dir).unwrap();
+
+        let actual_path = out_dir.join(format!("{}.actual.png", name));
+        let diff_path = out_dir.join(format!("{}.diff.png", name));
+        actual.save(&actual_path).unwrap();
+        diff.save(&diff_path).unwrap();
+
+        panic!(
+            "{}: {} pixels differ from {} (allowed {}), see {} and {}",
+            name,
+            different,
+            reference.display(),
+            allowed,
+            actual_path.display(),
+            diff_path.display()
+        );
+    }
+}
+
+#[test]
+fn cube() {
+    check_golden("cube", "cube.ron", 1);
+}
+
+#[test]
+fn moon() {
+    check_golden("moon", "moon.ron", 1);
+}
+
+#[test]
+fn cube_gltf() {
+    check_golden("cube_gltf", "cube-gltf.ron", 1);
+}
+
+#[test]
+fn cube_bare() {
+    check_golden("cube_bare", "cube-bare.ron", 1);
+}
+
+#[test]
+fn cube_msaa() {
+    check_golden("cube_msaa", "cube.ron", 4);
+}
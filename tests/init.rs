@@ -0,0 +1,59 @@
+//! Initializes the headless state from scenes that cannot be loaded, in `tests/scenes`.
+
+use std::path::Path;
+
+use wgpu_intro::init::InitError;
+
+fn load(scene_file: &str) -> Result<wgpu_intro::State, InitError> {
+    wgpu_intro::resources::set_asset_dir(
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join("scenes"),
+    );
+    pollster::block_on(wgpu_intro::State::new_headless(64, 64, scene_file, true))
+}
+
+/// Error of loading `scene_file` itself, rather than of an earlier stage
+fn asset_error(scene_file: &str) -> anyhow::Error {
+    match load(scene_file) {
+        Err(InitError::Asset { asset, error }) => {
+            assert_eq!(asset, scene_file);
+            error
+        }
+        Err(e) => panic!("{} failed in the wrong stage: {}", scene_file, e),
+        Ok(_) => panic!("{} loaded", scene_file),
+    }
+}
+
+fn assert_asset_error(scene_file: &str, message: &str) {
+    let error = format!("{:#}", asset_error(scene_file));
+    assert!(error.contains(message), "{}", error);
+}
+
+#[test]
+fn missing_scene() {
+    let error = asset_error("missing.ron");
+    assert_eq!(
+        error
+            .downcast_ref::<std::io::Error>()
+            .map(std::io::Error::kind),
+        Some(std::io::ErrorKind::NotFound),
+        "{:#}",
+        error
+    );
+}
+
+#[test]
+fn invalid_scene() {
+    assert_asset_error("invalid.ron", "could not parse invalid.ron");
+}
+
+#[test]
+fn zero_rotation_axis() {
+    assert_asset_error("zero-axis.ron", "rotates about a zero axis");
+}
+
+#[test]
+fn no_instances() {
+    assert_asset_error("no-instances.ron", "cube-bare.obj has no instances");
+}
@@ -0,0 +1,18 @@
+//! Checks the uniforms and vertex inputs the shaders declare against the `#[repr(C)]` structs
+//! filling their buffers, with the layouts naga computes.
+
+use wgpu_intro::{reflection, shaders};
+
+#[test]
+fn reloadable_shaders() {
+    if let Err(e) = reflection::check_shaders(&shaders::Shaders::default()) {
+        panic!("{}", e);
+    }
+}
+
+#[test]
+fn baked_shaders() {
+    if let Err(e) = reflection::check_baked_shaders() {
+        panic!("{}", e);
+    }
+}